@@ -0,0 +1,209 @@
+use log::*;
+use talk_loco_client::client::{talk::TalkClient, ClientRequestError};
+use talk_loco_command::{
+    request::chat::{
+        BlindUserReq, KickListDelItemReq, KickListReq, SetMemTypeReq, SetMetaReq, UpdateLinkReq,
+    },
+    response::chat::KickedMember,
+};
+
+use crate::kakao::{KakaoClient, OpenMemberType};
+
+const MEMBER_TYPE_MEMBER: i32 = 2;
+const MEMBER_TYPE_MANAGER: i32 = 4;
+
+// Channel meta types for SETMETA
+const META_TYPE_NOTICE: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminRole {
+    Host,
+    HostOrManager,
+}
+
+impl AdminRole {
+    fn allows(self, role: OpenMemberType) -> bool {
+        match self {
+            AdminRole::Host => role == OpenMemberType::Host,
+            AdminRole::HostOrManager => {
+                matches!(role, OpenMemberType::Host | OpenMemberType::Manager)
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AdminError {
+    #[error("not in channel {0}")]
+    UnknownChannel(i64),
+    #[error("channel {0} is a normal chat, only open chats are administered")]
+    NotOpenChannel(i64),
+    // role is Unknown until the channel roster is synced
+    #[error("{operation} needs {required:?}, the bot is {role:?} in channel {channel_id}")]
+    PermissionDenied {
+        channel_id: i64,
+        operation: &'static str,
+        required: AdminRole,
+        role: OpenMemberType,
+    },
+    #[error("request failed: {0}")]
+    Request(#[from] ClientRequestError),
+}
+
+// Open chat administration of one channel, each call checks the bot's role before sending the
+// command
+pub struct ChannelAdmin<'a> {
+    client: &'a mut KakaoClient,
+    channel_id: i64,
+    link_id: i64,
+}
+
+impl<'a> ChannelAdmin<'a> {
+    pub fn new(client: &'a mut KakaoClient, channel_id: i64) -> Result<Self, AdminError> {
+        let channel = client
+            .channel(channel_id)
+            .ok_or(AdminError::UnknownChannel(channel_id))?;
+        let link_id = channel
+            .link_id()
+            .ok_or(AdminError::NotOpenChannel(channel_id))?;
+
+        Ok(Self {
+            client,
+            channel_id,
+            link_id,
+        })
+    }
+
+    fn require_role(&self, operation: &'static str, required: AdminRole) -> Result<(), AdminError> {
+        let role = match self.client.user_id() {
+            Some(user_id) => self.client.roster.role(self.channel_id, user_id),
+            None => OpenMemberType::Unknown,
+        };
+        if !required.allows(role) {
+            return Err(AdminError::PermissionDenied {
+                channel_id: self.channel_id,
+                operation,
+                required,
+                role,
+            });
+        }
+        Ok(())
+    }
+
+    pub async fn set_manager(&mut self, user_id: i64, manager: bool) -> Result<(), AdminError> {
+        self.require_role("set manager", AdminRole::Host)?;
+        let member_type = match manager {
+            true => MEMBER_TYPE_MANAGER,
+            false => MEMBER_TYPE_MEMBER,
+        };
+
+        info!(
+            "Set user {} in channel_id={} to member type {}",
+            user_id, self.channel_id, member_type
+        );
+        let client = TalkClient(&self.client.talk_client.connection().session);
+        client
+            .set_member_type(&SetMemTypeReq {
+                chat_id: self.channel_id,
+                link_id: self.link_id,
+                member_ids: vec![user_id],
+                member_types: vec![member_type],
+            })
+            .await?;
+        info!("Set member type successfully");
+
+        self.client
+            .roster
+            .set_role(self.channel_id, user_id, member_type.into());
+        Ok(())
+    }
+
+    pub async fn set_notice(&self, notice: &str) -> Result<(), AdminError> {
+        self.require_role("set notice", AdminRole::HostOrManager)?;
+
+        info!("Set notice of channel_id={}", self.channel_id);
+        let client = TalkClient(&self.client.talk_client.connection().session);
+        client
+            .set_meta(&SetMetaReq {
+                chat_id: self.channel_id,
+                meta_type: META_TYPE_NOTICE,
+                content: notice.to_owned(),
+            })
+            .await?;
+        info!("Set notice successfully");
+        Ok(())
+    }
+
+    // None removes the passcode
+    pub async fn set_passcode(&self, passcode: Option<&str>) -> Result<(), AdminError> {
+        self.require_role("set passcode", AdminRole::Host)?;
+
+        info!(
+            "Set passcode of link_id={} enabled: {}",
+            self.link_id,
+            passcode.is_some()
+        );
+        let client = TalkClient(&self.client.talk_client.connection().session);
+        client
+            .update_link(&UpdateLinkReq {
+                link_id: self.link_id,
+                passcode: passcode.map(|x| x.to_owned()),
+            })
+            .await?;
+        info!("Set passcode successfully");
+        Ok(())
+    }
+
+    // Blinded users' chats are hidden from everyone until they are unblinded
+    pub async fn set_blinded(&self, user_id: i64, blind: bool) -> Result<(), AdminError> {
+        self.require_role("blind user", AdminRole::HostOrManager)?;
+
+        info!(
+            "Set user {} in channel_id={} blinded: {}",
+            user_id, self.channel_id, blind
+        );
+        let client = TalkClient(&self.client.talk_client.connection().session);
+        client
+            .blind_user(&BlindUserReq {
+                chat_id: self.channel_id,
+                link_id: self.link_id,
+                user_id,
+                blind,
+            })
+            .await?;
+        info!("Set blinded successfully");
+        Ok(())
+    }
+
+    pub async fn kicked_members(&self) -> Result<Vec<KickedMember>, AdminError> {
+        self.require_role("get kick list", AdminRole::HostOrManager)?;
+
+        info!("Get kick list of channel_id={}", self.channel_id);
+        let client = TalkClient(&self.client.talk_client.connection().session);
+        let res = client
+            .get_kick_list(&KickListReq {
+                chat_id: self.channel_id,
+                link_id: self.link_id,
+            })
+            .await?;
+        info!("Got {} kicked members", res.kicked_members.len());
+        Ok(res.kicked_members)
+    }
+
+    // Lets a kicked user join again
+    pub async fn unban_user(&self, user_id: i64) -> Result<(), AdminError> {
+        self.require_role("unban user", AdminRole::HostOrManager)?;
+
+        info!("Unban user {} in channel_id={}", user_id, self.channel_id);
+        let client = TalkClient(&self.client.talk_client.connection().session);
+        client
+            .delete_kicked_user(&KickListDelItemReq {
+                chat_id: self.channel_id,
+                link_id: self.link_id,
+                user_id,
+            })
+            .await?;
+        info!("Unbanned user successfully");
+        Ok(())
+    }
+}
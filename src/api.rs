@@ -0,0 +1,1383 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use futures::{StreamExt, TryStreamExt};
+use hyper::{
+    body::HttpBody,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use kiwi_talk_client::chat::Chatlog;
+use log::*;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::{
+    fs,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    admin::{AdminError, ChannelAdmin},
+    archive::{ArchivedChat, ArchivedMedia, ChatQuery},
+    audit::AuditQuery,
+    builder::{Emoticon, MessageBuilder},
+    channel::{ChannelError, OpenChannel},
+    config::ApiCfg,
+    history::{history, HistoryQuery},
+    join::JoinError,
+    kakao::{KakaoClient, OpenMemberType},
+    media::{MediaKind, MediaUpload},
+    moderation::{ModerationError, Trigger},
+    users::ChannelPresence,
+};
+
+const MAX_BODY_BYTES: usize = 64 * 1024;
+// Requests waiting for the event loop before new ones are refused
+const QUEUE_SIZE: usize = 32;
+// Audit entries listed when no limit is given, exports are not limited
+const AUDIT_LIMIT: usize = 100;
+const USERS_LIMIT: usize = 20;
+const MESSAGES_LIMIT: usize = 100;
+const ARCHIVE_LIMIT: usize = 100;
+
+#[derive(Debug)]
+pub enum ApiCommand {
+    ListChannels,
+    ListLinks,
+    JoinChannel {
+        link_url: String,
+        nickname: String,
+        passcode: Option<String>,
+        profile_path: Option<String>,
+    },
+    GetChatLogs {
+        channel_id: i64,
+        since: i64,
+        until: Option<i64>,
+        until_time: Option<i64>,
+        limit: usize,
+    },
+    LeaveChannel {
+        channel_id: i64,
+    },
+    SendMessage {
+        channel_id: i64,
+        message: SendBody,
+    },
+    HideMessage {
+        channel_id: i64,
+        log_id: i64,
+        chat_type: Option<i32>,
+    },
+    DeleteMessage {
+        channel_id: i64,
+        log_id: i64,
+    },
+    KickUser {
+        channel_id: i64,
+        user_id: i64,
+    },
+    GetUser {
+        user_id: i64,
+    },
+    FindUsers {
+        nickname: String,
+        limit: usize,
+    },
+    GetMembers {
+        channel_id: i64,
+        role: Option<OpenMemberType>,
+    },
+    SetManager {
+        channel_id: i64,
+        user_id: i64,
+        manager: bool,
+    },
+    SetNotice {
+        channel_id: i64,
+        text: String,
+    },
+    SetPasscode {
+        channel_id: i64,
+        passcode: Option<String>,
+    },
+    SetBlinded {
+        channel_id: i64,
+        user_id: i64,
+        blind: bool,
+    },
+    GetKickedMembers {
+        channel_id: i64,
+    },
+    UnbanUser {
+        channel_id: i64,
+        user_id: i64,
+    },
+    QueryAudit {
+        query: AuditQuery,
+    },
+    ExportAudit {
+        query: AuditQuery,
+    },
+    QueryArchive {
+        query: ChatQuery,
+    },
+    GetArchivedChat {
+        log_id: i64,
+    },
+    FindMedia {
+        sha256: String,
+    },
+    GetShadowRecords {
+        channel_id: Option<i64>,
+    },
+    SetShadowDefault {
+        enabled: bool,
+    },
+}
+
+#[derive(Debug)]
+pub struct ApiResponse {
+    status: StatusCode,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl ApiResponse {
+    fn ok(body: Value) -> Self {
+        Self::json(StatusCode::OK, body)
+    }
+
+    // One JSON object per line
+    fn jsonl(body: Vec<u8>) -> Self {
+        Self {
+            status: StatusCode::OK,
+            content_type: "application/x-ndjson",
+            body,
+        }
+    }
+
+    fn error(status: StatusCode, message: impl fmt::Display) -> Self {
+        Self::json(status, json!({ "error": message.to_string() }))
+    }
+
+    fn json(status: StatusCode, body: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn into_response(self) -> Response<Body> {
+        Response::builder()
+            .status(self.status)
+            .header(CONTENT_TYPE, self.content_type)
+            .body(Body::from(self.body))
+            .unwrap()
+    }
+}
+
+// A command from the HTTP server, run by KakaoClient::next_event between events
+#[derive(Debug)]
+pub struct ApiRequest {
+    command: ApiCommand,
+    reply: oneshot::Sender<ApiResponse>,
+}
+
+impl ApiRequest {
+    pub async fn run(self, client: &mut KakaoClient) {
+        debug!("Run api command {:?}", self.command);
+        let response = execute(client, self.command).await;
+        // The HTTP client may have hung up
+        let _ = self.reply.send(response);
+    }
+}
+
+async fn execute(client: &mut KakaoClient, command: ApiCommand) -> ApiResponse {
+    match command {
+        ApiCommand::ListChannels => {
+            let mut channels: Vec<&OpenChannel> = client.channels().collect();
+            channels.sort_by_key(|channel| channel.channel_id);
+            ApiResponse::ok(Value::Array(
+                channels.into_iter().map(channel_json).collect(),
+            ))
+        }
+        ApiCommand::ListLinks => match client.joined_links().await {
+            Ok(links) => ApiResponse::ok(Value::Array(
+                links
+                    .into_iter()
+                    .map(|(channel, link)| {
+                        json!({
+                            "channel_id": channel.channel_id,
+                            "link_id": link.link_id,
+                            "name": link.link_name,
+                            "url": link.link_url,
+                            "link_type": link.link_type,
+                        })
+                    })
+                    .collect(),
+            )),
+            Err(err) => ApiResponse::error(StatusCode::BAD_GATEWAY, err),
+        },
+        ApiCommand::JoinChannel {
+            link_url,
+            nickname,
+            passcode,
+            profile_path,
+        } => {
+            let res = client
+                .join_channel(
+                    &link_url,
+                    &nickname,
+                    profile_path.as_deref(),
+                    passcode.as_deref(),
+                )
+                .await;
+            match res {
+                Ok(channel) => ApiResponse::ok(channel_json(&channel)),
+                // Lets automated joiners tell whether to retry, change the request or give up
+                Err(err) => ApiResponse::json(
+                    join_error_status(&err),
+                    json!({
+                        "error": err.to_string(),
+                        "retryable": err.is_retryable(),
+                        "permanent": err.is_permanent(),
+                    }),
+                ),
+            }
+        }
+        ApiCommand::GetChatLogs {
+            channel_id,
+            since,
+            until,
+            until_time,
+            limit,
+        } => {
+            let mut query = HistoryQuery::new(channel_id, since);
+            if let Some(until) = until {
+                query = query.until_log_id(until);
+            }
+            if let Some(until_time) = until_time {
+                query = query.until_time(until_time);
+            }
+            let chat_logs: Result<Vec<Chatlog>> =
+                history(client, query).take(limit).try_collect().await;
+            match chat_logs {
+                Ok(chat_logs) => {
+                    ApiResponse::ok(Value::Array(chat_logs.iter().map(chatlog_json).collect()))
+                }
+                Err(err) => ApiResponse::error(StatusCode::BAD_GATEWAY, format!("{:#}", err)),
+            }
+        }
+        ApiCommand::LeaveChannel { channel_id } => {
+            let Some(channel) = client.channel(channel_id) else {
+                return unknown_channel(channel_id);
+            };
+            match channel.leave(client).await {
+                Ok(()) => ApiResponse::ok(json!({})),
+                Err(err) => ApiResponse::error(StatusCode::BAD_GATEWAY, err),
+            }
+        }
+        ApiCommand::SendMessage {
+            channel_id,
+            message,
+        } => {
+            let Some(channel) = client.channel(channel_id) else {
+                return unknown_channel(channel_id);
+            };
+            match send(client, &channel, message).await {
+                Ok(chatlog) => ApiResponse::ok(json!({
+                    "log_id": chatlog.log_id,
+                    "send_at": chatlog.send_at,
+                })),
+                Err(res) => res,
+            }
+        }
+        ApiCommand::HideMessage {
+            channel_id,
+            log_id,
+            chat_type,
+        } => {
+            let Some(channel) = client.channel(channel_id) else {
+                return unknown_channel(channel_id);
+            };
+            let chat_type = match chat_type {
+                Some(chat_type) => chat_type,
+                None => match archived_chat_type(client, channel_id, log_id) {
+                    Ok(chat_type) => chat_type,
+                    Err(res) => return res,
+                },
+            };
+            let res = channel.hide(client, log_id, chat_type, &operator()).await;
+            moderation_result(client, channel_id, res)
+        }
+        ApiCommand::DeleteMessage { channel_id, log_id } => {
+            let Some(channel) = client.channel(channel_id) else {
+                return unknown_channel(channel_id);
+            };
+            let res = channel.delete(client, log_id, &operator()).await;
+            moderation_result(client, channel_id, res)
+        }
+        ApiCommand::KickUser {
+            channel_id,
+            user_id,
+        } => {
+            let Some(channel) = client.channel(channel_id) else {
+                return unknown_channel(channel_id);
+            };
+            let res = channel.kick(client, user_id, &operator()).await;
+            moderation_result(client, channel_id, res)
+        }
+        ApiCommand::GetUser { user_id } => match user_json(client, user_id) {
+            Ok(Some(user)) => ApiResponse::ok(user),
+            Ok(None) => {
+                ApiResponse::error(StatusCode::NOT_FOUND, format!("unknown user {}", user_id))
+            }
+            Err(err) => ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, err),
+        },
+        ApiCommand::FindUsers { nickname, limit } => {
+            match client.users.find_by_nickname(&nickname, limit) {
+                Ok(users) => ApiResponse::ok(Value::Array(
+                    users
+                        .into_iter()
+                        .map(|user| {
+                            json!({
+                                "user_id": user.user_id,
+                                "nickname": user.nickname,
+                                "image_url": user.image_url,
+                                "last_seen": user.last_seen,
+                            })
+                        })
+                        .collect(),
+                )),
+                Err(err) => ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, err),
+            }
+        }
+        ApiCommand::GetMembers { channel_id, role } => {
+            let Some(channel) = client.channel(channel_id) else {
+                return unknown_channel(channel_id);
+            };
+            match members_json(client, &channel, role) {
+                Ok(members) => ApiResponse::ok(members),
+                Err(err) => ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, err),
+            }
+        }
+        ApiCommand::SetManager {
+            channel_id,
+            user_id,
+            manager,
+        } => {
+            let res = async {
+                ChannelAdmin::new(client, channel_id)?
+                    .set_manager(user_id, manager)
+                    .await
+            }
+            .await;
+            admin_result(res, |()| json!({}))
+        }
+        ApiCommand::SetNotice { channel_id, text } => {
+            let res = async {
+                ChannelAdmin::new(client, channel_id)?
+                    .set_notice(&text)
+                    .await
+            }
+            .await;
+            admin_result(res, |()| json!({}))
+        }
+        ApiCommand::SetPasscode {
+            channel_id,
+            passcode,
+        } => {
+            let res = async {
+                ChannelAdmin::new(client, channel_id)?
+                    .set_passcode(passcode.as_deref())
+                    .await
+            }
+            .await;
+            admin_result(res, |()| json!({}))
+        }
+        ApiCommand::SetBlinded {
+            channel_id,
+            user_id,
+            blind,
+        } => {
+            let res = async {
+                ChannelAdmin::new(client, channel_id)?
+                    .set_blinded(user_id, blind)
+                    .await
+            }
+            .await;
+            admin_result(res, |()| json!({}))
+        }
+        ApiCommand::GetKickedMembers { channel_id } => {
+            let res = async {
+                ChannelAdmin::new(client, channel_id)?
+                    .kicked_members()
+                    .await
+            }
+            .await;
+            admin_result(res, |members| {
+                Value::Array(
+                    members
+                        .into_iter()
+                        .map(|member| {
+                            json!({
+                                "user_id": member.user_id,
+                                "nickname": member.nickname,
+                                "image_url": member.profile_image_url,
+                            })
+                        })
+                        .collect(),
+                )
+            })
+        }
+        ApiCommand::UnbanUser {
+            channel_id,
+            user_id,
+        } => {
+            let res = async {
+                ChannelAdmin::new(client, channel_id)?
+                    .unban_user(user_id)
+                    .await
+            }
+            .await;
+            admin_result(res, |()| json!({}))
+        }
+        ApiCommand::QueryAudit { query } => {
+            let Some(audit_log) = &client.audit else {
+                return audit_disabled();
+            };
+            match audit_log.query(&query) {
+                Ok(entries) => ApiResponse::ok(json!(entries)),
+                Err(err) => ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, err),
+            }
+        }
+        ApiCommand::ExportAudit { query } => {
+            let Some(audit_log) = &client.audit else {
+                return audit_disabled();
+            };
+            let mut jsonl = Vec::new();
+            match audit_log.export_jsonl(&query, &mut jsonl) {
+                Ok(_) => ApiResponse::jsonl(jsonl),
+                Err(err) => ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, err),
+            }
+        }
+        ApiCommand::QueryArchive { query } => {
+            let Some(archive) = &client.archive else {
+                return archive_disabled();
+            };
+            match archive.query(&query) {
+                Ok(chats) => {
+                    ApiResponse::ok(Value::Array(chats.iter().map(archived_chat_json).collect()))
+                }
+                // Mostly malformed text match expressions
+                Err(err) => ApiResponse::error(StatusCode::BAD_REQUEST, format!("{:#}", err)),
+            }
+        }
+        ApiCommand::GetArchivedChat { log_id } => {
+            let Some(archive) = &client.archive else {
+                return archive_disabled();
+            };
+            let chat = archive
+                .get(log_id)
+                .and_then(|chat| Ok((chat, archive.media_of(log_id)?)));
+            match chat {
+                Ok((Some(chat), media)) => {
+                    let mut chat_json = archived_chat_json(&chat);
+                    chat_json["media"] = media.iter().map(media_json).collect();
+                    ApiResponse::ok(chat_json)
+                }
+                Ok((None, _)) => ApiResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("chat {} is not archived", log_id),
+                ),
+                Err(err) => ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, err),
+            }
+        }
+        ApiCommand::FindMedia { sha256 } => {
+            let Some(archive) = &client.archive else {
+                return archive_disabled();
+            };
+            match archive.media_by_hash(&sha256) {
+                Ok(media) => ApiResponse::ok(Value::Array(media.iter().map(media_json).collect())),
+                Err(err) => ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, err),
+            }
+        }
+        ApiCommand::GetShadowRecords { channel_id } => ApiResponse::ok(Value::Array(
+            client
+                .shadow
+                .records()
+                .filter(|record| {
+                    channel_id.map_or(true, |channel_id| record.channel_id == channel_id)
+                })
+                .map(|record| {
+                    json!({
+                        "at": record.at,
+                        "channel_id": record.channel_id,
+                        "log_id": record.log_id,
+                        "user_id": record.user_id,
+                        "message": record.message,
+                        "action": record.action,
+                        "reason": record.reason,
+                    })
+                })
+                .collect(),
+        )),
+        ApiCommand::SetShadowDefault { enabled } => {
+            client.shadow.set_default(enabled);
+            ApiResponse::ok(json!({ "enabled": enabled }))
+        }
+    }
+}
+
+fn chatlog_json(chatlog: &Chatlog) -> Value {
+    json!({
+        "log_id": chatlog.log_id,
+        "prev_log_id": chatlog.prev_log_id,
+        "sender_id": chatlog.sender_id,
+        "send_at": chatlog.send_at,
+        "chat_type": chatlog.chat.chat_type.0,
+        "message": chatlog.chat.content.message,
+        "attachment": chatlog.chat.content.attachment,
+    })
+}
+
+fn channel_json(channel: &OpenChannel) -> Value {
+    json!({
+        "channel_id": channel.channel_id,
+        "kind": if channel.is_open() { "open" } else { "normal" },
+        "link_id": channel.link_id(),
+        "name": channel.name,
+    })
+}
+
+fn archived_chat_json(chat: &ArchivedChat) -> Value {
+    json!({
+        "log_id": chat.log_id,
+        "prev_log_id": chat.prev_log_id,
+        "channel_id": chat.channel_id,
+        "link_id": chat.link_id,
+        "sender_id": chat.sender_id,
+        "sender_nickname": chat.sender_nickname,
+        "send_at": chat.send_at,
+        "chat_type": chat.chat_type,
+        "message": chat.message,
+        "attachment": chat.attachment,
+        "supplement": chat.supplement,
+        "message_id": chat.message_id,
+        "deleted_at": chat.deleted_at,
+        "hidden_at": chat.hidden_at,
+    })
+}
+
+fn media_json(media: &ArchivedMedia) -> Value {
+    json!({
+        "channel_id": media.channel_id,
+        "log_id": media.log_id,
+        "url": media.url,
+        "path": media.path,
+        "sha256": media.sha256,
+        "size": media.size,
+        "mime_type": media.mime_type,
+        "downloaded_at": media.downloaded_at,
+    })
+}
+
+// The user with their nickname history and the channels they were seen in
+fn user_json(client: &KakaoClient, user_id: i64) -> Result<Option<Value>> {
+    let Some(user) = client.users.get(user_id)? else {
+        return Ok(None);
+    };
+    let nicknames: Vec<Value> = client
+        .users
+        .nickname_history(user_id)?
+        .into_iter()
+        .map(|change| {
+            json!({
+                "channel_id": change.channel_id,
+                "nickname": change.nickname,
+                "changed_at": change.changed_at,
+            })
+        })
+        .collect();
+    let channels: Vec<Value> = client
+        .users
+        .channels_of(user_id)?
+        .into_iter()
+        .map(presence_json)
+        .collect();
+
+    Ok(Some(json!({
+        "user_id": user.user_id,
+        "nickname": user.nickname,
+        "image_url": user.image_url,
+        "first_seen": user.first_seen,
+        "last_seen": user.last_seen,
+        "nicknames": nicknames,
+        "channels": channels,
+    })))
+}
+
+// Current members from the roster, with when the user registry first and last saw them there
+fn members_json(
+    client: &KakaoClient,
+    channel: &OpenChannel,
+    role: Option<OpenMemberType>,
+) -> Result<Value> {
+    let presences: HashMap<i64, ChannelPresence> = client
+        .users
+        .members_of(channel.channel_id)?
+        .into_iter()
+        .map(|presence| (presence.user_id, presence))
+        .collect();
+    let members = match role {
+        Some(role) => client.roster.with_role(channel.channel_id, role),
+        None => channel.members(client),
+    };
+
+    Ok(Value::Array(
+        members
+            .into_iter()
+            .map(|member| {
+                let presence = presences.get(&member.user_id);
+                json!({
+                    "user_id": member.user_id,
+                    "nickname": member.nickname,
+                    "image_url": member.image_url,
+                    "role": format!("{:?}", member.role).to_lowercase(),
+                    "joined_at": member.joined_at,
+                    "first_seen": presence.map(|presence| presence.first_seen),
+                    "last_seen": presence.map(|presence| presence.last_seen),
+                })
+            })
+            .collect(),
+    ))
+}
+
+fn presence_json(presence: ChannelPresence) -> Value {
+    json!({
+        "channel_id": presence.channel_id,
+        "user_id": presence.user_id,
+        "nickname": presence.nickname,
+        "first_seen": presence.first_seen,
+        "last_seen": presence.last_seen,
+        "left_at": presence.left_at,
+    })
+}
+
+fn unknown_channel(channel_id: i64) -> ApiResponse {
+    ApiResponse::error(
+        StatusCode::NOT_FOUND,
+        format!("not in channel {}", channel_id),
+    )
+}
+
+fn audit_disabled() -> ApiResponse {
+    ApiResponse::error(StatusCode::NOT_FOUND, "the audit log is disabled")
+}
+
+// Hiding with the wrong type fails on the server, so guessing is not an option
+fn archived_chat_type(
+    client: &KakaoClient,
+    channel_id: i64,
+    log_id: i64,
+) -> Result<i32, ApiResponse> {
+    let Some(archive) = &client.archive else {
+        return Err(ApiResponse::error(
+            StatusCode::BAD_REQUEST,
+            "chat_type is required when the archive is disabled",
+        ));
+    };
+    archive
+        .get(log_id)
+        .map_err(|err| ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, err))?
+        .filter(|chat| chat.channel_id == channel_id)
+        .map(|chat| chat.chat_type)
+        .ok_or_else(|| {
+            ApiResponse::error(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "chat {} is not archived in this channel, chat_type is required",
+                    log_id
+                ),
+            )
+        })
+}
+
+fn archive_disabled() -> ApiResponse {
+    ApiResponse::error(StatusCode::NOT_FOUND, "the archive is disabled")
+}
+
+// Builds the chat the body describes and sends it, photos and files are uploaded first
+async fn send(
+    client: &mut KakaoClient,
+    channel: &OpenChannel,
+    message: SendBody,
+) -> Result<Chatlog, ApiResponse> {
+    let builder = match message {
+        SendBody::Text(text) => MessageBuilder::text(text),
+        SendBody::Parts(parts) => {
+            parts
+                .into_iter()
+                .fold(MessageBuilder::text(""), |builder, part| match part {
+                    TextPart::Text(text) => builder.push_text(&text),
+                    TextPart::Mention { user_id, nickname } => builder.mention(user_id, &nickname),
+                    TextPart::Link(url) => builder.link(&url),
+                })
+        }
+        SendBody::Reply { log_id, text } => {
+            let Some(archive) = &client.archive else {
+                return Err(archive_disabled());
+            };
+            let chat = archive
+                .get(log_id)
+                .map_err(|err| ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, err))?
+                .filter(|chat| chat.channel_id == channel.channel_id)
+                .ok_or_else(|| {
+                    ApiResponse::error(
+                        StatusCode::NOT_FOUND,
+                        format!("chat {} is not archived in this channel", log_id),
+                    )
+                })?;
+            MessageBuilder::reply(&chat, text)
+        }
+        SendBody::Emoticon {
+            path,
+            name,
+            animated,
+            width,
+            height,
+        } => MessageBuilder::emoticon(Emoticon {
+            path,
+            name,
+            animated,
+            width,
+            height,
+        }),
+        SendBody::Location {
+            lat,
+            lng,
+            title,
+            address,
+        } => MessageBuilder::location(lat, lng, &title, &address),
+        SendBody::Contact { name, url } => MessageBuilder::contact(&name, url.as_deref()),
+        SendBody::Profile { user_id, nickname } => MessageBuilder::profile(user_id, &nickname),
+        SendBody::Photo {
+            path,
+            width,
+            height,
+        } => {
+            let mut upload = read_upload(client, MediaKind::Photo, &path).await?;
+            if let (Some(width), Some(height)) = (width, height) {
+                upload = upload.dimensions(width, height);
+            }
+            return send_media(client, channel, &upload).await;
+        }
+        SendBody::File { path } => {
+            let upload = read_upload(client, MediaKind::File, &path).await?;
+            return send_media(client, channel, &upload).await;
+        }
+    };
+
+    channel
+        .send(client, builder.build())
+        .await
+        .map_err(|err| ApiResponse::error(StatusCode::BAD_GATEWAY, err))
+}
+
+// Only files inside api.upload_dir can be sent, the token must not be enough to read the
+// config or the stored credential. Symlinks and ".." are resolved before the check
+async fn read_upload(
+    client: &KakaoClient,
+    kind: MediaKind,
+    path: &Path,
+) -> Result<MediaUpload, ApiResponse> {
+    let cfg = &client.cfg;
+    let upload_dir = fs::canonicalize(cfg.system.data_dir.join(&cfg.api.upload_dir))
+        .await
+        .map_err(|err| {
+            ApiResponse::error(
+                StatusCode::NOT_FOUND,
+                format!("api.upload_dir is not usable: {}", err),
+            )
+        })?;
+    let file = fs::canonicalize(upload_dir.join(path))
+        .await
+        .map_err(|err| {
+            ApiResponse::error(
+                StatusCode::BAD_REQUEST,
+                format!("{}: {}", path.display(), err),
+            )
+        })?;
+    if !file.starts_with(&upload_dir) {
+        return Err(ApiResponse::error(
+            StatusCode::FORBIDDEN,
+            format!("{} is outside api.upload_dir", path.display()),
+        ));
+    }
+
+    MediaUpload::from_path(kind, &file)
+        .await
+        .map_err(|err| ApiResponse::error(StatusCode::BAD_REQUEST, format!("{:#}", err)))
+}
+
+async fn send_media(
+    client: &mut KakaoClient,
+    channel: &OpenChannel,
+    upload: &MediaUpload,
+) -> Result<Chatlog, ApiResponse> {
+    client
+        .send_media(channel.channel_id, upload)
+        .await
+        .map_err(|err| ApiResponse::error(StatusCode::BAD_GATEWAY, format!("{:#}", err)))
+}
+
+fn operator() -> Trigger {
+    Trigger::Operator {
+        via: "api".to_owned(),
+    }
+}
+
+// Moderation goes through moderation::apply like every other action, so shadow mode and the
+// audit log apply
+fn moderation_result(
+    client: &KakaoClient,
+    channel_id: i64,
+    res: Result<(), ChannelError>,
+) -> ApiResponse {
+    match res {
+        Ok(()) => ApiResponse::ok(json!({
+            "shadowed": client.shadow.is_enabled(channel_id),
+        })),
+        Err(err @ ChannelError::Moderation(ModerationError::NotOpenChannel { .. })) => {
+            ApiResponse::error(StatusCode::CONFLICT, err)
+        }
+        Err(err @ ChannelError::Moderation(ModerationError::MissingTarget { .. })) => {
+            ApiResponse::error(StatusCode::BAD_REQUEST, err)
+        }
+        Err(err) => ApiResponse::error(StatusCode::BAD_GATEWAY, err),
+    }
+}
+
+fn admin_result<T>(res: Result<T, AdminError>, body: impl FnOnce(T) -> Value) -> ApiResponse {
+    let status = match &res {
+        Ok(_) => StatusCode::OK,
+        Err(AdminError::UnknownChannel(_)) => StatusCode::NOT_FOUND,
+        Err(AdminError::NotOpenChannel(_)) => StatusCode::CONFLICT,
+        Err(AdminError::PermissionDenied { .. }) => StatusCode::FORBIDDEN,
+        Err(AdminError::Request(_)) => StatusCode::BAD_GATEWAY,
+    };
+    match res {
+        Ok(value) => ApiResponse::ok(body(value)),
+        Err(err) => ApiResponse::error(status, err),
+    }
+}
+
+fn join_error_status(err: &JoinError) -> StatusCode {
+    match err {
+        JoinError::InvalidLink => StatusCode::NOT_FOUND,
+        JoinError::MissingPasscode | JoinError::WrongPasscode | JoinError::Banned => {
+            StatusCode::FORBIDDEN
+        }
+        JoinError::RoomFull => StatusCode::CONFLICT,
+        JoinError::NicknameRejected => StatusCode::UNPROCESSABLE_ENTITY,
+        JoinError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        JoinError::Status { .. } | JoinError::Request { .. } => StatusCode::BAD_GATEWAY,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JoinBody {
+    link_url: String,
+    nickname: String,
+    passcode: Option<String>,
+    profile_path: Option<String>,
+}
+
+// One kind of message per body, e.g. {"text": "hi"} or {"location": {"lat": 37.5, "lng": 127.0}}
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum SendBody {
+    Text(String),
+    // Joined in order into one text chat
+    Parts(Vec<TextPart>),
+    // Quotes an archived chat of the same channel
+    Reply {
+        log_id: i64,
+        text: String,
+    },
+    Emoticon {
+        path: String,
+        name: String,
+        #[serde(default)]
+        animated: bool,
+        width: Option<u32>,
+        height: Option<u32>,
+    },
+    Location {
+        lat: f64,
+        lng: f64,
+        #[serde(default)]
+        title: String,
+        #[serde(default)]
+        address: String,
+    },
+    Contact {
+        name: String,
+        url: Option<String>,
+    },
+    Profile {
+        user_id: i64,
+        nickname: String,
+    },
+    // Paths are relative to api.upload_dir
+    Photo {
+        path: PathBuf,
+        width: Option<u32>,
+        height: Option<u32>,
+    },
+    File {
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum TextPart {
+    Text(String),
+    Mention { user_id: i64, nickname: String },
+    Link(String),
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HideBody {
+    // Looked up in the archive when missing
+    chat_type: Option<i32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KickBody {
+    user_id: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NoticeBody {
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PasscodeBody {
+    passcode: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShadowBody {
+    enabled: bool,
+}
+
+// Starts the server on its own task, requests come out of the returned receiver.
+// Hand it to KakaoClient::attach_api so they run between events
+pub fn serve(cfg: &ApiCfg) -> Result<mpsc::Receiver<ApiRequest>> {
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    let token: Arc<str> = Arc::from(cfg.token.as_str());
+
+    let make_service = make_service_fn(move |_| {
+        let sender = sender.clone();
+        let token = token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let sender = sender.clone();
+                let token = token.clone();
+                async move { Ok::<_, Infallible>(handle(req, &token, sender).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&cfg.bind)
+        .with_context(|| format!("bind api to {}", cfg.bind))?
+        .serve(make_service);
+    if !cfg.bind.ip().is_loopback() {
+        warn!("Admin api listens on non-loopback address {}", cfg.bind);
+    }
+    info!("Admin api listening on http://{}", server.local_addr());
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("Admin api stopped: {:?}", err);
+        }
+    });
+    Ok(receiver)
+}
+
+async fn handle(
+    req: Request<Body>,
+    token: &str,
+    sender: mpsc::Sender<ApiRequest>,
+) -> Response<Body> {
+    if !authorized(&req, token) {
+        return ApiResponse::error(StatusCode::UNAUTHORIZED, "missing or wrong bearer token")
+            .into_response();
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let query = parse_query(req.uri().query().unwrap_or_default());
+    let body = match read_body(req.into_body()).await {
+        Ok(body) => body,
+        Err(res) => return res.into_response(),
+    };
+
+    let command = match route(&method, &path, &query, &body) {
+        Ok(command) => command,
+        Err(res) => return res.into_response(),
+    };
+    info!("Api {} {}", method, path);
+
+    let (reply, response) = oneshot::channel();
+    if sender.try_send(ApiRequest { command, reply }).is_err() {
+        return ApiResponse::error(StatusCode::SERVICE_UNAVAILABLE, "bot is busy").into_response();
+    }
+    match response.await {
+        Ok(response) => response.into_response(),
+        Err(_) => ApiResponse::error(StatusCode::SERVICE_UNAVAILABLE, "bot is shutting down")
+            .into_response(),
+    }
+}
+
+// Compares in constant time so the token can't be guessed byte by byte
+fn authorized(req: &Request<Body>, token: &str) -> bool {
+    let Some(given) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, ApiResponse> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| ApiResponse::error(StatusCode::BAD_REQUEST, err))?;
+        if data.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(ApiResponse::error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "body is too large",
+            ));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+        .collect()
+}
+
+// Query values such as nicknames come percent encoded, malformed escapes are kept as they are
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn route(
+    method: &Method,
+    path: &str,
+    query: &HashMap<String, String>,
+    body: &[u8],
+) -> Result<ApiCommand, ApiResponse> {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    let command = match (method, segments.as_slice()) {
+        (&Method::GET, ["channels"]) => ApiCommand::ListChannels,
+        (&Method::GET, ["links"]) => ApiCommand::ListLinks,
+        (&Method::POST, ["channels", "join"]) => {
+            let body: JoinBody = json_body(body)?;
+            ApiCommand::JoinChannel {
+                link_url: body.link_url,
+                nickname: body.nickname,
+                passcode: body.passcode,
+                profile_path: body.profile_path,
+            }
+        }
+        (&Method::GET, ["channels", channel_id, "messages"]) => ApiCommand::GetChatLogs {
+            channel_id: id(channel_id)?,
+            since: match query.get("since") {
+                Some(since) => id(since)?,
+                None => 0,
+            },
+            until: query.get("until").map(|until| id(until)).transpose()?,
+            until_time: query
+                .get("until_time")
+                .map(|until_time| number("until_time", until_time))
+                .transpose()?,
+            limit: match query.get("limit") {
+                Some(limit) => number("limit", limit)?,
+                None => MESSAGES_LIMIT,
+            },
+        },
+        (&Method::DELETE, ["channels", channel_id]) => ApiCommand::LeaveChannel {
+            channel_id: id(channel_id)?,
+        },
+        (&Method::POST, ["channels", channel_id, "messages"]) => ApiCommand::SendMessage {
+            channel_id: id(channel_id)?,
+            message: json_body(body)?,
+        },
+        (&Method::POST, ["channels", channel_id, "messages", log_id, "hide"]) => {
+            let body: HideBody = match body.is_empty() {
+                true => HideBody::default(),
+                false => json_body(body)?,
+            };
+            ApiCommand::HideMessage {
+                channel_id: id(channel_id)?,
+                log_id: id(log_id)?,
+                chat_type: body.chat_type,
+            }
+        }
+        (&Method::DELETE, ["channels", channel_id, "messages", log_id]) => {
+            ApiCommand::DeleteMessage {
+                channel_id: id(channel_id)?,
+                log_id: id(log_id)?,
+            }
+        }
+        (&Method::POST, ["channels", channel_id, "kick"]) => {
+            let body: KickBody = json_body(body)?;
+            ApiCommand::KickUser {
+                channel_id: id(channel_id)?,
+                user_id: body.user_id,
+            }
+        }
+        (&Method::PUT, ["channels", channel_id, "managers", user_id])
+        | (&Method::DELETE, ["channels", channel_id, "managers", user_id]) => {
+            ApiCommand::SetManager {
+                channel_id: id(channel_id)?,
+                user_id: id(user_id)?,
+                manager: method == Method::PUT,
+            }
+        }
+        (&Method::PUT, ["channels", channel_id, "notice"]) => {
+            let body: NoticeBody = json_body(body)?;
+            ApiCommand::SetNotice {
+                channel_id: id(channel_id)?,
+                text: body.text,
+            }
+        }
+        (&Method::PUT, ["channels", channel_id, "passcode"]) => {
+            let body: PasscodeBody = json_body(body)?;
+            ApiCommand::SetPasscode {
+                channel_id: id(channel_id)?,
+                passcode: Some(body.passcode),
+            }
+        }
+        (&Method::DELETE, ["channels", channel_id, "passcode"]) => ApiCommand::SetPasscode {
+            channel_id: id(channel_id)?,
+            passcode: None,
+        },
+        (&Method::PUT, ["channels", channel_id, "blinded", user_id])
+        | (&Method::DELETE, ["channels", channel_id, "blinded", user_id]) => {
+            ApiCommand::SetBlinded {
+                channel_id: id(channel_id)?,
+                user_id: id(user_id)?,
+                blind: method == Method::PUT,
+            }
+        }
+        (&Method::GET, ["channels", channel_id, "kicked"]) => ApiCommand::GetKickedMembers {
+            channel_id: id(channel_id)?,
+        },
+        (&Method::DELETE, ["channels", channel_id, "kicked", user_id]) => ApiCommand::UnbanUser {
+            channel_id: id(channel_id)?,
+            user_id: id(user_id)?,
+        },
+        (&Method::GET, ["users"]) => ApiCommand::FindUsers {
+            nickname: query
+                .get("nickname")
+                .filter(|nickname| !nickname.is_empty())
+                .cloned()
+                .ok_or_else(|| {
+                    ApiResponse::error(StatusCode::BAD_REQUEST, "missing nickname query")
+                })?,
+            limit: match query.get("limit") {
+                Some(limit) => number("limit", limit)?,
+                None => USERS_LIMIT,
+            },
+        },
+        (&Method::GET, ["users", user_id]) => ApiCommand::GetUser {
+            user_id: id(user_id)?,
+        },
+        (&Method::GET, ["channels", channel_id, "members"]) => ApiCommand::GetMembers {
+            channel_id: id(channel_id)?,
+            role: query
+                .get("role")
+                .map(|role| member_role(role))
+                .transpose()?,
+        },
+        (&Method::GET, ["audit"]) => {
+            let mut query = audit_query(query)?;
+            query.limit.get_or_insert(AUDIT_LIMIT);
+            ApiCommand::QueryAudit { query }
+        }
+        (&Method::GET, ["audit", "export"]) => ApiCommand::ExportAudit {
+            query: audit_query(query)?,
+        },
+        (&Method::GET, ["archive"]) => {
+            let optional =
+                |name: &str| query.get(name).map(|value| number(name, value)).transpose();
+            ApiCommand::QueryArchive {
+                query: ChatQuery {
+                    channel_id: optional("channel_id")?,
+                    sender_id: optional("user_id")?,
+                    since: optional("since")?,
+                    until: optional("until")?,
+                    text: query.get("text").cloned(),
+                    limit: Some(match query.get("limit") {
+                        Some(limit) => number("limit", limit)?,
+                        None => ARCHIVE_LIMIT,
+                    }),
+                },
+            }
+        }
+        (&Method::GET, ["archive", log_id]) => ApiCommand::GetArchivedChat {
+            log_id: id(log_id)?,
+        },
+        (&Method::GET, ["media", sha256]) => ApiCommand::FindMedia {
+            sha256: sha256.to_lowercase(),
+        },
+        (&Method::GET, ["shadow", "records"]) => ApiCommand::GetShadowRecords {
+            channel_id: query
+                .get("channel_id")
+                .map(|channel_id| id(channel_id))
+                .transpose()?,
+        },
+        (&Method::PUT, ["shadow"]) => {
+            let body: ShadowBody = json_body(body)?;
+            ApiCommand::SetShadowDefault {
+                enabled: body.enabled,
+            }
+        }
+        _ => {
+            return Err(ApiResponse::error(
+                StatusCode::NOT_FOUND,
+                format!("no route for {} {}", method, path),
+            ))
+        }
+    };
+    Ok(command)
+}
+
+fn id(segment: &str) -> Result<i64, ApiResponse> {
+    segment.parse().map_err(|_| {
+        ApiResponse::error(
+            StatusCode::BAD_REQUEST,
+            format!("'{}' is not a valid id", segment),
+        )
+    })
+}
+
+fn number<T: FromStr>(name: &str, value: &str) -> Result<T, ApiResponse> {
+    value.parse().map_err(|_| {
+        ApiResponse::error(
+            StatusCode::BAD_REQUEST,
+            format!("'{}' is not a valid {}", value, name),
+        )
+    })
+}
+
+fn member_role(role: &str) -> Result<OpenMemberType, ApiResponse> {
+    match role {
+        "host" => Ok(OpenMemberType::Host),
+        "manager" => Ok(OpenMemberType::Manager),
+        "member" => Ok(OpenMemberType::Member),
+        "bot" => Ok(OpenMemberType::Bot),
+        _ => Err(ApiResponse::error(
+            StatusCode::BAD_REQUEST,
+            format!("unknown role '{}'", role),
+        )),
+    }
+}
+
+// Same filters for listing and exporting, all optional
+fn audit_query(query: &HashMap<String, String>) -> Result<AuditQuery, ApiResponse> {
+    let optional = |name: &str| query.get(name).map(|value| number(name, value)).transpose();
+    let action = match query.get("action") {
+        Some(action) => Some(
+            serde_json::from_value(Value::String(action.clone())).map_err(|_| {
+                ApiResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    format!("unknown action '{}'", action),
+                )
+            })?,
+        ),
+        None => None,
+    };
+
+    Ok(AuditQuery {
+        channel_id: optional("channel_id")?,
+        target_user_id: optional("user_id")?,
+        action,
+        trigger_kind: query.get("trigger").cloned(),
+        since: optional("since")?,
+        until: optional("until")?,
+        limit: query
+            .get("limit")
+            .map(|limit| number("limit", limit))
+            .transpose()?,
+    })
+}
+
+fn json_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, ApiResponse> {
+    serde_json::from_slice(body).map_err(|err| {
+        ApiResponse::error(StatusCode::BAD_REQUEST, format!("invalid body: {}", err))
+    })
+}
@@ -0,0 +1,373 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use kiwi_talk_client::event::chat::ChatReceived;
+use log::*;
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension, Row};
+
+use crate::{
+    db::{self, unix_now},
+    dispatcher::{BotEvent, EventHandler, Flow},
+    kakao::KakaoClient,
+    media::DownloadedMedia,
+};
+
+const MIGRATIONS: &[&str] = &[
+    // 1: chat logs, deletions/hides and full-text index
+    "CREATE TABLE chat_logs (
+        log_id INTEGER PRIMARY KEY,
+        prev_log_id INTEGER,
+        channel_id INTEGER NOT NULL,
+        link_id INTEGER,
+        sender_id INTEGER NOT NULL,
+        sender_nickname TEXT,
+        send_at INTEGER NOT NULL,
+        chat_type INTEGER NOT NULL,
+        message TEXT,
+        attachment TEXT,
+        supplement TEXT,
+        message_id INTEGER NOT NULL,
+        deleted_at INTEGER,
+        hidden_at INTEGER
+    );
+    CREATE INDEX chat_logs_channel ON chat_logs (channel_id, send_at);
+    CREATE INDEX chat_logs_sender ON chat_logs (sender_id, send_at);
+
+    CREATE TABLE chat_log_actions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        channel_id INTEGER NOT NULL,
+        log_id INTEGER NOT NULL,
+        action TEXT NOT NULL,
+        feed_log_id INTEGER,
+        at INTEGER NOT NULL
+    );
+    CREATE INDEX chat_log_actions_log ON chat_log_actions (log_id);
+
+    CREATE VIRTUAL TABLE chat_logs_fts USING fts5 (
+        message, content = 'chat_logs', content_rowid = 'log_id'
+    );
+    CREATE TRIGGER chat_logs_fts_insert AFTER INSERT ON chat_logs BEGIN
+        INSERT INTO chat_logs_fts (rowid, message) VALUES (new.log_id, new.message);
+    END;
+    CREATE TRIGGER chat_logs_fts_delete AFTER DELETE ON chat_logs BEGIN
+        INSERT INTO chat_logs_fts (chat_logs_fts, rowid, message)
+            VALUES ('delete', old.log_id, old.message);
+    END;
+    CREATE TRIGGER chat_logs_fts_update AFTER UPDATE OF message ON chat_logs BEGIN
+        INSERT INTO chat_logs_fts (chat_logs_fts, rowid, message)
+            VALUES ('delete', old.log_id, old.message);
+        INSERT INTO chat_logs_fts (rowid, message) VALUES (new.log_id, new.message);
+    END;",
+    // 2: downloaded media, files are named after their sha256
+    "CREATE TABLE chat_media (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        channel_id INTEGER NOT NULL,
+        log_id INTEGER NOT NULL,
+        url TEXT NOT NULL,
+        path TEXT NOT NULL,
+        sha256 TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        mime_type TEXT,
+        downloaded_at INTEGER NOT NULL
+    );
+    CREATE INDEX chat_media_log ON chat_media (log_id);
+    CREATE INDEX chat_media_sha256 ON chat_media (sha256);",
+];
+
+#[derive(Debug, Clone)]
+pub struct ArchivedChat {
+    pub log_id: i64,
+    pub prev_log_id: Option<i64>,
+    pub channel_id: i64,
+    pub link_id: Option<i64>,
+    pub sender_id: i64,
+    pub sender_nickname: Option<String>,
+    pub send_at: i64,
+    pub chat_type: i32,
+    pub message: Option<String>,
+    pub attachment: Option<String>,
+    pub supplement: Option<String>,
+    pub message_id: i64,
+    pub deleted_at: Option<i64>,
+    pub hidden_at: Option<i64>,
+}
+
+impl ArchivedChat {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            log_id: row.get("log_id")?,
+            prev_log_id: row.get("prev_log_id")?,
+            channel_id: row.get("channel_id")?,
+            link_id: row.get("link_id")?,
+            sender_id: row.get("sender_id")?,
+            sender_nickname: row.get("sender_nickname")?,
+            send_at: row.get("send_at")?,
+            chat_type: row.get("chat_type")?,
+            message: row.get("message")?,
+            attachment: row.get("attachment")?,
+            supplement: row.get("supplement")?,
+            message_id: row.get("message_id")?,
+            deleted_at: row.get("deleted_at")?,
+            hidden_at: row.get("hidden_at")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchivedMedia {
+    pub channel_id: i64,
+    pub log_id: i64,
+    pub url: String,
+    pub path: PathBuf,
+    pub sha256: String,
+    pub size: u64,
+    pub mime_type: Option<String>,
+    pub downloaded_at: i64,
+}
+
+impl ArchivedMedia {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            channel_id: row.get("channel_id")?,
+            log_id: row.get("log_id")?,
+            url: row.get("url")?,
+            path: PathBuf::from(row.get::<_, String>("path")?),
+            sha256: row.get("sha256")?,
+            size: row.get::<_, i64>("size")? as u64,
+            mime_type: row.get("mime_type")?,
+            downloaded_at: row.get("downloaded_at")?,
+        })
+    }
+}
+
+// All filters are optional and combined with AND, results are newest first
+#[derive(Debug, Clone, Default)]
+pub struct ChatQuery {
+    pub channel_id: Option<i64>,
+    pub sender_id: Option<i64>,
+    // Unix seconds, inclusive
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    // FTS5 match expression over the message text
+    pub text: Option<String>,
+    pub limit: Option<usize>,
+}
+
+pub struct Archive {
+    conn: Mutex<Connection>,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = db::open(path, MIGRATIONS).context("open archive")?;
+        info!("Opened chat archive {}", path.display());
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn insert_chat(&self, received: &ChatReceived) -> Result<()> {
+        let chatlog = &received.chat;
+        self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO chat_logs (
+                log_id, prev_log_id, channel_id, link_id, sender_id, sender_nickname, send_at,
+                chat_type, message, attachment, supplement, message_id
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                chatlog.log_id,
+                chatlog.prev_log_id,
+                chatlog.channel_id,
+                received.link_id,
+                chatlog.sender_id,
+                received.user_nickname,
+                chatlog.send_at,
+                chatlog.chat.chat_type.0,
+                chatlog.chat.content.message,
+                chatlog.chat.content.attachment,
+                chatlog.chat.content.supplement,
+                chatlog.chat.message_id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // action is "deleted", "hidden" or "unhidden", feed_log_id is the feed chat announcing it
+    pub fn record_action(
+        &self,
+        channel_id: i64,
+        log_id: i64,
+        action: &str,
+        feed_log_id: Option<i64>,
+    ) -> Result<()> {
+        let at = unix_now();
+        let (column, value) = match action {
+            "deleted" => ("deleted_at", Some(at)),
+            "hidden" => ("hidden_at", Some(at)),
+            "unhidden" => ("hidden_at", None),
+            _ => bail!("unknown chat action '{}'", action),
+        };
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO chat_log_actions (channel_id, log_id, action, feed_log_id, at)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![channel_id, log_id, action, feed_log_id, at],
+        )?;
+        tx.execute(
+            &format!("UPDATE chat_logs SET {} = ?1 WHERE log_id = ?2", column),
+            params![value, log_id],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get(&self, log_id: i64) -> Result<Option<ArchivedChat>> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT * FROM chat_logs WHERE log_id = ?1",
+                [log_id],
+                ArchivedChat::from_row,
+            )
+            .optional()?)
+    }
+
+    pub fn query(&self, query: &ChatQuery) -> Result<Vec<ArchivedChat>> {
+        let mut sql = String::from("SELECT chat_logs.* FROM chat_logs");
+        let mut filters = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+
+        if let Some(text) = &query.text {
+            sql.push_str(" JOIN chat_logs_fts ON chat_logs_fts.rowid = chat_logs.log_id");
+            filters.push("chat_logs_fts MATCH ?");
+            values.push(text.clone().into());
+        }
+        if let Some(channel_id) = query.channel_id {
+            filters.push("chat_logs.channel_id = ?");
+            values.push(channel_id.into());
+        }
+        if let Some(sender_id) = query.sender_id {
+            filters.push("chat_logs.sender_id = ?");
+            values.push(sender_id.into());
+        }
+        if let Some(since) = query.since {
+            filters.push("chat_logs.send_at >= ?");
+            values.push(since.into());
+        }
+        if let Some(until) = query.until {
+            filters.push("chat_logs.send_at <= ?");
+            values.push(until.into());
+        }
+
+        if !filters.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&filters.join(" AND "));
+        }
+        sql.push_str(" ORDER BY chat_logs.send_at DESC, chat_logs.log_id DESC");
+        if let Some(limit) = query.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql)?;
+        let chats = stmt
+            .query_map(params_from_iter(values), ArchivedChat::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(chats)
+    }
+
+    // Text chats paired with whether a moderator hid or deleted them, newest first
+    pub fn labeled_messages(&self, limit: usize) -> Result<Vec<(String, bool)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT message, deleted_at IS NOT NULL OR hidden_at IS NOT NULL FROM chat_logs
+                WHERE chat_type = 1 AND message IS NOT NULL
+                ORDER BY log_id DESC LIMIT ?1",
+        )?;
+        let messages = stmt
+            .query_map([limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(messages)
+    }
+
+    pub fn record_media(
+        &self,
+        channel_id: i64,
+        log_id: i64,
+        media: &DownloadedMedia,
+    ) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO chat_media (
+                channel_id, log_id, url, path, sha256, size, mime_type, downloaded_at
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                channel_id,
+                log_id,
+                media.url,
+                media.path.to_string_lossy(),
+                media.sha256,
+                media.size as i64,
+                media.mime_type,
+                unix_now(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn media_of(&self, log_id: i64) -> Result<Vec<ArchivedMedia>> {
+        self.find_media("log_id", Value::Integer(log_id))
+    }
+
+    // Other chats carrying the same content
+    pub fn media_by_hash(&self, sha256: &str) -> Result<Vec<ArchivedMedia>> {
+        self.find_media("sha256", Value::Text(sha256.to_owned()))
+    }
+
+    fn find_media(&self, column: &str, value: Value) -> Result<Vec<ArchivedMedia>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT * FROM chat_media WHERE {} = ?1 ORDER BY id",
+            column
+        ))?;
+        let media = stmt
+            .query_map([value], ArchivedMedia::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(media)
+    }
+}
+
+#[async_trait(?Send)]
+impl EventHandler for Archive {
+    async fn handle(&self, _client: &mut KakaoClient, event: &BotEvent) -> Result<Flow> {
+        if let Some(chat) = event.chat() {
+            self.insert_chat(chat).context("archive chat")?;
+        }
+
+        match event {
+            BotEvent::MessageDeleted { chat, log_id } => {
+                self.record_action(chat.channel_id, *log_id, "deleted", Some(chat.log_id))?;
+            }
+            BotEvent::MessageHidden { chat, log_ids } => {
+                for log_id in log_ids.iter() {
+                    self.record_action(chat.channel_id, *log_id, "hidden", Some(chat.log_id))?;
+                }
+            }
+            BotEvent::MessageUnhidden { chat, log_ids } => {
+                for log_id in log_ids.iter() {
+                    self.record_action(chat.channel_id, *log_id, "unhidden", Some(chat.log_id))?;
+                }
+            }
+            _ => (),
+        }
+
+        Ok(Flow::Continue)
+    }
+}
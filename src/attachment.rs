@@ -0,0 +1,297 @@
+use kiwi_talk_client::chat::Chat;
+use log::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+pub const CHAT_TYPE_TEXT: i32 = 1;
+pub const CHAT_TYPE_PHOTO: i32 = 2;
+pub const CHAT_TYPE_VIDEO: i32 = 3;
+pub const CHAT_TYPE_CONTACT: i32 = 4;
+pub const CHAT_TYPE_AUDIO: i32 = 5;
+pub const CHAT_TYPE_DITEM_EMOTICON: i32 = 6;
+pub const CHAT_TYPE_STICKER: i32 = 12;
+pub const CHAT_TYPE_VOTE: i32 = 14;
+pub const CHAT_TYPE_MAP: i32 = 16;
+pub const CHAT_TYPE_PROFILE: i32 = 17;
+pub const CHAT_TYPE_FILE: i32 = 18;
+pub const CHAT_TYPE_STICKER_ANI: i32 = 20;
+pub const CHAT_TYPE_STICKER_GIF: i32 = 25;
+pub const CHAT_TYPE_REPLY: i32 = 26;
+pub const CHAT_TYPE_MULTI_PHOTO: i32 = 27;
+pub const CHAT_TYPE_OPEN_VOTE: i32 = 97;
+
+// Set on the chat type of deleted chats
+pub const CHAT_TYPE_DELETED_FLAG: i32 = 16384;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mention {
+    pub user_id: i64,
+    // Which "@nickname" occurrences in the text, counting from 1
+    #[serde(default)]
+    pub at: Vec<usize>,
+    #[serde(default)]
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TextAttachment {
+    #[serde(default)]
+    pub mentions: Vec<Mention>,
+    // Links the client rendered a preview for
+    #[serde(default)]
+    pub urls: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplyAttachment {
+    #[serde(rename(deserialize = "src_logId"))]
+    pub src_log_id: i64,
+    #[serde(rename(deserialize = "src_userId"))]
+    pub src_user_id: i64,
+    #[serde(rename(deserialize = "src_linkId"))]
+    pub src_link_id: Option<i64>,
+    pub src_type: Option<i32>,
+    pub src_message: Option<String>,
+    #[serde(default)]
+    pub attach_only: bool,
+    #[serde(default)]
+    pub mentions: Vec<Mention>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhotoAttachment {
+    pub url: String,
+    #[serde(rename(deserialize = "thumbnailUrl"))]
+    pub thumbnail_url: Option<String>,
+    #[serde(rename(deserialize = "w"))]
+    pub width: Option<u32>,
+    #[serde(rename(deserialize = "h"))]
+    pub height: Option<u32>,
+    // Media key used for download and forwarding
+    #[serde(rename(deserialize = "k"))]
+    pub key: Option<String>,
+    #[serde(rename(deserialize = "s"))]
+    pub size: Option<u64>,
+    #[serde(rename(deserialize = "mt"))]
+    pub mime_type: Option<String>,
+    #[serde(rename(deserialize = "cs"))]
+    pub checksum: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultiPhotoAttachment {
+    #[serde(rename(deserialize = "imageUrls"), default)]
+    pub image_urls: Vec<String>,
+    #[serde(rename(deserialize = "thumbnailUrls"), default)]
+    pub thumbnail_urls: Vec<String>,
+    #[serde(rename(deserialize = "kl"), default)]
+    pub keys: Vec<String>,
+    #[serde(rename(deserialize = "sl"), default)]
+    pub sizes: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VideoAttachment {
+    pub url: String,
+    #[serde(rename(deserialize = "tk"))]
+    pub key: Option<String>,
+    #[serde(rename(deserialize = "w"))]
+    pub width: Option<u32>,
+    #[serde(rename(deserialize = "h"))]
+    pub height: Option<u32>,
+    // Seconds
+    #[serde(rename(deserialize = "d"))]
+    pub duration: Option<u64>,
+    #[serde(rename(deserialize = "s"))]
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioAttachment {
+    pub url: String,
+    #[serde(rename(deserialize = "k"))]
+    pub key: Option<String>,
+    #[serde(rename(deserialize = "d"))]
+    pub duration: Option<u64>,
+    #[serde(rename(deserialize = "s"))]
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileAttachment {
+    pub name: String,
+    pub url: String,
+    #[serde(rename(deserialize = "k"))]
+    pub key: Option<String>,
+    #[serde(rename(deserialize = "s"))]
+    pub size: Option<u64>,
+    // Unix seconds after which the server drops the file
+    pub expire: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmoticonAttachment {
+    pub path: String,
+    pub name: Option<String>,
+    #[serde(rename(deserialize = "type"))]
+    pub mime_type: Option<String>,
+    pub alt: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocationAttachment {
+    pub lat: f64,
+    pub lng: f64,
+    #[serde(rename(deserialize = "t"))]
+    pub title: Option<String>,
+    #[serde(rename(deserialize = "a"))]
+    pub address: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactAttachment {
+    pub name: String,
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileAttachment {
+    #[serde(rename(deserialize = "userId"))]
+    pub user_id: i64,
+    #[serde(rename(deserialize = "nickName"))]
+    pub nickname: Option<String>,
+}
+
+// Vote payloads vary between client versions, fields not decoded here stay in `extra`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollAttachment {
+    #[serde(rename(deserialize = "voteId"))]
+    pub vote_id: Option<i64>,
+    pub title: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentKind {
+    None,
+    Text,
+    Reply,
+    Photo,
+    MultiPhoto,
+    Video,
+    Audio,
+    File,
+    Emoticon,
+    Location,
+    Contact,
+    Profile,
+    Poll,
+    Unknown,
+}
+
+// Serialized with our field names and the kind as a tag, e.g. {"kind": "photo", "url": ...}
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Attachment {
+    // No attachment, or an empty one
+    None,
+    Text(TextAttachment),
+    Reply(ReplyAttachment),
+    Photo(PhotoAttachment),
+    MultiPhoto(MultiPhotoAttachment),
+    Video(VideoAttachment),
+    Audio(AudioAttachment),
+    File(FileAttachment),
+    Emoticon(EmoticonAttachment),
+    Location(LocationAttachment),
+    Contact(ContactAttachment),
+    Profile(ProfileAttachment),
+    Poll(PollAttachment),
+    // Chat types not decoded here, or attachments that failed to decode
+    Unknown { chat_type: i32, raw: Value },
+}
+
+impl Attachment {
+    pub fn parse(chat: &Chat) -> Self {
+        let chat_type = chat.chat_type.0 & !CHAT_TYPE_DELETED_FLAG;
+        let raw: Value = match chat.content.attachment.as_deref() {
+            None | Some("") => return Attachment::None,
+            Some(attachment) => match serde_json::from_str(attachment) {
+                Ok(raw) => raw,
+                Err(err) => {
+                    debug!(
+                        "Undecodable attachment on chat type {}: {:?}",
+                        chat_type, err
+                    );
+                    return Attachment::Unknown {
+                        chat_type,
+                        raw: Value::String(attachment.to_owned()),
+                    };
+                }
+            },
+        };
+        if raw.as_object().is_some_and(Map::is_empty) {
+            return Attachment::None;
+        }
+
+        let parsed = match chat_type {
+            CHAT_TYPE_TEXT => decode(&raw).map(Attachment::Text),
+            CHAT_TYPE_REPLY => decode(&raw).map(Attachment::Reply),
+            CHAT_TYPE_PHOTO => decode(&raw).map(Attachment::Photo),
+            CHAT_TYPE_MULTI_PHOTO => decode(&raw).map(Attachment::MultiPhoto),
+            CHAT_TYPE_VIDEO => decode(&raw).map(Attachment::Video),
+            CHAT_TYPE_AUDIO => decode(&raw).map(Attachment::Audio),
+            CHAT_TYPE_FILE => decode(&raw).map(Attachment::File),
+            CHAT_TYPE_DITEM_EMOTICON
+            | CHAT_TYPE_STICKER
+            | CHAT_TYPE_STICKER_ANI
+            | CHAT_TYPE_STICKER_GIF => decode(&raw).map(Attachment::Emoticon),
+            CHAT_TYPE_MAP => decode(&raw).map(Attachment::Location),
+            CHAT_TYPE_CONTACT => decode(&raw).map(Attachment::Contact),
+            CHAT_TYPE_PROFILE => decode(&raw).map(Attachment::Profile),
+            CHAT_TYPE_VOTE | CHAT_TYPE_OPEN_VOTE => decode(&raw).map(Attachment::Poll),
+            _ => None,
+        };
+
+        parsed.unwrap_or(Attachment::Unknown { chat_type, raw })
+    }
+
+    pub fn kind(&self) -> AttachmentKind {
+        match self {
+            Attachment::None => AttachmentKind::None,
+            Attachment::Text(_) => AttachmentKind::Text,
+            Attachment::Reply(_) => AttachmentKind::Reply,
+            Attachment::Photo(_) => AttachmentKind::Photo,
+            Attachment::MultiPhoto(_) => AttachmentKind::MultiPhoto,
+            Attachment::Video(_) => AttachmentKind::Video,
+            Attachment::Audio(_) => AttachmentKind::Audio,
+            Attachment::File(_) => AttachmentKind::File,
+            Attachment::Emoticon(_) => AttachmentKind::Emoticon,
+            Attachment::Location(_) => AttachmentKind::Location,
+            Attachment::Contact(_) => AttachmentKind::Contact,
+            Attachment::Profile(_) => AttachmentKind::Profile,
+            Attachment::Poll(_) => AttachmentKind::Poll,
+            Attachment::Unknown { .. } => AttachmentKind::Unknown,
+        }
+    }
+
+    pub fn mentions(&self) -> &[Mention] {
+        match self {
+            Attachment::Text(text) => &text.mentions,
+            Attachment::Reply(reply) => &reply.mentions,
+            _ => &[],
+        }
+    }
+}
+
+fn decode<T: DeserializeOwned>(raw: &Value) -> Option<T> {
+    match T::deserialize(raw) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            debug!("Cannot decode attachment {}: {:?}", raw, err);
+            None
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::{io::Write, path::Path, sync::Mutex};
+
+use anyhow::{Context, Result};
+use log::*;
+use rusqlite::{params, params_from_iter, types::Value, Connection, Row};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{db, moderation::ModerationAction};
+
+const MIGRATIONS: &[&str] = &[
+    // 1: moderation actions
+    "CREATE TABLE moderation_actions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        at INTEGER NOT NULL,
+        action TEXT NOT NULL,
+        trigger_kind TEXT NOT NULL,
+        trigger TEXT NOT NULL,
+        channel_id INTEGER NOT NULL,
+        link_id INTEGER,
+        log_id INTEGER,
+        target_user_id INTEGER,
+        target_nickname TEXT,
+        chat_type INTEGER,
+        message TEXT,
+        attachment TEXT,
+        outcome TEXT NOT NULL,
+        error TEXT,
+        undo TEXT
+    );
+    CREATE INDEX moderation_actions_channel ON moderation_actions (channel_id, at);
+    CREATE INDEX moderation_actions_target ON moderation_actions (target_user_id, at);",
+    // 2: what the server answered to the request
+    "ALTER TABLE moderation_actions ADD COLUMN response TEXT;",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Ok,
+    Error,
+    // Recorded in shadow mode, nothing was sent
+    Shadowed,
+}
+
+// What is needed to revert the action by hand, a snapshot of the request that was sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum UndoInfo {
+    // Hidden chats can be restored by the host or a manager from the chat room
+    Hide {
+        link_id: i64,
+        channel_id: i64,
+        log_id: i64,
+        chat_type: i32,
+    },
+    // Deleted chats cannot be restored, the content is kept in the entry
+    Delete {
+        channel_id: i64,
+        log_id: i64,
+    },
+    // Kicked users are blocked from rejoining until removed from the link's kick list
+    Kick {
+        link_id: i64,
+        channel_id: i64,
+        user_id: i64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub at: i64,
+    pub action: ModerationAction,
+    pub trigger: serde_json::Value,
+    pub channel_id: i64,
+    pub link_id: Option<i64>,
+    pub log_id: Option<i64>,
+    pub target_user_id: Option<i64>,
+    pub target_nickname: Option<String>,
+    pub chat_type: Option<i32>,
+    pub message: Option<String>,
+    pub attachment: Option<String>,
+    pub outcome: Outcome,
+    pub response: Option<String>,
+    pub error: Option<String>,
+    pub undo: Option<UndoInfo>,
+}
+
+impl AuditEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            at: row.get("at")?,
+            action: from_json(&format!("\"{}\"", row.get::<_, String>("action")?))?,
+            trigger: from_json(&row.get::<_, String>("trigger")?)?,
+            channel_id: row.get("channel_id")?,
+            link_id: row.get("link_id")?,
+            log_id: row.get("log_id")?,
+            target_user_id: row.get("target_user_id")?,
+            target_nickname: row.get("target_nickname")?,
+            chat_type: row.get("chat_type")?,
+            message: row.get("message")?,
+            attachment: row.get("attachment")?,
+            outcome: from_json(&format!("\"{}\"", row.get::<_, String>("outcome")?))?,
+            response: row.get("response")?,
+            error: row.get("error")?,
+            undo: row
+                .get::<_, Option<String>>("undo")?
+                .map(|undo| from_json(&undo))
+                .transpose()?,
+        })
+    }
+}
+
+// All filters are optional and combined with AND, results are newest first
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub channel_id: Option<i64>,
+    pub target_user_id: Option<i64>,
+    pub action: Option<ModerationAction>,
+    // rule, spam, flood, command or operator
+    pub trigger_kind: Option<String>,
+    // Unix seconds, inclusive
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+pub struct AuditLog {
+    conn: Mutex<Connection>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = db::open(path, MIGRATIONS).context("open audit log")?;
+        info!("Opened moderation audit log {}", path.display());
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    // The id is ignored, a new one is assigned
+    pub fn insert(&self, entry: &AuditEntry) -> Result<i64> {
+        let trigger_kind = entry
+            .trigger
+            .get("kind")
+            .and_then(|kind| kind.as_str())
+            .unwrap_or_default();
+        let undo = entry.undo.as_ref().map(serde_json::to_string).transpose()?;
+
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO moderation_actions (
+                at, action, trigger_kind, trigger, channel_id, link_id, log_id, target_user_id,
+                target_nickname, chat_type, message, attachment, outcome, response, error, undo
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            params![
+                entry.at,
+                enum_str(&entry.action)?,
+                trigger_kind,
+                entry.trigger.to_string(),
+                entry.channel_id,
+                entry.link_id,
+                entry.log_id,
+                entry.target_user_id,
+                entry.target_nickname,
+                entry.chat_type,
+                entry.message,
+                entry.attachment,
+                enum_str(&entry.outcome)?,
+                entry.response,
+                entry.error,
+                undo,
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>> {
+        let mut sql = String::from("SELECT * FROM moderation_actions");
+        let mut filters = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+
+        if let Some(channel_id) = query.channel_id {
+            filters.push("channel_id = ?");
+            values.push(channel_id.into());
+        }
+        if let Some(target_user_id) = query.target_user_id {
+            filters.push("target_user_id = ?");
+            values.push(target_user_id.into());
+        }
+        if let Some(action) = &query.action {
+            filters.push("action = ?");
+            values.push(enum_str(action)?.into());
+        }
+        if let Some(trigger_kind) = &query.trigger_kind {
+            filters.push("trigger_kind = ?");
+            values.push(trigger_kind.clone().into());
+        }
+        if let Some(since) = query.since {
+            filters.push("at >= ?");
+            values.push(since.into());
+        }
+        if let Some(until) = query.until {
+            filters.push("at <= ?");
+            values.push(until.into());
+        }
+
+        if !filters.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&filters.join(" AND "));
+        }
+        sql.push_str(" ORDER BY at DESC, id DESC");
+        if let Some(limit) = query.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql)?;
+        let entries = stmt
+            .query_map(params_from_iter(values), AuditEntry::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    // One JSON object per line, oldest first. Returns the number of entries written
+    pub fn export_jsonl(&self, query: &AuditQuery, mut writer: impl Write) -> Result<usize> {
+        let entries = self.query(query)?;
+        for entry in entries.iter().rev() {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(entries.len())
+    }
+}
+
+fn from_json<T: DeserializeOwned>(json: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(json).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))
+    })
+}
+
+// Serialized name of a unit enum variant, as stored in the table
+fn enum_str(value: &impl Serialize) -> Result<String> {
+    match serde_json::to_value(value)? {
+        serde_json::Value::String(name) => Ok(name),
+        value => anyhow::bail!("expected a unit variant, got {}", value),
+    }
+}
@@ -0,0 +1,247 @@
+use kiwi_talk_client::{
+    chat::{Chat, ChatContent, ChatType},
+    event::chat::ChatReceived,
+};
+use serde_json::{Map, Value};
+
+use crate::{
+    archive::ArchivedChat,
+    attachment::{
+        Mention, CHAT_TYPE_CONTACT, CHAT_TYPE_FILE, CHAT_TYPE_MAP, CHAT_TYPE_PHOTO,
+        CHAT_TYPE_PROFILE, CHAT_TYPE_REPLY, CHAT_TYPE_STICKER, CHAT_TYPE_STICKER_ANI,
+        CHAT_TYPE_TEXT,
+    },
+    media::UploadedMedia,
+};
+
+// The chat a reply quotes
+#[derive(Debug, Clone)]
+pub struct ReplyTo {
+    pub log_id: i64,
+    pub sender_id: i64,
+    pub chat_type: i32,
+    pub message: String,
+    pub link_id: Option<i64>,
+}
+
+impl From<&ChatReceived> for ReplyTo {
+    fn from(value: &ChatReceived) -> Self {
+        Self {
+            log_id: value.log_id,
+            sender_id: value.chat.sender_id,
+            chat_type: value.chat.chat.chat_type.0,
+            message: value.chat.chat.content.message.clone().unwrap_or_default(),
+            link_id: value.link_id,
+        }
+    }
+}
+
+impl From<&ArchivedChat> for ReplyTo {
+    fn from(value: &ArchivedChat) -> Self {
+        Self {
+            log_id: value.log_id,
+            sender_id: value.sender_id,
+            chat_type: value.chat_type,
+            message: value.message.clone().unwrap_or_default(),
+            link_id: value.link_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Emoticon {
+    // e.g. "4412207.emot_001.webp"
+    pub path: String,
+    // Shown where emoticons can't be displayed, e.g. "(이모티콘)"
+    pub name: String,
+    pub animated: bool,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+// Builds the Chat for send_message with the attachment JSON the official clients expect.
+// Mentions and links can be mixed into any text. Replies are text only, so they have their own
+// constructor instead of being added to other kinds
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    chat_type: i32,
+    text: String,
+    mentions: Vec<Mention>,
+    urls: Vec<String>,
+    reply_to: Option<ReplyTo>,
+    attachment: Map<String, Value>,
+}
+
+impl MessageBuilder {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::with_type(CHAT_TYPE_TEXT, text.into())
+    }
+
+    // A text chat quoting another one
+    pub fn reply(reply_to: impl Into<ReplyTo>, text: impl Into<String>) -> Self {
+        let mut builder = Self::with_type(CHAT_TYPE_REPLY, text.into());
+        builder.reply_to = Some(reply_to.into());
+        builder
+    }
+
+    fn with_type(chat_type: i32, text: String) -> Self {
+        Self {
+            chat_type,
+            text,
+            mentions: Vec::new(),
+            urls: Vec::new(),
+            reply_to: None,
+            attachment: Map::new(),
+        }
+    }
+
+    pub fn emoticon(emoticon: Emoticon) -> Self {
+        let chat_type = match emoticon.animated {
+            true => CHAT_TYPE_STICKER_ANI,
+            false => CHAT_TYPE_STICKER,
+        };
+        let mut builder = Self::with_type(chat_type, String::new());
+        builder.attach("path", emoticon.path);
+        builder.attach("name", emoticon.name.clone());
+        builder.attach("type", "image/webp");
+        builder.attach("alt", emoticon.name);
+        if let Some(width) = emoticon.width {
+            builder.attach("width", width);
+        }
+        if let Some(height) = emoticon.height {
+            builder.attach("height", height);
+        }
+        builder
+    }
+
+    pub fn location(lat: f64, lng: f64, title: &str, address: &str) -> Self {
+        let mut builder = Self::with_type(CHAT_TYPE_MAP, title.to_owned());
+        builder.attach("lat", lat);
+        builder.attach("lng", lng);
+        builder.attach("t", title);
+        builder.attach("a", address);
+        builder.attach("c", false);
+        builder
+    }
+
+    // A contact card, url points at the vCard
+    pub fn contact(name: &str, url: Option<&str>) -> Self {
+        let mut builder = Self::with_type(CHAT_TYPE_CONTACT, name.to_owned());
+        builder.attach("name", name);
+        if let Some(url) = url {
+            builder.attach("url", url);
+        }
+        builder
+    }
+
+    // Shares a Kakao profile
+    pub fn profile(user_id: i64, nickname: &str) -> Self {
+        let mut builder = Self::with_type(CHAT_TYPE_PROFILE, nickname.to_owned());
+        builder.attach("userId", user_id);
+        builder.attach("nickName", nickname);
+        builder
+    }
+
+    // An uploaded photo, see KakaoClient::upload_media
+    pub fn photo(media: &UploadedMedia) -> Self {
+        let mut builder = Self::with_type(CHAT_TYPE_PHOTO, "사진".to_owned());
+        builder.attach("k", media.path.as_str());
+        builder.attach("url", media.url.as_str());
+        builder.attach("s", media.size);
+        builder.attach("cs", media.checksum.as_str());
+        builder.attach("mt", media.mime_type.as_str());
+        if let (Some(width), Some(height)) = (media.width, media.height) {
+            builder.attach("w", width);
+            builder.attach("h", height);
+        }
+        builder
+    }
+
+    pub fn file(media: &UploadedMedia) -> Self {
+        let mut builder = Self::with_type(CHAT_TYPE_FILE, media.name.clone());
+        builder.attach("name", media.name.as_str());
+        builder.attach("k", media.path.as_str());
+        builder.attach("url", media.url.as_str());
+        builder.attach("s", media.size);
+        builder.attach("size", media.size);
+        builder.attach("cs", media.checksum.as_str());
+        builder
+    }
+
+    pub fn push_text(mut self, text: &str) -> Self {
+        self.text.push_str(text);
+        self
+    }
+
+    // Appends "@nickname" and marks it as a mention of user_id
+    pub fn mention(mut self, user_id: i64, nickname: &str) -> Self {
+        let tag = format!("@{}", nickname);
+        let occurrence = self.text.matches(tag.as_str()).count() + 1;
+        self.text.push_str(&tag);
+
+        match self
+            .mentions
+            .iter_mut()
+            .find(|mention| mention.user_id == user_id)
+        {
+            Some(mention) => mention.at.push(occurrence),
+            None => self.mentions.push(Mention {
+                user_id,
+                at: vec![occurrence],
+                len: nickname.chars().count(),
+            }),
+        }
+        self
+    }
+
+    // Appends the url, the client renders a preview for it
+    pub fn link(mut self, url: &str) -> Self {
+        self.text.push_str(url);
+        self.urls.push(url.to_owned());
+        self
+    }
+
+    fn attach(&mut self, key: &str, value: impl Into<Value>) {
+        self.attachment.insert(key.to_owned(), value.into());
+    }
+
+    pub fn build(self) -> Chat {
+        let mut attachment = self.attachment;
+
+        if !self.mentions.is_empty() {
+            let mentions = serde_json::to_value(&self.mentions).unwrap_or_default();
+            attachment.insert("mentions".to_owned(), mentions);
+        }
+        if !self.urls.is_empty() {
+            attachment.insert("urls".to_owned(), self.urls.into());
+        }
+        if let Some(reply_to) = self.reply_to {
+            attachment.insert("attach_only".to_owned(), false.into());
+            attachment.insert("attach_type".to_owned(), CHAT_TYPE_TEXT.into());
+            attachment.insert("src_logId".to_owned(), reply_to.log_id.into());
+            attachment.insert("src_userId".to_owned(), reply_to.sender_id.into());
+            attachment.insert("src_type".to_owned(), reply_to.chat_type.into());
+            attachment.insert("src_message".to_owned(), reply_to.message.into());
+            attachment.insert("src_mentions".to_owned(), Value::Array(Vec::new()));
+            if let Some(link_id) = reply_to.link_id {
+                attachment.insert("src_linkId".to_owned(), link_id.into());
+            }
+            attachment
+                .entry("mentions")
+                .or_insert_with(|| Value::Array(Vec::new()));
+        }
+
+        Chat {
+            chat_type: ChatType(self.chat_type),
+            content: ChatContent {
+                message: Some(self.text),
+                attachment: match attachment.is_empty() {
+                    true => None,
+                    false => Some(Value::Object(attachment).to_string()),
+                },
+                supplement: None,
+            },
+            message_id: 0,
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use kiwi_talk_client::{
+    channel::ChannelDataVariant,
+    chat::{Chat, Chatlog},
+};
+use talk_loco_client::client::ClientRequestError;
+
+use crate::{
+    kakao::KakaoClient,
+    moderation::{self, ModerationAction, ModerationError, Target, Trigger},
+    roster::RosterMember,
+    send_queue::{SendError, SendPriority},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    Normal,
+    Open { link_id: i64 },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChannelError {
+    #[error(transparent)]
+    Moderation(#[from] ModerationError),
+    #[error("request failed: {0}")]
+    Request(#[from] ClientRequestError),
+    #[error(transparent)]
+    Send(#[from] SendError),
+}
+
+// A channel the account is in, with the ids moderation requests need.
+// Handles are snapshots, get a fresh one from KakaoClient::channel after joins and leaves
+#[derive(Debug, Clone)]
+pub struct OpenChannel {
+    pub channel_id: i64,
+    pub kind: ChannelKind,
+    // Only known for channels joined through join_channel
+    pub name: Option<String>,
+}
+
+impl OpenChannel {
+    pub fn new(channel_id: i64, kind: ChannelKind, name: Option<String>) -> Self {
+        Self {
+            channel_id,
+            kind,
+            name,
+        }
+    }
+
+    pub fn from_data(channel_id: i64, data: &ChannelDataVariant) -> Self {
+        let kind = match data {
+            ChannelDataVariant::Open(data) => ChannelKind::Open {
+                link_id: data.link_id,
+            },
+            ChannelDataVariant::Normal(_) => ChannelKind::Normal,
+        };
+        Self::new(channel_id, kind, None)
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self.kind, ChannelKind::Open { .. })
+    }
+
+    pub fn link_id(&self) -> Option<i64> {
+        match self.kind {
+            ChannelKind::Open { link_id } => Some(link_id),
+            ChannelKind::Normal => None,
+        }
+    }
+
+    // Members as last synced or seen in feeds
+    pub fn members<'a>(&self, client: &'a KakaoClient) -> Vec<&'a RosterMember> {
+        client.roster.members(self.channel_id)
+    }
+
+    pub async fn send(
+        &self,
+        client: &mut KakaoClient,
+        chat: Chat,
+    ) -> Result<Chatlog, ChannelError> {
+        Ok(client
+            .send_message_queued(self.channel_id, chat, SendPriority::Normal)
+            .await?)
+    }
+
+    // Moderation goes through moderation::apply, so shadow mode and the audit log apply
+    pub async fn hide(
+        &self,
+        client: &mut KakaoClient,
+        log_id: i64,
+        chat_type: i32,
+        trigger: &Trigger,
+    ) -> Result<(), ChannelError> {
+        let target = Target::message(self.channel_id, self.link_id(), log_id, chat_type);
+        Ok(moderation::apply(client, &target, ModerationAction::Hide, trigger).await?)
+    }
+
+    pub async fn delete(
+        &self,
+        client: &mut KakaoClient,
+        log_id: i64,
+        trigger: &Trigger,
+    ) -> Result<(), ChannelError> {
+        let target = Target {
+            channel_id: self.channel_id,
+            link_id: self.link_id(),
+            log_id: Some(log_id),
+            ..Default::default()
+        };
+        Ok(moderation::apply(client, &target, ModerationAction::Delete, trigger).await?)
+    }
+
+    pub async fn kick(
+        &self,
+        client: &mut KakaoClient,
+        user_id: i64,
+        trigger: &Trigger,
+    ) -> Result<(), ChannelError> {
+        let target = Target::user(self.channel_id, self.link_id(), user_id);
+        Ok(moderation::apply(client, &target, ModerationAction::Kick, trigger).await?)
+    }
+
+    pub async fn leave(&self, client: &mut KakaoClient) -> Result<(), ChannelError> {
+        Ok(client.leave_channel(self.channel_id, false).await?)
+    }
+}
@@ -0,0 +1,436 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use log::*;
+
+use crate::{
+    attachment::{Attachment, ReplyAttachment},
+    builder::MessageBuilder,
+    config::CommandsCfg,
+    dispatcher::{BotEvent, EventHandler, Flow},
+    kakao::{KakaoClient, OpenMemberType},
+    send_queue::SendPriority,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Everyone,
+    Manager,
+    Host,
+    // User ids listed in commands.operators
+    Operator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    // A single word or "quoted text"
+    Word,
+    Integer,
+    // Everything left in the message
+    Rest,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArgSpec {
+    pub name: String,
+    pub kind: ArgKind,
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArgValue {
+    Text(String),
+    Integer(i64),
+}
+
+#[derive(Debug)]
+pub struct CommandContext {
+    pub channel_id: i64,
+    pub link_id: Option<i64>,
+    pub sender_id: i64,
+    pub command: String,
+    pub args: HashMap<String, ArgValue>,
+    // The quoted chat when the command was sent as a reply
+    pub reply_to: Option<ReplyAttachment>,
+}
+
+impl CommandContext {
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.args.get(name)? {
+            ArgValue::Text(text) => Some(text),
+            ArgValue::Integer(_) => None,
+        }
+    }
+
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        match self.args.get(name)? {
+            ArgValue::Integer(value) => Some(*value),
+            ArgValue::Text(_) => None,
+        }
+    }
+}
+
+// Returning Some(reply) sends it back to the channel the command came from
+#[async_trait(?Send)]
+pub trait CommandHandler {
+    async fn run(&self, client: &mut KakaoClient, ctx: &CommandContext) -> Result<Option<String>>;
+}
+
+#[async_trait(?Send)]
+impl<F> CommandHandler for F
+where
+    F: for<'a> Fn(
+        &'a mut KakaoClient,
+        &'a CommandContext,
+    ) -> LocalBoxFuture<'a, Result<Option<String>>>,
+{
+    async fn run(&self, client: &mut KakaoClient, ctx: &CommandContext) -> Result<Option<String>> {
+        self(client, ctx).await
+    }
+}
+
+pub struct Command {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub args: Vec<ArgSpec>,
+    pub help: String,
+    pub cooldown: Duration,
+    pub permission: Permission,
+    handler: Box<dyn CommandHandler>,
+}
+
+impl Command {
+    pub fn new(name: &str, handler: impl CommandHandler + 'static) -> Self {
+        Self {
+            name: name.to_lowercase(),
+            aliases: Vec::new(),
+            args: Vec::new(),
+            help: String::new(),
+            cooldown: Duration::ZERO,
+            permission: Permission::Everyone,
+            handler: Box::new(handler),
+        }
+    }
+
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.aliases.push(alias.to_lowercase());
+        self
+    }
+
+    pub fn arg(mut self, name: &str, kind: ArgKind) -> Self {
+        self.args.push(ArgSpec {
+            name: name.to_owned(),
+            kind,
+            required: true,
+        });
+        self
+    }
+
+    pub fn optional_arg(mut self, name: &str, kind: ArgKind) -> Self {
+        self.args.push(ArgSpec {
+            name: name.to_owned(),
+            kind,
+            required: false,
+        });
+        self
+    }
+
+    pub fn help(mut self, help: &str) -> Self {
+        self.help = help.to_owned();
+        self
+    }
+
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    pub fn permission(mut self, permission: Permission) -> Self {
+        self.permission = permission;
+        self
+    }
+
+    pub fn usage(&self, prefix: &str) -> String {
+        let mut usage = format!("{}{}", prefix, self.name);
+        for arg in self.args.iter() {
+            let dots = if arg.kind == ArgKind::Rest { "..." } else { "" };
+            match arg.required {
+                true => write!(usage, " <{}{}>", arg.name, dots).unwrap(),
+                false => write!(usage, " [{}{}]", arg.name, dots).unwrap(),
+            }
+        }
+        usage
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name == name || self.aliases.iter().any(|alias| alias == name)
+    }
+
+    fn parse_args(
+        &self,
+        input: &str,
+        tokens: &[(usize, String)],
+    ) -> Result<HashMap<String, ArgValue>> {
+        let mut args = HashMap::new();
+        let mut tokens = tokens.iter();
+
+        for spec in self.args.iter() {
+            let value = match spec.kind {
+                ArgKind::Rest => {
+                    let rest = tokens
+                        .next()
+                        .map(|(start, _)| input[*start..].trim().to_owned());
+                    tokens.by_ref().for_each(drop);
+                    rest.map(ArgValue::Text)
+                }
+                ArgKind::Word => tokens
+                    .next()
+                    .map(|(_, token)| ArgValue::Text(token.clone())),
+                ArgKind::Integer => match tokens.next() {
+                    Some((_, token)) => match token.parse() {
+                        Ok(value) => Some(ArgValue::Integer(value)),
+                        Err(_) => bail!("<{}> must be a number", spec.name),
+                    },
+                    None => None,
+                },
+            };
+
+            match value {
+                Some(value) => {
+                    args.insert(spec.name.clone(), value);
+                }
+                None if spec.required => bail!("missing <{}>", spec.name),
+                None => (),
+            }
+        }
+
+        if tokens.next().is_some() {
+            bail!("too many arguments");
+        }
+
+        Ok(args)
+    }
+}
+
+pub struct CommandRegistry {
+    prefix: String,
+    operators: Vec<i64>,
+    commands: Vec<Command>,
+    // (command, sender_id) -> last use
+    last_used: RefCell<HashMap<(String, i64), Instant>>,
+}
+
+impl CommandRegistry {
+    pub fn new(cfg: &CommandsCfg) -> Self {
+        Self {
+            prefix: cfg.prefix.clone(),
+            operators: cfg.operators.clone(),
+            commands: Vec::new(),
+            last_used: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(&mut self, command: Command) -> &mut Self {
+        if self.find(&command.name).is_some() {
+            warn!(
+                "Command '{}' registered twice, the first one wins",
+                command.name
+            );
+        }
+        self.commands.push(command);
+        self
+    }
+
+    fn find(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|command| command.matches(name))
+    }
+
+    fn permission_of(&self, client: &KakaoClient, channel_id: i64, user_id: i64) -> Permission {
+        if self.operators.contains(&user_id) {
+            return Permission::Operator;
+        }
+
+        match client.get_open_member_type(channel_id, user_id) {
+            OpenMemberType::Host => Permission::Host,
+            OpenMemberType::Manager => Permission::Manager,
+            _ => Permission::Everyone,
+        }
+    }
+
+    fn help(&self, permission: Permission, name: Option<&str>) -> String {
+        if let Some(command) = name.and_then(|name| self.find(&name.to_lowercase())) {
+            let mut help = command.usage(&self.prefix);
+            if !command.help.is_empty() {
+                write!(help, "\n{}", command.help).unwrap();
+            }
+            if !command.aliases.is_empty() {
+                write!(help, "\nAliases: {}", command.aliases.join(", ")).unwrap();
+            }
+            return help;
+        }
+
+        let mut help = String::from("Commands:");
+        for command in self
+            .commands
+            .iter()
+            .filter(|command| command.permission <= permission)
+        {
+            write!(help, "\n{}", command.usage(&self.prefix)).unwrap();
+            if !command.help.is_empty() {
+                write!(help, " - {}", command.help).unwrap();
+            }
+        }
+        write!(help, "\n{}help [command]", self.prefix).unwrap();
+        help
+    }
+
+    // Remaining time when the command is still cooling down for this sender
+    fn check_cooldown(&self, command: &Command, sender_id: i64) -> Option<Duration> {
+        if command.cooldown.is_zero() {
+            return None;
+        }
+
+        let mut last_used = self.last_used.borrow_mut();
+        let key = (command.name.clone(), sender_id);
+        let now = Instant::now();
+        if let Some(last) = last_used.get(&key) {
+            let elapsed = now.duration_since(*last);
+            if elapsed < command.cooldown {
+                return Some(command.cooldown - elapsed);
+            }
+        }
+        last_used.insert(key, now);
+        None
+    }
+
+    async fn reply(client: &mut KakaoClient, channel_id: i64, message: String) {
+        let chat = MessageBuilder::text(message).build();
+        if let Err(err) = client
+            .send_message_queued(channel_id, chat, SendPriority::Normal)
+            .await
+        {
+            error!("Cannot send command reply: {:?}", err);
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl EventHandler for CommandRegistry {
+    async fn handle(&self, client: &mut KakaoClient, event: &BotEvent) -> Result<Flow> {
+        let BotEvent::Chat(received) = event else {
+            return Ok(Flow::Continue);
+        };
+        let Some(input) = received
+            .chat
+            .chat
+            .content
+            .message
+            .as_deref()
+            .and_then(|message| message.strip_prefix(self.prefix.as_str()))
+        else {
+            return Ok(Flow::Continue);
+        };
+
+        let tokens = tokenize(input);
+        let Some((_, name)) = tokens.first() else {
+            return Ok(Flow::Continue);
+        };
+        let name = name.to_lowercase();
+        let channel_id = received.channel_id;
+        let sender_id = received.chat.sender_id;
+        let permission = self.permission_of(client, channel_id, sender_id);
+
+        let Some(command) = self.find(&name) else {
+            if name == "help" {
+                let topic = tokens.get(1).map(|(_, topic)| topic.as_str());
+                Self::reply(client, channel_id, self.help(permission, topic)).await;
+                return Ok(Flow::Stop);
+            }
+            return Ok(Flow::Continue);
+        };
+
+        if permission < command.permission {
+            info!(
+                "User {} lacks permission for command '{}'",
+                sender_id, command.name
+            );
+            Self::reply(
+                client,
+                channel_id,
+                "You are not allowed to use this command".into(),
+            )
+            .await;
+            return Ok(Flow::Stop);
+        }
+
+        let args = match command.parse_args(input, &tokens[1..]) {
+            Ok(args) => args,
+            Err(err) => {
+                let usage = format!("{}\nUsage: {}", err, command.usage(&self.prefix));
+                Self::reply(client, channel_id, usage).await;
+                return Ok(Flow::Stop);
+            }
+        };
+
+        if let Some(remaining) = self.check_cooldown(command, sender_id) {
+            info!(
+                "Command '{}' cooling down for user {} ({:?} left)",
+                command.name, sender_id, remaining
+            );
+            return Ok(Flow::Stop);
+        }
+
+        let ctx = CommandContext {
+            channel_id,
+            link_id: received.link_id,
+            sender_id,
+            command: command.name.clone(),
+            args,
+            reply_to: match Attachment::parse(&received.chat.chat) {
+                Attachment::Reply(reply) => Some(reply),
+                _ => None,
+            },
+        };
+        info!("Run command {:?}", ctx);
+
+        if let Some(reply) = command.handler.run(client, &ctx).await? {
+            Self::reply(client, channel_id, reply).await;
+        }
+
+        Ok(Flow::Stop)
+    }
+}
+
+// Whitespace separated tokens with their byte offset, double quotes group words
+fn tokenize(input: &str) -> Vec<(usize, String)> {
+    let mut tokens = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    let mut quoted = false;
+
+    for (i, c) in input.char_indices() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_with(|| (i, String::new()));
+            }
+            c if c.is_whitespace() && !quoted => {
+                if let Some(token) = current.take() {
+                    tokens.push(token);
+                }
+            }
+            c => current.get_or_insert_with(|| (i, String::new())).1.push(c),
+        }
+    }
+    if let Some(token) = current {
+        tokens.push(token);
+    }
+
+    tokens
+}
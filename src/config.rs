@@ -0,0 +1,1086 @@
+use std::{
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use kiwi_talk_app::system::{DeviceInfo, DeviceUuid, SystemInfo};
+use log::*;
+use serde::{Deserialize, Serialize};
+use talk_api_client::{
+    agent::TalkApiAgent,
+    auth::{xvc::default::Win32XVCHasher, AuthClientConfig, AuthDeviceConfig},
+};
+use toml::{Table, Value};
+
+use crate::{dispatcher::EventKind, moderation::ModerationAction, spam::SpamAggregate};
+
+const DEFAULT_CONFIG_PATH: &str = "kakao.toml";
+const CONFIG_PATH_ENV: &str = "KAKAO_CONFIG";
+const ENV_PREFIX: &str = "KAKAO_";
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid config key `{key}`: {reason}")]
+pub struct ConfigError {
+    pub key: String,
+    pub reason: String,
+}
+
+impl ConfigError {
+    fn new(key: &str, reason: impl Into<String>) -> Self {
+        Self {
+            key: key.to_owned(),
+            reason: reason.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KakaoClientCfg {
+    pub account: AccountCfg,
+    pub device: DeviceCfg,
+    pub client: ClientCfg,
+    pub xvc: XvcCfg,
+    pub system: SystemCfg,
+    pub credential: CredentialCfg,
+    pub reconnect: ReconnectCfg,
+    pub commands: CommandsCfg,
+    pub archive: ArchiveCfg,
+    pub backfill: BackfillCfg,
+    pub users: UsersCfg,
+    pub spam: SpamCfg,
+    pub rules: RulesCfg,
+    pub moderation: ModerationCfg,
+    pub audit: AuditCfg,
+    pub flood: FloodCfg,
+    pub send: SendCfg,
+    pub media: MediaCfg,
+    pub membership: MembershipCfg,
+    pub api: ApiCfg,
+    pub webhook: WebhookCfg,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountCfg {
+    pub email: String,
+    pub password: String,
+    // Kicks other sessions of the same device when logging in with the account
+    pub force_login: bool,
+}
+
+impl Default for AccountCfg {
+    fn default() -> Self {
+        Self {
+            email: String::new(),
+            password: String::new(),
+            force_login: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceCfg {
+    pub name: String,
+    pub model: Option<String>,
+    // Unique id base64 encoded
+    pub uuid: String,
+    pub locale: String,
+}
+
+impl Default for DeviceCfg {
+    fn default() -> Self {
+        Self {
+            name: "TEST_DEVICE".into(),
+            model: None,
+            uuid: "6SMj9g0xFYodk+ItC4FKX1EgnZiLPibCWGXuqIgEwx56uwoFJg+GCX0YneaczW4yEt3QzbI+6Hhz9env9cV6wQ==".into(),
+            locale: "KR".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientCfg {
+    pub language: String,
+    // Talk client version
+    pub version: String,
+    // Talk agent, only "win32" is supported by the XVC hasher
+    pub agent: String,
+    pub agent_version: String,
+}
+
+impl Default for ClientCfg {
+    fn default() -> Self {
+        Self {
+            language: "ko".into(),
+            version: "3.4.7".into(),
+            agent: "win32".into(),
+            agent_version: "10.0".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct XvcCfg {
+    pub seed_1: String,
+    pub seed_2: String,
+}
+
+impl Default for XvcCfg {
+    fn default() -> Self {
+        Self {
+            seed_1: "JAYDEN".into(),
+            seed_2: "JAYMOND".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemCfg {
+    pub data_dir: PathBuf,
+    pub device_data_dir: PathBuf,
+}
+
+impl Default for SystemCfg {
+    fn default() -> Self {
+        Self {
+            data_dir: "data_dir".into(),
+            device_data_dir: "device_data_dir".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialCfg {
+    // Relative to system.data_dir
+    pub path: PathBuf,
+    // Passphrase used to encrypt the stored credential, empty disables the store
+    pub key: String,
+}
+
+impl Default for CredentialCfg {
+    fn default() -> Self {
+        Self {
+            path: "credential.bin".into(),
+            key: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectCfg {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub multiplier: f64,
+    // Fraction of the delay randomly added or removed, 0.0 to 1.0
+    pub jitter: f64,
+    // 0 retries forever
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectCfg {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 1000,
+            max_delay_ms: 60_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandsCfg {
+    pub prefix: String,
+    // User ids allowed to run every command regardless of their open chat role
+    pub operators: Vec<i64>,
+}
+
+impl Default for CommandsCfg {
+    fn default() -> Self {
+        Self {
+            prefix: "!".into(),
+            operators: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveCfg {
+    pub enabled: bool,
+    // Relative to system.data_dir
+    pub path: PathBuf,
+}
+
+impl Default for ArchiveCfg {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "archive.sqlite".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsersCfg {
+    // Relative to system.data_dir
+    pub path: PathBuf,
+}
+
+impl Default for UsersCfg {
+    fn default() -> Self {
+        Self {
+            path: "users.sqlite".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackfillCfg {
+    // Detects missed chats through prev_log_id and fetches them before delivering newer ones
+    pub enabled: bool,
+    // Relative to system.data_dir
+    pub cursor_path: PathBuf,
+    // get_chat_logs requests per gap before giving up, 0 for no limit
+    pub max_pages: u32,
+}
+
+impl Default for BackfillCfg {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cursor_path: "cursors.json".into(),
+            max_pages: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpamCfg {
+    // Scores chats in open channels and acts on the ones crossing a threshold
+    pub enabled: bool,
+    pub aggregate: SpamAggregate,
+    pub link_score: f64,
+    // open.kakao.com, band, telegram and discord invites
+    pub invite_link_score: f64,
+    // Phone numbers and messenger ids
+    pub contact_score: f64,
+    pub keywords: Vec<String>,
+    pub keyword_score: f64,
+    // Same text from the same sender this many times within the window
+    pub repeat_count: usize,
+    pub repeat_window_secs: u64,
+    pub repeat_score: f64,
+    // Naive Bayes trained from archived chats, hidden or deleted ones count as spam
+    pub bayes: bool,
+    pub bayes_min_samples: usize,
+    pub bayes_max_samples: usize,
+    // Every action whose score is reached is taken
+    pub actions: Vec<SpamThreshold>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpamThreshold {
+    pub score: f64,
+    pub action: ModerationAction,
+}
+
+impl Default for SpamCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            aggregate: SpamAggregate::Max,
+            link_score: 0.5,
+            invite_link_score: 0.9,
+            contact_score: 0.7,
+            keywords: Vec::new(),
+            keyword_score: 0.6,
+            repeat_count: 3,
+            repeat_window_secs: 120,
+            repeat_score: 0.8,
+            bayes: true,
+            bayes_min_samples: 200,
+            bayes_max_samples: 20000,
+            actions: vec![
+                SpamThreshold {
+                    score: 0.5,
+                    action: ModerationAction::Log,
+                },
+                SpamThreshold {
+                    score: 0.8,
+                    action: ModerationAction::Hide,
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesCfg {
+    pub enabled: bool,
+    // See rules.example.toml, relative to the working directory
+    pub path: PathBuf,
+    // One strike is forgiven per period without offenses, 0 keeps strikes forever
+    pub strike_decay_secs: u64,
+}
+
+impl Default for RulesCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "rules.toml".into(),
+            strike_decay_secs: 86400,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationCfg {
+    // Shadow mode records hide/delete/kick instead of doing them
+    pub shadow: bool,
+    // Channels in shadow mode regardless of `shadow`
+    pub shadow_channels: Vec<i64>,
+    // Channel that shadowed actions are reported to
+    pub report_channel_id: Option<i64>,
+    // Channels flood, rules and spam act in, empty means every channel
+    pub channels: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditCfg {
+    // Records every moderation action with the message it targeted
+    pub enabled: bool,
+    // Relative to system.data_dir
+    pub path: PathBuf,
+}
+
+impl Default for AuditCfg {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "audit.sqlite".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FloodCfg {
+    // Raises AbuseDetected events for chats crossing these limits
+    pub enabled: bool,
+    pub user_window_secs: u64,
+    pub user_max_messages: usize,
+    pub channel_window_secs: u64,
+    pub channel_max_messages: usize,
+    pub duplicate_window_secs: u64,
+    pub duplicate_max: usize,
+    // Same character repeated, 0 disables
+    pub max_char_run: usize,
+    pub max_emoji_ratio: f64,
+    // Shorter chats are not checked for emoji
+    pub emoji_min_length: usize,
+    // 0 disables
+    pub max_mentions: usize,
+    // Taken on the offending chat, channel floods are only logged
+    pub actions: Vec<ModerationAction>,
+}
+
+impl Default for FloodCfg {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            user_window_secs: 10,
+            user_max_messages: 8,
+            channel_window_secs: 10,
+            channel_max_messages: 40,
+            duplicate_window_secs: 60,
+            duplicate_max: 3,
+            max_char_run: 50,
+            max_emoji_ratio: 0.8,
+            emoji_min_length: 10,
+            max_mentions: 5,
+            actions: vec![ModerationAction::Log],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SendCfg {
+    // Chats per second across all channels, and how many can go out at once
+    pub global_rate: f64,
+    pub global_burst: u32,
+    // Chats per second in a single channel
+    pub channel_rate: f64,
+    pub channel_burst: u32,
+    // Connection errors are retried with exponential backoff, server refusals are not.
+    // At most 10 retries, a single delay is capped at 5 minutes
+    pub max_retries: u32,
+    pub retry_delay_ms: u64,
+}
+
+impl Default for SendCfg {
+    fn default() -> Self {
+        Self {
+            global_rate: 2.0,
+            global_burst: 5,
+            channel_rate: 1.0,
+            channel_burst: 3,
+            max_retries: 3,
+            retry_delay_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaCfg {
+    // Legacy HTTP media servers, uploads answer with the path the media is served under
+    pub upload_url: String,
+    pub download_url: String,
+    // Relative to system.data_dir, files are named after their sha256
+    pub download_dir: PathBuf,
+    // Downloads photos and files of incoming chats and records them in the archive
+    pub download_incoming: bool,
+    // Hosts media is downloaded from, subdomains included. Attachment urls are written by the
+    // sender, so anything else is refused
+    pub download_hosts: Vec<String>,
+    pub max_download_bytes: u64,
+    pub timeout_secs: u64,
+    // Incoming chats waiting for their media to be downloaded, chats past it are not downloaded
+    pub download_queue_size: usize,
+}
+
+impl Default for MediaCfg {
+    fn default() -> Self {
+        Self {
+            upload_url: "https://up-m.talk.kakao.com/upload".into(),
+            download_url: "https://dn-m.talk.kakao.com".into(),
+            download_dir: "media".into(),
+            download_incoming: false,
+            download_hosts: vec!["talk.kakao.com".into(), "kakaocdn.net".into()],
+            max_download_bytes: 50 * 1024 * 1024,
+            timeout_secs: 60,
+            download_queue_size: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MembershipCfg {
+    // Open chats the bot keeps itself joined to, with the profile it uses there
+    pub links: Vec<MembershipLink>,
+    // Also checked on reconnect and when the bot leaves or is removed from a channel
+    pub check_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MembershipLink {
+    // e.g. "https://open.kakao.com/o/gfvKeahf"
+    pub url: String,
+    pub nickname: String,
+    pub passcode: Option<String>,
+    // Uploaded once and used as the profile image in this chat
+    pub profile_image: Option<PathBuf>,
+}
+
+impl Default for MembershipCfg {
+    fn default() -> Self {
+        Self {
+            links: Vec::new(),
+            check_interval_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiCfg {
+    // Local HTTP admin API, see README
+    pub enabled: bool,
+    pub bind: SocketAddr,
+    // Sent as "Authorization: Bearer <token>", required when enabled
+    pub token: String,
+    // Relative to system.data_dir, photos and files sent through the api must be in here
+    pub upload_dir: PathBuf,
+}
+
+impl Default for ApiCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: SocketAddr::from(([127, 0, 0, 1], 8787)),
+            token: String::new(),
+            upload_dir: "uploads".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookCfg {
+    pub enabled: bool,
+    pub endpoints: Vec<WebhookEndpoint>,
+    pub timeout_secs: u64,
+    // Attempts per event, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // Events waiting per endpoint, events past it go straight to the dead letter file
+    pub queue_size: usize,
+    // Relative to system.data_dir, one JSON object per undelivered event
+    pub dead_letter_path: PathBuf,
+}
+
+impl Default for WebhookCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoints: Vec::new(),
+            timeout_secs: 10,
+            max_attempts: 5,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            queue_size: 256,
+            dead_letter_path: "webhook_dead_letters.jsonl".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    // Key of the HMAC-SHA256 signature in X-Kakao-Signature
+    pub secret: String,
+    // Empty delivers events of every channel
+    #[serde(default)]
+    pub channels: Vec<i64>,
+    // Empty delivers every event kind the webhook schema covers
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+}
+
+impl KakaoClientCfg {
+    // Precedence: defaults < config file < KAKAO_* env vars < CLI flags
+    pub fn load() -> Result<Self> {
+        Self::load_from(env::args().skip(1), env::vars())
+    }
+
+    pub fn load_from(
+        args: impl IntoIterator<Item = String>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let vars: Vec<(String, String)> = vars.into_iter().collect();
+        let args = CliArgs::parse(args)?;
+
+        let config_path = args.config_path.or_else(|| {
+            vars.iter()
+                .find(|(name, _)| name == CONFIG_PATH_ENV)
+                .map(|(_, value)| PathBuf::from(value))
+        });
+
+        let mut table = match Value::try_from(Self::default())? {
+            Value::Table(table) => table,
+            _ => unreachable!("config serializes to a table"),
+        };
+
+        match config_path {
+            Some(path) => merge_tables(&mut table, read_table(&path)?),
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                merge_tables(&mut table, read_table(Path::new(DEFAULT_CONFIG_PATH))?)
+            }
+            None => info!("No config file, using defaults"),
+        }
+
+        for (name, value) in vars.iter() {
+            if name == CONFIG_PATH_ENV {
+                continue;
+            }
+            if let Some(key) = env_key(name) {
+                set_key(&mut table, &key, value)?;
+            }
+        }
+
+        for (key, value) in args.overrides.iter() {
+            set_key(&mut table, key, value)?;
+        }
+
+        let cfg: Self = serde_path_to_error::deserialize(Value::Table(table))
+            .map_err(|err| ConfigError::new(&err.path().to_string(), err.inner().message()))?;
+        cfg.validate()?;
+
+        Ok(cfg)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        non_empty("account.email", &self.account.email)?;
+        if !self.account.email.contains('@') {
+            return Err(ConfigError::new("account.email", "not an email address"));
+        }
+        non_empty("account.password", &self.account.password)?;
+
+        non_empty("device.name", &self.device.name)?;
+        non_empty("device.uuid", &self.device.uuid)?;
+        if !self
+            .device
+            .uuid
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='))
+        {
+            return Err(ConfigError::new("device.uuid", "not base64 encoded"));
+        }
+        non_empty("device.locale", &self.device.locale)?;
+
+        non_empty("client.language", &self.client.language)?;
+        if self
+            .client
+            .version
+            .split('.')
+            .any(|part| part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()))
+        {
+            return Err(ConfigError::new(
+                "client.version",
+                "expected a dotted version like 3.4.7",
+            ));
+        }
+        if self.client.agent != "win32" {
+            return Err(ConfigError::new(
+                "client.agent",
+                format!(
+                    "unsupported agent '{}', expected 'win32'",
+                    self.client.agent
+                ),
+            ));
+        }
+        non_empty("client.agent_version", &self.client.agent_version)?;
+
+        non_empty("xvc.seed_1", &self.xvc.seed_1)?;
+        non_empty("xvc.seed_2", &self.xvc.seed_2)?;
+
+        if self.system.data_dir.as_os_str().is_empty() {
+            return Err(ConfigError::new("system.data_dir", "must not be empty"));
+        }
+        if self.system.device_data_dir.as_os_str().is_empty() {
+            return Err(ConfigError::new(
+                "system.device_data_dir",
+                "must not be empty",
+            ));
+        }
+
+        if self.credential.path.as_os_str().is_empty() {
+            return Err(ConfigError::new("credential.path", "must not be empty"));
+        }
+
+        if self.reconnect.initial_delay_ms == 0 {
+            return Err(ConfigError::new(
+                "reconnect.initial_delay_ms",
+                "must be greater than 0",
+            ));
+        }
+        if self.reconnect.max_delay_ms < self.reconnect.initial_delay_ms {
+            return Err(ConfigError::new(
+                "reconnect.max_delay_ms",
+                "must not be less than reconnect.initial_delay_ms",
+            ));
+        }
+        if self.reconnect.multiplier < 1.0 {
+            return Err(ConfigError::new(
+                "reconnect.multiplier",
+                "must be at least 1.0",
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.reconnect.jitter) {
+            return Err(ConfigError::new(
+                "reconnect.jitter",
+                "must be between 0.0 and 1.0",
+            ));
+        }
+
+        if self.archive.enabled && self.archive.path.as_os_str().is_empty() {
+            return Err(ConfigError::new("archive.path", "must not be empty"));
+        }
+
+        if self.users.path.as_os_str().is_empty() {
+            return Err(ConfigError::new("users.path", "must not be empty"));
+        }
+
+        if self.backfill.enabled && self.backfill.cursor_path.as_os_str().is_empty() {
+            return Err(ConfigError::new(
+                "backfill.cursor_path",
+                "must not be empty",
+            ));
+        }
+
+        let spam_scores = [
+            ("spam.link_score", self.spam.link_score),
+            ("spam.invite_link_score", self.spam.invite_link_score),
+            ("spam.contact_score", self.spam.contact_score),
+            ("spam.keyword_score", self.spam.keyword_score),
+            ("spam.repeat_score", self.spam.repeat_score),
+        ];
+        for (key, score) in spam_scores {
+            if !(0.0..=1.0).contains(&score) {
+                return Err(ConfigError::new(key, "must be between 0.0 and 1.0"));
+            }
+        }
+        if self.spam.repeat_count < 2 {
+            return Err(ConfigError::new("spam.repeat_count", "must be at least 2"));
+        }
+        if let Some(threshold) = self
+            .spam
+            .actions
+            .iter()
+            .find(|threshold| !(0.0..=1.0).contains(&threshold.score))
+        {
+            return Err(ConfigError::new(
+                "spam.actions",
+                format!("score {} must be between 0.0 and 1.0", threshold.score),
+            ));
+        }
+
+        if self.audit.enabled && self.audit.path.as_os_str().is_empty() {
+            return Err(ConfigError::new("audit.path", "must not be empty"));
+        }
+
+        let flood_windows = [
+            ("flood.user_window_secs", self.flood.user_window_secs),
+            ("flood.channel_window_secs", self.flood.channel_window_secs),
+            (
+                "flood.duplicate_window_secs",
+                self.flood.duplicate_window_secs,
+            ),
+        ];
+        for (key, window) in flood_windows {
+            if window == 0 {
+                return Err(ConfigError::new(key, "must be greater than 0"));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.flood.max_emoji_ratio) {
+            return Err(ConfigError::new(
+                "flood.max_emoji_ratio",
+                "must be between 0.0 and 1.0",
+            ));
+        }
+
+        if self.send.global_rate <= 0.0 {
+            return Err(ConfigError::new(
+                "send.global_rate",
+                "must be greater than 0",
+            ));
+        }
+        if self.send.channel_rate <= 0.0 {
+            return Err(ConfigError::new(
+                "send.channel_rate",
+                "must be greater than 0",
+            ));
+        }
+        if self.send.global_burst == 0 {
+            return Err(ConfigError::new("send.global_burst", "must be at least 1"));
+        }
+        if self.send.channel_burst == 0 {
+            return Err(ConfigError::new("send.channel_burst", "must be at least 1"));
+        }
+        if self.send.max_retries > 10 {
+            return Err(ConfigError::new("send.max_retries", "must be at most 10"));
+        }
+
+        for (key, url) in [
+            ("media.upload_url", &self.media.upload_url),
+            ("media.download_url", &self.media.download_url),
+        ] {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(ConfigError::new(key, "must be an http(s) url"));
+            }
+        }
+        if let Some(host) = self.media.download_hosts.iter().find(|host| {
+            host.is_empty()
+                || !host
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-.".contains(&b))
+        }) {
+            return Err(ConfigError::new(
+                "media.download_hosts",
+                format!("'{}' is not a host name", host),
+            ));
+        }
+        if self.media.max_download_bytes == 0 {
+            return Err(ConfigError::new(
+                "media.max_download_bytes",
+                "must be greater than 0",
+            ));
+        }
+        if self.media.download_incoming && !self.archive.enabled {
+            return Err(ConfigError::new(
+                "media.download_incoming",
+                "needs archive.enabled, downloads are recorded in the archive",
+            ));
+        }
+        if self.media.timeout_secs == 0 {
+            return Err(ConfigError::new("media.timeout_secs", "must be at least 1"));
+        }
+        if self.media.download_queue_size == 0 {
+            return Err(ConfigError::new(
+                "media.download_queue_size",
+                "must be at least 1",
+            ));
+        }
+
+        if self.api.enabled && self.api.token.len() < 16 {
+            return Err(ConfigError::new(
+                "api.token",
+                "must be at least 16 characters when the api is enabled",
+            ));
+        }
+
+        if self.webhook.enabled {
+            for endpoint in self.webhook.endpoints.iter() {
+                if !endpoint.url.starts_with("http://") && !endpoint.url.starts_with("https://") {
+                    return Err(ConfigError::new(
+                        "webhook.endpoints",
+                        format!("'{}' is not an http(s) url", endpoint.url),
+                    ));
+                }
+                non_empty("webhook.endpoints.secret", &endpoint.secret)?;
+            }
+        }
+        if self.webhook.timeout_secs == 0 {
+            return Err(ConfigError::new(
+                "webhook.timeout_secs",
+                "must be at least 1",
+            ));
+        }
+        if self.webhook.max_attempts == 0 {
+            return Err(ConfigError::new(
+                "webhook.max_attempts",
+                "must be at least 1",
+            ));
+        }
+        if self.webhook.max_backoff_ms < self.webhook.initial_backoff_ms {
+            return Err(ConfigError::new(
+                "webhook.max_backoff_ms",
+                "must not be less than webhook.initial_backoff_ms",
+            ));
+        }
+        if self.webhook.queue_size == 0 {
+            return Err(ConfigError::new("webhook.queue_size", "must be at least 1"));
+        }
+        if self.webhook.dead_letter_path.as_os_str().is_empty() {
+            return Err(ConfigError::new(
+                "webhook.dead_letter_path",
+                "must not be empty",
+            ));
+        }
+
+        for link in self.membership.links.iter() {
+            if !link.url.starts_with("https://open.kakao.com/") {
+                return Err(ConfigError::new(
+                    "membership.links",
+                    format!("'{}' is not an open chat link", link.url),
+                ));
+            }
+            non_empty("membership.links.nickname", &link.nickname)?;
+        }
+        if !self.membership.links.is_empty() && self.membership.check_interval_secs == 0 {
+            return Err(ConfigError::new(
+                "membership.check_interval_secs",
+                "must be at least 1",
+            ));
+        }
+
+        if self.rules.enabled && self.rules.path.as_os_str().is_empty() {
+            return Err(ConfigError::new("rules.path", "must not be empty"));
+        }
+
+        if self.commands.prefix.is_empty() || self.commands.prefix.contains(char::is_whitespace) {
+            return Err(ConfigError::new(
+                "commands.prefix",
+                "must be non-empty and contain no whitespace",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn auth_client_config(&self) -> AuthClientConfig<'_> {
+        AuthClientConfig {
+            device: AuthDeviceConfig {
+                name: &self.device.name,
+                model: self.device.model.as_deref(),
+                uuid: &self.device.uuid,
+            },
+            language: &self.client.language,
+            version: &self.client.version,
+            agent: TalkApiAgent::Win32(&self.client.agent_version),
+        }
+    }
+
+    pub fn xvc_hasher(&self) -> Win32XVCHasher<'_> {
+        Win32XVCHasher(&self.xvc.seed_1, &self.xvc.seed_2)
+    }
+
+    pub fn system_info(&self) -> SystemInfo {
+        SystemInfo {
+            device_data_dir: self.system.device_data_dir.clone(),
+            data_dir: self.system.data_dir.clone(),
+            device_info: DeviceInfo {
+                locale: self.device.locale.clone(),
+                name: self.device.name.clone(),
+                device_uuid: DeviceUuid(self.device.uuid.clone()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CliArgs {
+    config_path: Option<PathBuf>,
+    overrides: Vec<(String, String)>,
+}
+
+impl CliArgs {
+    // Accepts `--config <path>` and `--<section>.<key> <value>` (or `--<section>.<key>=<value>`)
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                bail!("unexpected argument '{}'", arg);
+            };
+
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.to_owned(), value.to_owned()),
+                None => {
+                    let value = args
+                        .next()
+                        .with_context(|| format!("missing value for flag '--{}'", flag))?;
+                    (flag.to_owned(), value)
+                }
+            };
+
+            if name == "config" {
+                parsed.config_path = Some(value.into());
+            } else if name.contains('.') {
+                parsed.overrides.push((name, value));
+            } else {
+                bail!(
+                    "unknown flag '--{}', expected --config or --<section>.<key>",
+                    name
+                );
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn non_empty(key: &str, value: &str) -> Result<(), ConfigError> {
+    if value.trim().is_empty() {
+        return Err(ConfigError::new(key, "must not be empty"));
+    }
+    Ok(())
+}
+
+fn read_table(path: &Path) -> Result<Table> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("read config file {}", path.display()))?;
+    let table = contents
+        .parse::<Table>()
+        .with_context(|| format!("parse config file {}", path.display()))?;
+    info!("Loaded config file {}", path.display());
+    Ok(table)
+}
+
+fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge_tables(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// KAKAO_ACCOUNT_EMAIL -> account.email, KAKAO_SYSTEM_DATA_DIR -> system.data_dir
+fn env_key(name: &str) -> Option<String> {
+    let rest = name.strip_prefix(ENV_PREFIX)?.to_ascii_lowercase();
+    let (section, key) = rest.split_once('_')?;
+    Some(format!("{}.{}", section, key))
+}
+
+fn set_key(table: &mut Table, key: &str, raw: &str) -> Result<(), ConfigError> {
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().filter(|part| !part.is_empty());
+    let (Some(last), false) = (last, parts.is_empty()) else {
+        return Err(ConfigError::new(key, "expected <section>.<key>"));
+    };
+
+    let mut current = table;
+    for part in parts {
+        current = match current
+            .entry(part.to_owned())
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            Value::Table(table) => table,
+            _ => {
+                return Err(ConfigError::new(
+                    key,
+                    format!("'{}' is not a section", part),
+                ))
+            }
+        };
+    }
+
+    // Overrides are plain strings, so use the type of the existing value to interpret them
+    let value = match current.get(last) {
+        Some(Value::String(_)) => Value::String(raw.to_owned()),
+        Some(Value::Integer(_)) => {
+            Value::Integer(raw.parse().map_err(|_| {
+                ConfigError::new(key, format!("expected an integer, got '{}'", raw))
+            })?)
+        }
+        Some(Value::Float(_)) => Value::Float(
+            raw.parse()
+                .map_err(|_| ConfigError::new(key, format!("expected a number, got '{}'", raw)))?,
+        ),
+        Some(Value::Boolean(_)) => Value::Boolean(raw.parse().map_err(|_| {
+            ConfigError::new(key, format!("expected true or false, got '{}'", raw))
+        })?),
+        Some(_) => parse_literal(raw).ok_or_else(|| {
+            ConfigError::new(key, format!("expected a TOML value, got '{}'", raw))
+        })?,
+        None => parse_literal(raw).unwrap_or_else(|| Value::String(raw.to_owned())),
+    };
+    current.insert(last.to_owned(), value);
+
+    Ok(())
+}
+
+fn parse_literal(raw: &str) -> Option<Value> {
+    format!("value = {}", raw)
+        .parse::<Table>()
+        .ok()?
+        .remove("value")
+}
@@ -0,0 +1,195 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+};
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use kiwi_talk_app::app::AppCredential;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::KakaoClientCfg;
+
+// File layout: MAGIC, argon2 m_cost, t_cost, p_cost (u32 LE each), salt, nonce, ciphertext
+const MAGIC: &[u8; 4] = b"KCR1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 12 + SALT_LEN;
+
+// Argon2id with the OWASP recommended minimum, files keep the costs they were written with
+const M_COST: u32 = 19 * 1024;
+const T_COST: u32 = 2;
+const P_COST: u32 = 1;
+// Refuses files asking for more than 1 GiB of memory
+const MAX_M_COST: u32 = 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct StoredCredential {
+    access_token: String,
+    refresh_token: String,
+    user_id: Option<i64>,
+}
+
+// Keeps the last AppCredential on disk, encrypted with AES-256-GCM under a key
+// derived from the user supplied passphrase with a salted Argon2id
+pub struct CredentialStore {
+    path: PathBuf,
+    passphrase: String,
+}
+
+impl CredentialStore {
+    pub fn new(path: PathBuf, passphrase: &str) -> Self {
+        Self {
+            path,
+            passphrase: passphrase.to_owned(),
+        }
+    }
+
+    fn cipher(&self, params: Params, salt: &[u8]) -> Result<Aes256Gcm> {
+        let mut key = Key::<Aes256Gcm>::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(self.passphrase.as_bytes(), salt, &mut key)
+            .map_err(|err| anyhow!("derive credential key: {}", err))?;
+        Ok(Aes256Gcm::new(&key))
+    }
+
+    // None when no key is configured, credentials are not persisted then
+    pub fn from_cfg(cfg: &KakaoClientCfg) -> Option<Self> {
+        if cfg.credential.key.is_empty() {
+            return None;
+        }
+
+        Some(Self::new(
+            cfg.system.data_dir.join(&cfg.credential.path),
+            &cfg.credential.key,
+        ))
+    }
+
+    pub fn load(&self) -> Result<Option<AppCredential>> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("read credential file {}", self.path.display()))
+            }
+        };
+        if !data.starts_with(MAGIC) {
+            warn!(
+                "Credential file {} has an old format, logging in again",
+                self.path.display()
+            );
+            return Ok(None);
+        }
+        ensure!(
+            data.len() > HEADER_LEN + NONCE_LEN,
+            "credential file is truncated"
+        );
+
+        let (header, rest) = data.split_at(HEADER_LEN);
+        let cost = |i: usize| {
+            let at = MAGIC.len() + i * 4;
+            u32::from_le_bytes(header[at..at + 4].try_into().unwrap())
+        };
+        let (m_cost, t_cost, p_cost) = (cost(0), cost(1), cost(2));
+        ensure!(
+            m_cost <= MAX_M_COST,
+            "credential file asks for {} KiB to derive its key",
+            m_cost
+        );
+        let params = Params::new(m_cost, t_cost, p_cost, None)
+            .map_err(|err| anyhow!("credential file key parameters: {}", err))?;
+        let salt = &header[HEADER_LEN - SALT_LEN..];
+
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher(params, salt)?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("decrypt credential file, wrong key?"))?;
+        let stored: StoredCredential =
+            serde_json::from_slice(&plaintext).context("parse credential file")?;
+        info!("Loaded stored credential from {}", self.path.display());
+
+        Ok(Some(AppCredential {
+            access_token: stored.access_token,
+            refresh_token: stored.refresh_token,
+            user_id: stored.user_id,
+        }))
+    }
+
+    pub fn save(&self, credential: &AppCredential) -> Result<()> {
+        let plaintext = serde_json::to_vec(&StoredCredential {
+            access_token: credential.access_token.clone(),
+            refresh_token: credential.refresh_token.clone(),
+            user_id: credential.user_id,
+        })?;
+
+        // Fresh salt on every save
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let params = Params::new(M_COST, T_COST, P_COST, None)
+            .map_err(|err| anyhow!("credential key parameters: {}", err))?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher(params, &salt)?
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| anyhow!("encrypt credential"))?;
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("create credential dir")?;
+        }
+        let mut data = MAGIC.to_vec();
+        for cost in [M_COST, T_COST, P_COST] {
+            data.extend_from_slice(&cost.to_le_bytes());
+        }
+        data.extend_from_slice(&salt);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        self.write_atomic(&data)
+            .with_context(|| format!("write credential file {}", self.path.display()))?;
+        info!("Saved credential to {}", self.path.display());
+
+        Ok(())
+    }
+
+    // Owner only from the start, and renamed into place so a crash never leaves half a file
+    fn write_atomic(&self, data: &[u8]) -> Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        // The mode only applies to new files, so a leftover temp file is not reused
+        match fs::remove_file(&tmp_path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                return Err(err).context("remove stale temp file")
+            }
+            _ => (),
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp_path).context("create temp file")?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&tmp_path, &self.path).context("move temp file into place")?;
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).context("remove credential file")
+            }
+            _ => Ok(()),
+        }
+    }
+}
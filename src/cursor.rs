@@ -0,0 +1,60 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::*;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Last delivered log id per channel, persisted so missed chats can be backfilled after a restart
+pub struct CursorStore {
+    path: PathBuf,
+    last_saved: Option<Instant>,
+}
+
+impl CursorStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            last_saved: None,
+        }
+    }
+
+    pub fn load(&self) -> Result<HashMap<i64, i64>> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("read cursor file {}", self.path.display()))
+            }
+        };
+
+        let cursors = serde_json::from_slice(&data).context("parse cursor file")?;
+        info!("Loaded chat cursors from {}", self.path.display());
+        Ok(cursors)
+    }
+
+    pub fn save(&mut self, cursors: &HashMap<i64, i64>) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("create cursor dir")?;
+        }
+        fs::write(&self.path, serde_json::to_vec(cursors)?)
+            .with_context(|| format!("write cursor file {}", self.path.display()))?;
+        self.last_saved = Some(Instant::now());
+        Ok(())
+    }
+
+    // Saves at most every few seconds, chats arrive far more often than that on busy accounts
+    pub fn save_throttled(&mut self, cursors: &HashMap<i64, i64>) -> Result<()> {
+        match self.last_saved {
+            Some(last_saved) if last_saved.elapsed() < SAVE_INTERVAL => Ok(()),
+            _ => self.save(cursors),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use std::{
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::*;
+use rusqlite::Connection;
+
+// Opens a SQLite database and applies the migrations it has not seen yet,
+// the applied count is kept in PRAGMA user_version
+pub fn open(path: &Path, migrations: &[&str]) -> Result<Connection> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("create database dir")?;
+    }
+
+    let mut conn =
+        Connection::open(path).with_context(|| format!("open database {}", path.display()))?;
+
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in migrations.iter().enumerate().skip(version) {
+        info!("Migrating {} to version {}", path.display(), i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("migrate {} to version {}", path.display(), i + 1))?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(conn)
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs() as i64)
+        .unwrap_or_default()
+}
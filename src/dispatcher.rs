@@ -0,0 +1,317 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use kiwi_talk_client::{
+    error::KiwiTalkClientError,
+    event::{
+        chat::{ChatEvent, ChatReceived},
+        KiwiTalkClientEvent,
+    },
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use talk_loco_command::structs::openlink::OpenLinkUser;
+
+use crate::{
+    feed::{self, Feed, FeedMember},
+    flood::AbuseKind,
+    kakao::{KakaoClient, KakaoEvent},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Chat,
+    ProfileChanged,
+    MemberJoined,
+    MemberLeft,
+    MessageDeleted,
+    MessageHidden,
+    MessageUnhidden,
+    AbuseDetected,
+    Error,
+    Disconnected,
+    Reconnected,
+    Unhandled,
+}
+
+// KakaoEvent sorted by what bots care about. Member and deletion events are decoded
+// from feed chats, the original chat is kept for logging and archiving
+#[derive(Debug)]
+pub enum BotEvent {
+    Chat(ChatReceived),
+    ProfileChanged {
+        channel_id: i64,
+        link_id: i64,
+        user: OpenLinkUser,
+    },
+    MemberJoined {
+        chat: ChatReceived,
+        members: Vec<FeedMember>,
+    },
+    MemberLeft {
+        chat: ChatReceived,
+        members: Vec<FeedMember>,
+        kicked: bool,
+    },
+    MessageDeleted {
+        chat: ChatReceived,
+        log_id: i64,
+    },
+    MessageHidden {
+        chat: ChatReceived,
+        log_ids: Vec<i64>,
+    },
+    // A host or manager restored hidden chats
+    MessageUnhidden {
+        chat: ChatReceived,
+        log_ids: Vec<i64>,
+    },
+    AbuseDetected {
+        chat: ChatReceived,
+        abuses: Vec<AbuseKind>,
+    },
+    Error(KiwiTalkClientError),
+    Disconnected {
+        reason: String,
+    },
+    Reconnected {
+        attempts: u32,
+        last_log_ids: HashMap<i64, i64>,
+    },
+    Unhandled(KiwiTalkClientEvent),
+}
+
+impl BotEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            BotEvent::Chat(_) => EventKind::Chat,
+            BotEvent::ProfileChanged { .. } => EventKind::ProfileChanged,
+            BotEvent::MemberJoined { .. } => EventKind::MemberJoined,
+            BotEvent::MemberLeft { .. } => EventKind::MemberLeft,
+            BotEvent::MessageDeleted { .. } => EventKind::MessageDeleted,
+            BotEvent::MessageHidden { .. } => EventKind::MessageHidden,
+            BotEvent::MessageUnhidden { .. } => EventKind::MessageUnhidden,
+            BotEvent::AbuseDetected { .. } => EventKind::AbuseDetected,
+            BotEvent::Error(_) => EventKind::Error,
+            BotEvent::Disconnected { .. } => EventKind::Disconnected,
+            BotEvent::Reconnected { .. } => EventKind::Reconnected,
+            BotEvent::Unhandled(_) => EventKind::Unhandled,
+        }
+    }
+
+    pub fn channel_id(&self) -> Option<i64> {
+        match self {
+            BotEvent::ProfileChanged { channel_id, .. } => Some(*channel_id),
+            _ => self.chat().map(|chat| chat.channel_id),
+        }
+    }
+
+    // The chat behind the event, including feed chats
+    pub fn chat(&self) -> Option<&ChatReceived> {
+        match self {
+            BotEvent::Chat(chat)
+            | BotEvent::MemberJoined { chat, .. }
+            | BotEvent::MemberLeft { chat, .. }
+            | BotEvent::MessageDeleted { chat, .. }
+            | BotEvent::MessageHidden { chat, .. }
+            | BotEvent::MessageUnhidden { chat, .. }
+            | BotEvent::AbuseDetected { chat, .. } => Some(chat),
+            _ => None,
+        }
+    }
+}
+
+impl From<KakaoEvent> for BotEvent {
+    fn from(event: KakaoEvent) -> Self {
+        match event {
+            KakaoEvent::Talk(KiwiTalkClientEvent::Chat(ChatEvent::Chat(chat))) => {
+                match Feed::parse(&chat.chat.chat) {
+                    Some(feed) => from_feed(chat, feed),
+                    None => BotEvent::Chat(chat),
+                }
+            }
+            KakaoEvent::Talk(KiwiTalkClientEvent::ProfileChanged(e)) => BotEvent::ProfileChanged {
+                channel_id: e.channel_id,
+                link_id: e.link_id,
+                user: e.open_link_user,
+            },
+            KakaoEvent::Talk(KiwiTalkClientEvent::Error(err)) => BotEvent::Error(err),
+            KakaoEvent::Talk(event) => BotEvent::Unhandled(event),
+            KakaoEvent::AbuseDetected { chat, abuses } => BotEvent::AbuseDetected { chat, abuses },
+            KakaoEvent::Disconnected { reason } => BotEvent::Disconnected { reason },
+            KakaoEvent::Reconnected {
+                attempts,
+                last_log_ids,
+            } => BotEvent::Reconnected {
+                attempts,
+                last_log_ids,
+            },
+        }
+    }
+}
+
+fn from_feed(chat: ChatReceived, feed: Feed) -> BotEvent {
+    match feed.feed_type {
+        feed::FEED_INVITE | feed::FEED_OPENLINK_JOIN => BotEvent::MemberJoined {
+            members: feed.all_members(),
+            chat,
+        },
+        feed::FEED_LEAVE | feed::FEED_SECRET_LEAVE => BotEvent::MemberLeft {
+            members: feed.all_members(),
+            kicked: false,
+            chat,
+        },
+        feed::FEED_OPENLINK_KICKED | feed::FEED_CHANNEL_KICKED => BotEvent::MemberLeft {
+            members: feed.all_members(),
+            kicked: true,
+            chat,
+        },
+        feed::FEED_DELETE_TO_ALL => match feed.log_id {
+            Some(log_id) => BotEvent::MessageDeleted { chat, log_id },
+            None => BotEvent::Chat(chat),
+        },
+        // The same feed reports hiding and restoring chats
+        feed::FEED_OPENLINK_REWRITE if feed.hidden => BotEvent::MessageHidden {
+            log_ids: feed.log_ids,
+            chat,
+        },
+        feed::FEED_OPENLINK_REWRITE => BotEvent::MessageUnhidden {
+            log_ids: feed.log_ids,
+            chat,
+        },
+        _ => BotEvent::Chat(chat),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    // Skip the remaining lower priority handlers for this event
+    Stop,
+}
+
+// Handlers run one at a time on the task driving the dispatcher, so they don't need to be Send
+#[async_trait(?Send)]
+pub trait EventHandler {
+    async fn handle(&self, client: &mut KakaoClient, event: &BotEvent) -> Result<Flow>;
+}
+
+// Lets plain fns be registered:
+// fn handler<'a>(client: &'a mut KakaoClient, event: &'a BotEvent) -> LocalBoxFuture<'a, Result<Flow>>
+#[async_trait(?Send)]
+impl<F> EventHandler for F
+where
+    F: for<'a> Fn(&'a mut KakaoClient, &'a BotEvent) -> LocalBoxFuture<'a, Result<Flow>>,
+{
+    async fn handle(&self, client: &mut KakaoClient, event: &BotEvent) -> Result<Flow> {
+        self(client, event).await
+    }
+}
+
+// Lets a handler be registered while other parts of the bot keep using it
+#[async_trait(?Send)]
+impl<T: EventHandler + ?Sized> EventHandler for Arc<T> {
+    async fn handle(&self, client: &mut KakaoClient, event: &BotEvent) -> Result<Flow> {
+        (**self).handle(client, event).await
+    }
+}
+
+pub struct HandlerEntry {
+    kind: Option<EventKind>,
+    priority: i32,
+    channels: Option<HashSet<i64>>,
+    handler: Box<dyn EventHandler>,
+}
+
+impl HandlerEntry {
+    // Higher runs first, handlers with the same priority run in registration order
+    pub fn priority(&mut self, priority: i32) -> &mut Self {
+        self.priority = priority;
+        self
+    }
+
+    // Only deliver events from these channels, events without a channel are always delivered
+    pub fn channels(&mut self, channels: impl IntoIterator<Item = i64>) -> &mut Self {
+        self.channels = Some(channels.into_iter().collect());
+        self
+    }
+
+    fn accepts(&self, event: &BotEvent) -> bool {
+        if self.kind.is_some_and(|kind| kind != event.kind()) {
+            return false;
+        }
+
+        match (&self.channels, event.channel_id()) {
+            (Some(channels), Some(channel_id)) => channels.contains(&channel_id),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Dispatcher {
+    handlers: Vec<HandlerEntry>,
+    sorted: bool,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on(
+        &mut self,
+        kind: EventKind,
+        handler: impl EventHandler + 'static,
+    ) -> &mut HandlerEntry {
+        self.push(Some(kind), Box::new(handler))
+    }
+
+    pub fn on_any(&mut self, handler: impl EventHandler + 'static) -> &mut HandlerEntry {
+        self.push(None, Box::new(handler))
+    }
+
+    fn push(
+        &mut self,
+        kind: Option<EventKind>,
+        handler: Box<dyn EventHandler>,
+    ) -> &mut HandlerEntry {
+        self.sorted = false;
+        self.handlers.push(HandlerEntry {
+            kind,
+            priority: 0,
+            channels: None,
+            handler,
+        });
+        self.handlers.last_mut().unwrap()
+    }
+
+    pub async fn run(&mut self, client: &mut KakaoClient) -> Result<()> {
+        loop {
+            let event = client.next_event().await?;
+            self.dispatch(client, event.into()).await;
+        }
+    }
+
+    pub async fn dispatch(&mut self, client: &mut KakaoClient, event: BotEvent) {
+        if !self.sorted {
+            self.handlers
+                .sort_by_key(|entry| std::cmp::Reverse(entry.priority));
+            self.sorted = true;
+        }
+
+        for entry in self.handlers.iter().filter(|entry| entry.accepts(&event)) {
+            match entry.handler.handle(client, &event).await {
+                Ok(Flow::Continue) => (),
+                Ok(Flow::Stop) => break,
+                Err(err) => error!("Handler failed on {:?} event: {:?}", event.kind(), err),
+            }
+        }
+    }
+}
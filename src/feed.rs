@@ -0,0 +1,63 @@
+use kiwi_talk_client::chat::Chat;
+use serde::Deserialize;
+
+// Feed chats are system messages (joins, leaves, deletions...) with a JSON body in the message
+pub const FEED_CHAT_TYPE: i32 = 0;
+
+pub const FEED_INVITE: i32 = 1;
+pub const FEED_LEAVE: i32 = 2;
+pub const FEED_SECRET_LEAVE: i32 = 3;
+pub const FEED_OPENLINK_JOIN: i32 = 4;
+pub const FEED_OPENLINK_DELETE_LINK: i32 = 5;
+pub const FEED_OPENLINK_KICKED: i32 = 6;
+pub const FEED_CHANNEL_KICKED: i32 = 7;
+pub const FEED_CHANNEL_DELETED: i32 = 8;
+pub const FEED_OPEN_MANAGER_GRANT: i32 = 11;
+pub const FEED_OPEN_MANAGER_REVOKE: i32 = 12;
+pub const FEED_OPENLINK_REWRITE: i32 = 13;
+pub const FEED_DELETE_TO_ALL: i32 = 14;
+pub const FEED_OPENLINK_HAND_OVER_HOST: i32 = 15;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Feed {
+    pub feed_type: i32,
+    #[serde(default)]
+    pub members: Vec<FeedMember>,
+    pub member: Option<FeedMember>,
+    pub log_id: Option<i64>,
+    #[serde(default)]
+    pub log_ids: Vec<i64>,
+    #[serde(default)]
+    pub hidden: bool,
+    // Host handover only
+    pub prev_host: Option<FeedMember>,
+    pub new_host: Option<FeedMember>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedMember {
+    pub user_id: i64,
+    #[serde(rename = "nickName")]
+    pub nickname: Option<String>,
+}
+
+impl Feed {
+    pub fn parse(chat: &Chat) -> Option<Self> {
+        if chat.chat_type.0 != FEED_CHAT_TYPE {
+            return None;
+        }
+
+        serde_json::from_str(chat.content.message.as_deref()?).ok()
+    }
+
+    // Some feeds carry a single `member` instead of `members`
+    pub fn all_members(&self) -> Vec<FeedMember> {
+        self.members
+            .iter()
+            .chain(self.member.iter())
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use kiwi_talk_client::event::chat::ChatReceived;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    attachment::Attachment,
+    config::FloodCfg,
+    dispatcher::{BotEvent, EventHandler, Flow},
+    feed,
+    kakao::KakaoClient,
+    moderation::{self, ModerationAction, Target, Trigger},
+};
+
+// Observations between sweeps of idle senders and channels
+const SWEEP_INTERVAL: u32 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AbuseKind {
+    // Too many chats from one sender in the window
+    UserFlood { count: usize, window_secs: i64 },
+    // Too many chats in the channel as a whole, raised once per window
+    ChannelFlood { count: usize, window_secs: i64 },
+    Duplicate { count: usize, window_secs: i64 },
+    // Longest run of the same character
+    CharacterSpam { run: usize },
+    EmojiSpam { ratio: f64 },
+    MentionSpam { mentions: usize },
+}
+
+impl AbuseKind {
+    // Whether the sender of the chat is to blame, channel floods are everyone's
+    pub fn is_user_abuse(&self) -> bool {
+        !matches!(self, AbuseKind::ChannelFlood { .. })
+    }
+}
+
+impl fmt::Display for AbuseKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AbuseKind::UserFlood { count, window_secs } => {
+                write!(f, "{} chats in {}s from one user", count, window_secs)
+            }
+            AbuseKind::ChannelFlood { count, window_secs } => {
+                write!(f, "{} chats in {}s in the channel", count, window_secs)
+            }
+            AbuseKind::Duplicate { count, window_secs } => {
+                write!(f, "same chat {} times in {}s", count, window_secs)
+            }
+            AbuseKind::CharacterSpam { run } => write!(f, "same character {} times in a row", run),
+            AbuseKind::EmojiSpam { ratio } => write!(f, "{:.0}% emoji", ratio * 100.0),
+            AbuseKind::MentionSpam { mentions } => write!(f, "{} mentions", mentions),
+        }
+    }
+}
+
+// Sliding windows over chat send times, per (channel, sender) and per channel.
+// Server send times are used so backfilled chats are counted when they were sent
+#[derive(Debug)]
+pub struct FloodDetector {
+    cfg: FloodCfg,
+    users: HashMap<(i64, i64), VecDeque<(i64, String)>>,
+    channels: HashMap<i64, VecDeque<i64>>,
+    // Window start of the last channel flood raised, to raise it once per window
+    channel_floods: HashMap<i64, i64>,
+    // Send time of the last user abuse raised per (channel, sender), the sender's abuse is
+    // raised once per window too, so one burst is acted on once
+    user_abuses: HashMap<(i64, i64), i64>,
+    observed: u32,
+}
+
+impl FloodDetector {
+    pub fn new(cfg: &FloodCfg) -> Self {
+        Self {
+            cfg: cfg.clone(),
+            users: HashMap::new(),
+            channels: HashMap::new(),
+            channel_floods: HashMap::new(),
+            user_abuses: HashMap::new(),
+            observed: 0,
+        }
+    }
+
+    pub fn observe(&mut self, chat: &ChatReceived) -> Vec<AbuseKind> {
+        let mut abuses = Vec::new();
+        let user = (chat.channel_id, chat.chat.sender_id);
+        if chat.chat.chat.chat_type.0 == feed::FEED_CHAT_TYPE {
+            return abuses;
+        }
+
+        self.observed += 1;
+        if self.observed % SWEEP_INTERVAL == 0 {
+            self.sweep(chat.chat.send_at);
+        }
+
+        let now = chat.chat.send_at;
+        let content = &chat.chat.chat.content;
+        let text = normalize(content.message.as_deref().unwrap_or_default());
+
+        let user_window = self.cfg.user_window_secs as i64;
+        let duplicate_window = self.cfg.duplicate_window_secs as i64;
+        let sent = self.users.entry(user).or_default();
+        sent.push_back((now, text.clone()));
+        while sent
+            .front()
+            .is_some_and(|(at, _)| now - at >= user_window.max(duplicate_window))
+        {
+            sent.pop_front();
+        }
+
+        let count = sent.iter().filter(|(at, _)| now - at < user_window).count();
+        if count > self.cfg.user_max_messages {
+            abuses.push(AbuseKind::UserFlood {
+                count,
+                window_secs: user_window,
+            });
+        }
+        if !text.is_empty() {
+            let duplicates = sent
+                .iter()
+                .filter(|(at, sent)| now - at < duplicate_window && *sent == text)
+                .count();
+            if duplicates > self.cfg.duplicate_max {
+                abuses.push(AbuseKind::Duplicate {
+                    count: duplicates,
+                    window_secs: duplicate_window,
+                });
+            }
+        }
+
+        let channel_window = self.cfg.channel_window_secs as i64;
+        let sent = self.channels.entry(chat.channel_id).or_default();
+        sent.push_back(now);
+        while sent.front().is_some_and(|at| now - at >= channel_window) {
+            sent.pop_front();
+        }
+        if sent.len() > self.cfg.channel_max_messages {
+            let window_start = *sent.front().unwrap();
+            match self.channel_floods.get(&chat.channel_id) {
+                Some(last_raised) if window_start - last_raised < channel_window => (),
+                _ => {
+                    self.channel_floods.insert(chat.channel_id, window_start);
+                    abuses.push(AbuseKind::ChannelFlood {
+                        count: sent.len(),
+                        window_secs: channel_window,
+                    });
+                }
+            }
+        }
+
+        if let Some(message) = content.message.as_deref() {
+            let run = longest_run(message);
+            if self.cfg.max_char_run > 0 && run > self.cfg.max_char_run {
+                abuses.push(AbuseKind::CharacterSpam { run });
+            }
+
+            let chars = message.chars().filter(|c| !c.is_whitespace()).count();
+            if chars >= self.cfg.emoji_min_length {
+                let emoji = message.chars().filter(|c| is_emoji(*c)).count();
+                let ratio = emoji as f64 / chars as f64;
+                if ratio > self.cfg.max_emoji_ratio {
+                    abuses.push(AbuseKind::EmojiSpam { ratio });
+                }
+            }
+        }
+
+        let mentions = Attachment::parse(&chat.chat.chat).mentions().len();
+        if self.cfg.max_mentions > 0 && mentions > self.cfg.max_mentions {
+            abuses.push(AbuseKind::MentionSpam { mentions });
+        }
+
+        if abuses.iter().any(AbuseKind::is_user_abuse) {
+            match self.user_abuses.get(&user) {
+                Some(last_raised) if now - last_raised < user_window.max(duplicate_window) => {
+                    abuses.retain(|abuse| !abuse.is_user_abuse());
+                }
+                _ => {
+                    self.user_abuses.insert(user, now);
+                }
+            }
+        }
+
+        abuses
+    }
+
+    fn sweep(&mut self, now: i64) {
+        let keep = self
+            .cfg
+            .user_window_secs
+            .max(self.cfg.duplicate_window_secs)
+            .max(self.cfg.channel_window_secs) as i64;
+        self.users
+            .retain(|_, sent| sent.back().is_some_and(|(at, _)| now - at < keep));
+        self.channels
+            .retain(|_, sent| sent.back().is_some_and(|at| now - at < keep));
+        self.channel_floods
+            .retain(|channel_id, _| self.channels.contains_key(channel_id));
+        self.user_abuses
+            .retain(|user, _| self.users.contains_key(user));
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn longest_run(text: &str) -> usize {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut last = None;
+    for c in text.chars().filter(|c| !c.is_whitespace()) {
+        run = if last == Some(c) { run + 1 } else { 1 };
+        last = Some(c);
+        longest = longest.max(run);
+    }
+    longest
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x1F000..=0x1FAFF | 0x2600..=0x27BF | 0x2B00..=0x2BFF | 0xFE0F | 0x200D
+    )
+}
+
+// Applies flood.actions to the chats the detector flagged
+pub struct FloodGuard {
+    actions: Vec<ModerationAction>,
+}
+
+impl FloodGuard {
+    pub fn new(cfg: &FloodCfg) -> Self {
+        Self {
+            actions: cfg.actions.clone(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl EventHandler for FloodGuard {
+    async fn handle(&self, client: &mut KakaoClient, event: &BotEvent) -> Result<Flow> {
+        let BotEvent::AbuseDetected { chat, abuses } = event else {
+            return Ok(Flow::Continue);
+        };
+        let reasons: Vec<String> = abuses.iter().map(AbuseKind::to_string).collect();
+        warn!(
+            "Abuse in channel_id={} by user {}: {}",
+            chat.channel_id,
+            chat.chat.sender_id,
+            reasons.join(", ")
+        );
+
+        // Hide and kick only exist in open chats
+        if chat.link_id.is_none() {
+            return Ok(Flow::Continue);
+        }
+        let abuses: Vec<AbuseKind> = abuses
+            .iter()
+            .filter(|abuse| abuse.is_user_abuse())
+            .cloned()
+            .collect();
+        if abuses.is_empty() {
+            return Ok(Flow::Continue);
+        }
+
+        let trigger = Trigger::Flood { abuses };
+        for action in self.actions.iter().copied() {
+            if let Err(err) = moderation::apply(client, &Target::from(chat), action, &trigger).await
+            {
+                error!(
+                    "Cannot {:?} chat {} for {}: {:?}",
+                    action, chat.log_id, trigger, err
+                );
+            }
+        }
+
+        Ok(Flow::Continue)
+    }
+}
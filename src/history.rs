@@ -0,0 +1,150 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use futures::{stream, Stream, TryStreamExt};
+use kiwi_talk_client::chat::Chatlog;
+use log::*;
+
+use crate::kakao::{chatlog_from_loco, KakaoClient};
+
+// Pages through channel history with GETCHATLOGS. The command only returns logs newer than
+// `since`, so history is walked forward from there. Paging backward from a log id is not
+// supported, to read further back start from an older log id
+#[derive(Debug, Clone)]
+pub struct HistoryQuery {
+    // (channel_id, since log id), every channel is fetched in the same request
+    pub channels: Vec<(i64, i64)>,
+    // Stop a channel once this log id is reached, inclusive
+    pub until_log_id: Option<i64>,
+    // Stop a channel once chats sent after this unix time show up
+    pub until_time: Option<i64>,
+    // Upper bound on requests, 0 means no limit
+    pub max_pages: u32,
+}
+
+impl HistoryQuery {
+    pub fn new(channel_id: i64, since: i64) -> Self {
+        Self {
+            channels: vec![(channel_id, since)],
+            until_log_id: None,
+            until_time: None,
+            max_pages: 0,
+        }
+    }
+
+    pub fn channel(mut self, channel_id: i64, since: i64) -> Self {
+        self.channels.push((channel_id, since));
+        self
+    }
+
+    pub fn until_log_id(mut self, log_id: i64) -> Self {
+        self.until_log_id = Some(log_id);
+        self
+    }
+
+    pub fn until_time(mut self, send_at: i64) -> Self {
+        self.until_time = Some(send_at);
+        self
+    }
+
+    pub fn max_pages(mut self, max_pages: u32) -> Self {
+        self.max_pages = max_pages;
+        self
+    }
+
+    fn reached_end(&self, chatlog: &Chatlog) -> bool {
+        self.until_log_id
+            .is_some_and(|until| chatlog.log_id > until)
+            || self.until_time.is_some_and(|until| chatlog.send_at > until)
+    }
+}
+
+struct HistoryState<'a> {
+    client: &'a KakaoClient,
+    query: HistoryQuery,
+    // Channels still being paged, channel_id -> since
+    cursors: HashMap<i64, i64>,
+    seen: HashSet<i64>,
+    pages: u32,
+}
+
+impl<'a> HistoryState<'a> {
+    async fn next_page(&mut self) -> Result<Option<Vec<Chatlog>>> {
+        if self.cursors.is_empty()
+            || (self.query.max_pages > 0 && self.pages >= self.query.max_pages)
+        {
+            return Ok(None);
+        }
+        self.pages += 1;
+
+        let sinces: Vec<(i64, i64)> = self
+            .cursors
+            .iter()
+            .map(|(channel_id, since)| (*channel_id, *since))
+            .collect();
+        let (chat_logs, eof) = self
+            .client
+            .get_chat_logs_batch(&sinces)
+            .await
+            .context("get chat logs")?;
+
+        let mut chat_logs: Vec<Chatlog> = chat_logs.into_iter().map(chatlog_from_loco).collect();
+        chat_logs.sort_by_key(|chatlog| (chatlog.channel_id, chatlog.log_id));
+
+        let mut page = Vec::new();
+        let mut progressed = HashSet::new();
+        let mut finished = HashSet::new();
+        for chatlog in chat_logs {
+            let Some(since) = self.cursors.get_mut(&chatlog.channel_id) else {
+                continue;
+            };
+            if chatlog.log_id <= *since || finished.contains(&chatlog.channel_id) {
+                continue;
+            }
+            if self.query.reached_end(&chatlog) {
+                finished.insert(chatlog.channel_id);
+                continue;
+            }
+
+            *since = chatlog.log_id;
+            progressed.insert(chatlog.channel_id);
+            if self.seen.insert(chatlog.log_id) {
+                page.push(chatlog);
+            }
+        }
+
+        // A channel is done once a page brings nothing new for it
+        self.cursors.retain(|channel_id, _| {
+            !eof && progressed.contains(channel_id) && !finished.contains(channel_id)
+        });
+        info!(
+            "History page {} returned {} chats, {} channels left",
+            self.pages,
+            page.len(),
+            self.cursors.len()
+        );
+
+        Ok(Some(page))
+    }
+}
+
+// Chats in log id order per channel, pages are fetched lazily as the stream is polled
+pub fn history(
+    client: &KakaoClient,
+    query: HistoryQuery,
+) -> impl Stream<Item = Result<Chatlog>> + '_ {
+    let state = HistoryState {
+        client,
+        cursors: query.channels.iter().copied().collect(),
+        query,
+        seen: HashSet::new(),
+        pages: 0,
+    };
+
+    stream::try_unfold(state, |mut state| async move {
+        let page = state.next_page().await?;
+        Ok::<_, anyhow::Error>(page.map(|page| (page, state)))
+    })
+    .map_ok(|page| stream::iter(page.into_iter().map(Ok)))
+    .try_flatten()
+}
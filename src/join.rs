@@ -0,0 +1,91 @@
+use std::fmt;
+
+use talk_loco_client::client::ClientRequestError;
+
+// LOCO statuses the open chat join commands answer with
+const STATUS_CHAT_SPAM_LIMIT: i32 = -303;
+const STATUS_LINK_JOIN_TPS_EXCEEDED: i32 = -312;
+const STATUS_OPENLINK_UNAVAILABLE: i32 = -324;
+const STATUS_NICKNAME_REJECTED: i32 = -331;
+const STATUS_INVALID_CHANNEL: i32 = -401;
+const STATUS_CHANNEL_USER_LIMITED: i32 = -501;
+const STATUS_KICKED_FROM_CHAT: i32 = -802;
+const STATUS_PASSCODE_MISMATCH: i32 = -803;
+const STATUS_TEMP_RESTRICTED: i32 = -805;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinStage {
+    JoinInfo,
+    CheckJoin,
+    Join,
+}
+
+impl fmt::Display for JoinStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinStage::JoinInfo => write!(f, "get join info"),
+            JoinStage::CheckJoin => write!(f, "check join"),
+            JoinStage::Join => write!(f, "join channel"),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JoinError {
+    #[error("invite link is invalid or the open chat is gone")]
+    InvalidLink,
+    #[error("open chat needs a passcode")]
+    MissingPasscode,
+    #[error("wrong passcode")]
+    WrongPasscode,
+    #[error("open chat is full")]
+    RoomFull,
+    #[error("account was kicked from the open chat and can't rejoin")]
+    Banned,
+    #[error("nickname was rejected")]
+    NicknameRejected,
+    #[error("too many join attempts")]
+    RateLimited,
+    #[error("{stage} failed with status {status}")]
+    Status { stage: JoinStage, status: i32 },
+    #[error("{stage} failed: {source}")]
+    Request {
+        stage: JoinStage,
+        source: ClientRequestError,
+    },
+}
+
+impl JoinError {
+    pub fn from_request(stage: JoinStage, err: ClientRequestError, has_passcode: bool) -> Self {
+        let status = match err {
+            ClientRequestError::Status(status) => status,
+            source => return JoinError::Request { stage, source },
+        };
+
+        match status {
+            STATUS_INVALID_CHANNEL | STATUS_OPENLINK_UNAVAILABLE => JoinError::InvalidLink,
+            STATUS_PASSCODE_MISMATCH if has_passcode => JoinError::WrongPasscode,
+            STATUS_PASSCODE_MISMATCH => JoinError::MissingPasscode,
+            STATUS_CHANNEL_USER_LIMITED => JoinError::RoomFull,
+            STATUS_KICKED_FROM_CHAT => JoinError::Banned,
+            STATUS_NICKNAME_REJECTED => JoinError::NicknameRejected,
+            STATUS_CHAT_SPAM_LIMIT | STATUS_LINK_JOIN_TPS_EXCEEDED | STATUS_TEMP_RESTRICTED => {
+                JoinError::RateLimited
+            }
+            status => JoinError::Status { stage, status },
+        }
+    }
+
+    // Whether the same join can succeed later without changing anything
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            JoinError::RateLimited | JoinError::RoomFull | JoinError::Request { .. }
+        )
+    }
+
+    // Whether no retry can succeed, whatever the nickname or passcode
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, JoinError::InvalidLink | JoinError::Banned)
+    }
+}
@@ -1,142 +1,656 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    pin::pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use futures::{
     channel::mpsc::{channel, Receiver},
+    future::{pending, Either},
     StreamExt,
 };
 use kiwi_talk_app::{
     app::{client::create_client_2, AppCredential},
-    system::{DeviceInfo, DeviceUuid, SystemInfo},
+    system::SystemInfo,
 };
 use kiwi_talk_client::{
     channel::{ChannelDataVariant, ClientChannel},
-    chat::{Chat, Chatlog},
+    chat::{Chat, ChatContent, ChatType, Chatlog},
     error::KiwiTalkClientError,
-    event::{chat::ChatEvent, KiwiTalkClientEvent},
+    event::{
+        chat::{ChatEvent, ChatReceived},
+        KiwiTalkClientEvent,
+    },
     status::ClientStatus,
     KiwiTalkClient,
 };
 use log::*;
-use talk_api_client::{
-    agent::TalkApiAgent,
-    auth::{
-        xvc::default::Win32XVCHasher, AccountLoginForm, AuthClientConfig, AuthDeviceConfig,
-        LoginMethod, TalkAuthClient,
-    },
-};
+use rand::Rng;
+use talk_api_client::auth::{AccountLoginForm, LoginMethod, TalkAuthClient};
 use talk_loco_client::client::{talk::TalkClient, ClientRequestError};
 use talk_loco_command::{
     request::chat::{
-        join_channel::JoinChannelReqProfile, CheckJoinReq, DeleteMsgReq, GetChatLogsReq,
-        HideMsgReq, JoinChannelReq, JoinInfoReq, KickUserReq,
+        join_channel::JoinChannelReqProfile, CheckJoinReq, DeleteMsgReq, GetChatLogsReq, GetMemReq,
+        HideMsgReq, InfoLinkReq, JoinChannelReq, JoinInfoReq, KickUserReq, LeaveReq,
+        UpdateLinkProfileReq,
     },
-    response::chat::{join_channel::ChatRoomMember, JoinChannelRes},
-    structs::{chat::Chatlog as Chatlog2, openlink::OpenLinkUser, user::DisplayUserInfo},
+    structs::{
+        chat::Chatlog as Chatlog2,
+        openlink::{OpenLink, OpenLinkUser},
+        user::DisplayUserInfo,
+    },
+};
+use tokio::{sync::mpsc, time::sleep};
+
+use crate::{
+    api::ApiRequest,
+    archive::Archive,
+    audit::AuditLog,
+    builder::MessageBuilder,
+    channel::{ChannelKind, OpenChannel},
+    config::KakaoClientCfg,
+    credential::CredentialStore,
+    cursor::CursorStore,
+    feed::{self, Feed},
+    flood::{AbuseKind, FloodDetector},
+    history::{history, HistoryQuery},
+    join::{JoinError, JoinStage},
+    media::{MediaClient, MediaKind, MediaUpload, UploadedMedia},
+    moderation::ShadowMode,
+    roster::{Roster, RosterMember},
+    send_queue::{Next, SendError, SendPriority, SendQueue, SendReceipt},
+    users::UserRegistry,
 };
 
-pub struct KakaoClientCfg<'a> {
-    pub email: &'a str,
-    pub password: &'a str,
+#[derive(Debug)]
+pub enum KakaoEvent {
+    Talk(KiwiTalkClientEvent),
+    Disconnected {
+        reason: String,
+    },
+    // last_log_ids holds the last log id seen per channel before the connection dropped.
+    // Missed chats are backfilled automatically and delivered right after this event
+    Reconnected {
+        attempts: u32,
+        last_log_ids: HashMap<i64, i64>,
+    },
+    // Raised right after the chat that crossed flood limits, with every limit it crossed
+    AbuseDetected {
+        chat: ChatReceived,
+        abuses: Vec<AbuseKind>,
+    },
 }
 
 pub struct KakaoClient {
+    pub cfg: KakaoClientCfg,
     pub talk_client: KiwiTalkClient,
     pub talk_event_recv: Receiver<KiwiTalkClientEvent>,
     pub initial_channels: HashMap<i64, ChannelDataVariant>,
-    pub known_users: HashMap<i64, KakaoUser>,
+    channels: HashMap<i64, OpenChannel>,
+    pub users: UserRegistry,
+    // Members and open chat roles per channel, synced on startup and join and kept
+    // current by member and profile events
+    pub roster: Roster,
+    pub shadow: ShadowMode,
+    pub audit: Option<AuditLog>,
+    pub archive: Option<Arc<Archive>>,
+    pub media: MediaClient,
+    flood: Option<FloodDetector>,
+    send_queue: SendQueue,
+    pub last_log_ids: HashMap<i64, i64>,
+    credential: AppCredential,
+    credential_store: Option<CredentialStore>,
+    cursor_store: Option<CursorStore>,
+    pending_events: VecDeque<KakaoEvent>,
+    disconnected: bool,
+    api_recv: Option<mpsc::Receiver<ApiRequest>>,
+}
+
+struct Connection {
+    credential: AppCredential,
+    talk_client: KiwiTalkClient,
+    talk_event_recv: Receiver<KiwiTalkClientEvent>,
+    channels: HashMap<i64, ChannelDataVariant>,
 }
 
 impl KakaoClient {
-    pub async fn new(cfg: KakaoClientCfg<'_>) -> Result<Self> {
+    pub async fn new(cfg: &KakaoClientCfg) -> Result<Self> {
         info!("New Kakao client");
 
-        let config = AuthClientConfig {
-            device: AuthDeviceConfig {
-                name: "TEST_DEVICE",
-                model: None,
-                uuid: "6SMj9g0xFYodk+ItC4FKX1EgnZiLPibCWGXuqIgEwx56uwoFJg+GCX0YneaczW4yEt3QzbI+6Hhz9env9cV6wQ=="
-            },
-            language: "ko",
-            version: "3.4.7",
-            agent: TalkApiAgent::Win32("10.0"),
+        let store = CredentialStore::from_cfg(cfg);
+        let stored = match &store {
+            Some(store) => store.load().unwrap_or_else(|err| {
+                warn!("Cannot load stored credential: {:?}", err);
+                None
+            }),
+            None => None,
+        };
+
+        let connection = Self::establish(cfg, &store, stored.as_ref()).await?;
+
+        let cursor_store = cfg
+            .backfill
+            .enabled
+            .then(|| CursorStore::new(cfg.system.data_dir.join(&cfg.backfill.cursor_path)));
+        let last_log_ids = match &cursor_store {
+            Some(cursor_store) => cursor_store.load().unwrap_or_else(|err| {
+                warn!("Cannot load chat cursors: {:?}", err);
+                HashMap::new()
+            }),
+            None => HashMap::new(),
+        };
+
+        let users = UserRegistry::open(&cfg.system.data_dir.join(&cfg.users.path))?;
+        let audit = match cfg.audit.enabled {
+            true => Some(AuditLog::open(&cfg.system.data_dir.join(&cfg.audit.path))?),
+            false => None,
+        };
+        let archive = match cfg.archive.enabled {
+            true => Some(Arc::new(Archive::open(
+                &cfg.system.data_dir.join(&cfg.archive.path),
+            )?)),
+            false => None,
         };
-        let hasher = Win32XVCHasher("JAYDEN", "JAYMOND");
+        let media = MediaClient::new(&cfg.media, &cfg.system.data_dir)?;
+
+        let mut client = Self {
+            cfg: cfg.clone(),
+            talk_client: connection.talk_client,
+            talk_event_recv: connection.talk_event_recv,
+            channels: open_channels(&connection.channels),
+            initial_channels: connection.channels,
+            users,
+            roster: Roster::default(),
+            shadow: ShadowMode::new(&cfg.moderation),
+            audit,
+            archive,
+            media,
+            flood: cfg.flood.enabled.then(|| FloodDetector::new(&cfg.flood)),
+            send_queue: SendQueue::new(&cfg.send),
+            last_log_ids,
+            credential: connection.credential,
+            credential_store: store,
+            cursor_store,
+            pending_events: VecDeque::new(),
+            disconnected: false,
+            api_recv: None,
+        };
+        client.backfill_all().await;
+        client.sync_rosters().await;
+
+        Ok(client)
+    }
+
+    // Stored access token first, then a refreshed one, then a full account login
+    async fn establish(
+        cfg: &KakaoClientCfg,
+        store: &Option<CredentialStore>,
+        stored: Option<&AppCredential>,
+    ) -> Result<Connection> {
+        let auth_client = TalkAuthClient::new(cfg.auth_client_config(), cfg.xvc_hasher());
+        let system_info = cfg.system_info();
+
+        if let Some(credential) = stored {
+            info!("Connecting with stored access token...");
+            match Self::connect(credential, &system_info).await {
+                Ok((talk_client, talk_event_recv, channels)) => {
+                    return Ok(Connection {
+                        credential: AppCredential {
+                            access_token: credential.access_token.clone(),
+                            refresh_token: credential.refresh_token.clone(),
+                            user_id: credential.user_id,
+                        },
+                        talk_client,
+                        talk_event_recv,
+                        channels,
+                    })
+                }
+                Err(err) => warn!("Stored access token rejected: {:?}", err),
+            }
+
+            info!("Refreshing access token...");
+            let refreshed = auth_client
+                .renew_token(&credential.refresh_token, &credential.access_token)
+                .await
+                .context("renew token")
+                .and_then(|res| res.data.context("renew token response data"));
+            match refreshed {
+                Ok(data) => {
+                    let credential = AppCredential {
+                        access_token: data.credential.access_token,
+                        refresh_token: data.credential.refresh_token,
+                        user_id: credential.user_id,
+                    };
+                    Self::store_credential(store, &credential);
+
+                    match Self::connect(&credential, &system_info).await {
+                        Ok((talk_client, talk_event_recv, channels)) => {
+                            return Ok(Connection {
+                                credential,
+                                talk_client,
+                                talk_event_recv,
+                                channels,
+                            })
+                        }
+                        Err(err) => warn!("Refreshed access token rejected: {:?}", err),
+                    }
+                }
+                Err(err) => warn!("Cannot refresh access token: {:?}", err),
+            }
+
+            // Neither token works anymore, don't try them again if the login fails too
+            if let Some(store) = store {
+                if let Err(err) = store.clear() {
+                    warn!("Cannot remove stored credential: {:?}", err);
+                }
+            }
+        }
 
         info!("Logging in...");
-        let auth_client = TalkAuthClient::new(config, hasher);
         let login_form = LoginMethod::Account(AccountLoginForm {
-            email: cfg.email,
-            password: cfg.password,
+            email: &cfg.account.email,
+            password: &cfg.account.password,
         });
-        let login_response = auth_client.login(login_form, true).await.context("login")?;
+        let login_response = auth_client
+            .login(login_form, cfg.account.force_login)
+            .await
+            .context("login")?;
         let login_data = login_response.data.context("login response data")?;
         info!("Logged in");
 
-        // NOTE: This part below is from the Kiwi App
-        info!("Starting Kiwi app client...");
         let credential = AppCredential {
             access_token: login_data.credential.access_token,
             refresh_token: login_data.credential.refresh_token,
             user_id: Some(login_data.user_id as i64),
         };
+        Self::store_credential(store, &credential);
+
+        let (talk_client, talk_event_recv, channels) =
+            Self::connect(&credential, &system_info).await?;
+        Ok(Connection {
+            credential,
+            talk_client,
+            talk_event_recv,
+            channels,
+        })
+    }
+
+    async fn connect(
+        credential: &AppCredential,
+        system_info: &SystemInfo,
+    ) -> Result<(
+        KiwiTalkClient,
+        Receiver<KiwiTalkClientEvent>,
+        HashMap<i64, ChannelDataVariant>,
+    )> {
+        // NOTE: This part below is from the Kiwi App
+        info!("Starting Kiwi app client...");
         let client_status = ClientStatus::Unlocked;
-        let system_info = SystemInfo {
-            device_data_dir: "device_data_dir".into(),
-            data_dir: "data_dir".into(),
-            device_info: DeviceInfo {
-                locale: "KR".into(),
-                name: config.device.name.into(),
-                device_uuid: DeviceUuid(config.device.uuid.into()),
-            },
-        };
 
         let (sender, recv) = channel(256);
         let (client, channels): (KiwiTalkClient, HashMap<i64, ChannelDataVariant>) =
-            create_client_2(&credential, client_status, &system_info, sender)
+            create_client_2(credential, client_status, system_info, sender)
                 .await
                 .context("create client")?;
         info!("Started Kiwi app client");
 
-        Ok(Self {
-            talk_client: client,
-            talk_event_recv: recv,
-            initial_channels: channels,
-            known_users: HashMap::new(),
-        })
+        Ok((client, recv, channels))
     }
 
-    pub async fn next_event(&mut self) -> Result<KiwiTalkClientEvent> {
-        let msg = self.talk_event_recv.next().await.context("kiwi event")?;
-        info!("Received message: {:?}", msg);
+    fn store_credential(store: &Option<CredentialStore>, credential: &AppCredential) {
+        if let Some(store) = store {
+            if let Err(err) = store.save(credential) {
+                warn!("Cannot store credential: {:?}", err);
+            }
+        }
+    }
 
-        match &msg {
-            KiwiTalkClientEvent::Chat(ChatEvent::Chat(e)) => {
-                if let Some(nickname) = e.user_nickname.clone() {
-                    match self.known_users.entry(e.chat.sender_id) {
-                        Entry::Occupied(mut entry) => {
-                            entry.get_mut().nickname = nickname;
-                        }
-                        Entry::Vacant(entry) => {
-                            entry.insert(KakaoUser {
-                                user_id: e.chat.sender_id,
-                                nickname,
-                                image_url: None,
-                            });
-                        }
+    async fn reconnect(&mut self) -> Result<()> {
+        let reconnect = &self.cfg.reconnect;
+        let mut delay = reconnect.initial_delay_ms as f64;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+
+            let jitter = 1.0 + rand::thread_rng().gen_range(-reconnect.jitter..=reconnect.jitter);
+            let wait = Duration::from_millis((delay * jitter) as u64);
+            info!("Reconnecting in {:?} (attempt {})", wait, attempts);
+            sleep(wait).await;
+
+            match Self::establish(&self.cfg, &self.credential_store, Some(&self.credential)).await {
+                Ok(connection) => {
+                    self.talk_client = connection.talk_client;
+                    self.talk_event_recv = connection.talk_event_recv;
+                    self.refresh_channels(connection.channels);
+                    self.credential = connection.credential;
+                    self.disconnected = false;
+                    info!("Reconnected after {} attempts", attempts);
+
+                    self.pending_events.push_back(KakaoEvent::Reconnected {
+                        attempts,
+                        last_log_ids: self.last_log_ids.clone(),
+                    });
+                    self.backfill_all().await;
+                    self.sync_rosters().await;
+                    return Ok(());
+                }
+                Err(err) => warn!("Reconnect attempt {} failed: {:?}", attempts, err),
+            }
+
+            if reconnect.max_attempts != 0 && attempts >= reconnect.max_attempts {
+                bail!("gave up reconnecting after {} attempts", attempts);
+            }
+            delay = (delay * reconnect.multiplier).min(reconnect.max_delay_ms as f64);
+        }
+    }
+
+    // Keeps names learned from joins for channels the account is still in
+    fn refresh_channels(&mut self, initial_channels: HashMap<i64, ChannelDataVariant>) {
+        let mut channels = open_channels(&initial_channels);
+        for channel in channels.values_mut() {
+            if let Some(known) = self.channels.get(&channel.channel_id) {
+                channel.name = known.name.clone();
+            }
+        }
+        self.channels = channels;
+        self.initial_channels = initial_channels;
+    }
+
+    fn mark_disconnected(&mut self, reason: String) {
+        warn!("Disconnected: {}", reason);
+        self.disconnected = true;
+        self.pending_events
+            .push_back(KakaoEvent::Disconnected { reason });
+    }
+
+    pub async fn next_event(&mut self) -> Result<KakaoEvent> {
+        loop {
+            if let Some(event) = self.pending_events.pop_front() {
+                return Ok(event);
+            }
+
+            if self.disconnected {
+                self.reconnect().await?;
+                continue;
+            }
+
+            // Queued chats go out and api requests run while waiting for the next event
+            let wait = self.flush_sends().await;
+            let woken = tokio::select! {
+                msg = self.talk_event_recv.next() => Either::Left(msg),
+                Some(request) = next_api_request(&mut self.api_recv) => Either::Right(request),
+                _ = sleep_for(wait) => continue,
+            };
+            let msg = match woken {
+                Either::Left(msg) => msg,
+                Either::Right(request) => {
+                    request.run(self).await;
+                    continue;
+                }
+            };
+            let Some(msg) = msg else {
+                self.mark_disconnected("event stream closed".to_owned());
+                continue;
+            };
+            info!("Received message: {:?}", msg);
+
+            if let KiwiTalkClientEvent::Chat(ChatEvent::Chat(e)) = &msg {
+                let last = self.last_log_ids.get(&e.channel_id).copied();
+                match (last, e.chat.prev_log_id) {
+                    (Some(last), _) if e.log_id <= last => {
+                        info!("Skip already delivered chat {}", e.log_id);
+                        continue;
+                    }
+                    (Some(last), Some(prev_log_id))
+                        if self.cursor_store.is_some() && prev_log_id > last =>
+                    {
+                        info!(
+                            "Gap detected in channel_id={} between {} and {}",
+                            e.channel_id, last, prev_log_id
+                        );
+                        let query = HistoryQuery::new(e.channel_id, last)
+                            .until_log_id(e.log_id - 1)
+                            .max_pages(self.cfg.backfill.max_pages);
+                        self.backfill(query).await;
                     }
+                    _ => (),
                 }
             }
+
+            self.push_talk_event(msg);
+        }
+    }
+
+    // Runs requests from the admin api between events, see api::serve
+    pub fn attach_api(&mut self, api_recv: mpsc::Receiver<ApiRequest>) {
+        self.api_recv = Some(api_recv);
+    }
+
+    // Chats the bot sends move the cursor too, or the next chat would look like a gap.
+    // Only moves forward
+    fn advance_cursor(&mut self, channel_id: i64, log_id: i64) {
+        let last = self.last_log_ids.entry(channel_id).or_insert(log_id);
+        *last = (*last).max(log_id);
+        if let Some(cursor_store) = &mut self.cursor_store {
+            if let Err(err) = cursor_store.save_throttled(&self.last_log_ids) {
+                warn!("Cannot save chat cursors: {:?}", err);
+            }
+        }
+    }
+
+    fn push_talk_event(&mut self, msg: KiwiTalkClientEvent) {
+        let mut abuse = None;
+        let disconnect_reason = match &msg {
+            KiwiTalkClientEvent::Chat(ChatEvent::Chat(e)) => {
+                self.advance_cursor(e.channel_id, e.log_id);
+                self.observe_members(e);
+                abuse = self.detect_abuse(e);
+                None
+            }
             KiwiTalkClientEvent::ProfileChanged(e) => {
-                self.known_users
-                    .insert(e.open_link_user.user_id, e.open_link_user.clone().into());
+                self.roster
+                    .upsert(e.channel_id, e.open_link_user.clone().into());
+                let user = e.open_link_user.clone().into();
+                if let Err(err) = self
+                    .users
+                    .record_user(e.channel_id, &user)
+                    .and_then(|_| self.users.seen_in_channel(e.channel_id, user.user_id))
+                {
+                    warn!("Cannot record user {}: {:?}", user.user_id, err);
+                }
+                None
+            }
+            KiwiTalkClientEvent::Unhandled(e) => {
+                warn!("Unhandled event: {:?}", e);
+                None
             }
-            KiwiTalkClientEvent::Unhandled(e) => warn!("Unhandled event: {:?}", e),
-            KiwiTalkClientEvent::Error(err) => error!("Error event: {:?}", err),
+            KiwiTalkClientEvent::Error(err) => {
+                error!("Error event: {:?}", err);
+                Some(format!("client error: {:?}", err))
+            }
+            KiwiTalkClientEvent::Kickout(e) => Some(format!("kicked out: {:?}", e)),
+            _ => None,
+        };
+
+        self.pending_events.push_back(KakaoEvent::Talk(msg));
+        self.pending_events.extend(abuse);
+        if let Some(reason) = disconnect_reason {
+            self.mark_disconnected(reason);
+        }
+    }
+
+    // Hosts, managers and bots are not checked
+    fn detect_abuse(&mut self, e: &ChatReceived) -> Option<KakaoEvent> {
+        match self.roster.role(e.channel_id, e.chat.sender_id) {
+            OpenMemberType::Host | OpenMemberType::Manager | OpenMemberType::Bot => return None,
             _ => (),
         }
 
-        Ok(msg)
+        let abuses = self.flood.as_mut()?.observe(e);
+        if abuses.is_empty() {
+            return None;
+        }
+        Some(KakaoEvent::AbuseDetected {
+            chat: e.clone(),
+            abuses,
+        })
+    }
+
+    fn observe_members(&mut self, e: &ChatReceived) {
+        let sender_id = e.chat.sender_id;
+        let mut result = self.users.seen_in_channel(e.channel_id, sender_id);
+        if let Some(nickname) = &e.user_nickname {
+            result = result.and_then(|_| {
+                self.users
+                    .record_nickname(e.channel_id, sender_id, nickname)
+            });
+        }
+
+        if let Some(feed) = Feed::parse(&e.chat.chat) {
+            self.roster.apply_feed(e.channel_id, &feed, e.chat.send_at);
+            if self.is_removal_of_self(&feed) {
+                info!("No longer in channel_id={}", e.channel_id);
+                self.channels.remove(&e.channel_id);
+            }
+            for member in feed.all_members() {
+                result = result.and_then(|_| match feed.feed_type {
+                    feed::FEED_INVITE | feed::FEED_OPENLINK_JOIN => {
+                        self.users.seen_in_channel(e.channel_id, member.user_id)
+                    }
+                    feed::FEED_LEAVE
+                    | feed::FEED_SECRET_LEAVE
+                    | feed::FEED_OPENLINK_KICKED
+                    | feed::FEED_CHANNEL_KICKED => {
+                        self.users.left_channel(e.channel_id, member.user_id)
+                    }
+                    _ => Ok(()),
+                });
+                if let Some(nickname) = &member.nickname {
+                    result = result.and_then(|_| {
+                        self.users
+                            .record_nickname(e.channel_id, member.user_id, nickname)
+                    });
+                }
+            }
+        }
+
+        if let Err(err) = result {
+            warn!("Cannot record users of chat {}: {:?}", e.log_id, err);
+        }
+    }
+
+    fn is_removal_of_self(&self, feed: &Feed) -> bool {
+        match feed.feed_type {
+            feed::FEED_CHANNEL_DELETED | feed::FEED_OPENLINK_DELETE_LINK => true,
+            feed::FEED_LEAVE
+            | feed::FEED_SECRET_LEAVE
+            | feed::FEED_OPENLINK_KICKED
+            | feed::FEED_CHANNEL_KICKED => feed
+                .all_members()
+                .iter()
+                .any(|member| Some(member.user_id) == self.user_id()),
+            _ => false,
+        }
+    }
+
+    // Reloads the member list of every channel, used on startup and after reconnecting
+    async fn sync_rosters(&mut self) {
+        let channel_ids: Vec<i64> = self.channels.keys().copied().collect();
+        for channel_id in channel_ids {
+            if let Err(err) = self.sync_roster(channel_id).await {
+                warn!(
+                    "Cannot sync members of channel_id={}: {:?}",
+                    channel_id, err
+                );
+            }
+        }
+    }
+
+    pub async fn sync_roster(&mut self, channel_id: i64) -> Result<(), ClientRequestError> {
+        info!("Get members for channel_id={}", channel_id);
+        let client = TalkClient(&self.talk_client.connection().session);
+        let res = client
+            .get_members(&GetMemReq {
+                chat_id: channel_id,
+            })
+            .await?;
+        info!("Got {} members", res.members.len());
+
+        let members: Vec<RosterMember> = res.members.into_iter().map(RosterMember::from).collect();
+        for member in members.iter() {
+            if let Err(err) = self
+                .users
+                .record_user(channel_id, &member.into())
+                .and_then(|_| self.users.seen_in_channel(channel_id, member.user_id))
+            {
+                warn!("Cannot record user {}: {:?}", member.user_id, err);
+            }
+        }
+        self.roster.replace(channel_id, members);
+
+        Ok(())
+    }
+
+    // Catches up every channel with a cursor, used on startup and after reconnecting
+    async fn backfill_all(&mut self) {
+        if self.cursor_store.is_none() {
+            return;
+        }
+
+        // Every channel is paged in the same requests
+        let mut cursors = self.last_log_ids.iter();
+        let Some((channel_id, since)) = cursors.next() else {
+            return;
+        };
+        let query = cursors
+            .fold(
+                HistoryQuery::new(*channel_id, *since),
+                |query, (channel_id, since)| query.channel(*channel_id, *since),
+            )
+            .max_pages(self.cfg.backfill.max_pages);
+        self.backfill(query).await;
+    }
+
+    // Queues the chats of the query in order, as if they were received. Chats fetched before a
+    // failed page are still queued
+    async fn backfill(&mut self, query: HistoryQuery) {
+        let mut chat_logs = Vec::new();
+        {
+            let channels = query.channels.clone();
+            let mut pages = pin!(history(self, query));
+            while let Some(chatlog) = pages.next().await {
+                match chatlog {
+                    Ok(chatlog) => chat_logs.push(chatlog),
+                    Err(err) => {
+                        warn!("Cannot backfill channels {:?}: {:?}", channels, err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for chatlog in chat_logs {
+            // Own chats were already handled when they were sent
+            if Some(chatlog.sender_id) == self.user_id() {
+                self.advance_cursor(chatlog.channel_id, chatlog.log_id);
+                continue;
+            }
+            info!(
+                "Backfilled chat {} in channel_id={}",
+                chatlog.log_id, chatlog.channel_id
+            );
+            self.push_talk_event(KiwiTalkClientEvent::Chat(ChatEvent::Chat(ChatReceived {
+                channel_id: chatlog.channel_id,
+                link_id: self.get_channel_link_id(chatlog.channel_id),
+                log_id: chatlog.log_id,
+                user_nickname: None,
+                chat: chatlog,
+            })));
+        }
     }
 
     pub async fn join_channel(
@@ -145,7 +659,7 @@ impl KakaoClient {
         nickname: &str,
         profile_path: Option<&str>,
         passcode: Option<&str>,
-    ) -> Result<JoinChannelRes, KiwiTalkClientError> {
+    ) -> Result<OpenChannel, JoinError> {
         info!(
             "Join channel via link '{}' with passcode: {:?}",
             link_url,
@@ -153,6 +667,7 @@ impl KakaoClient {
         );
 
         let client = TalkClient(&self.talk_client.connection().session);
+        let has_passcode = passcode.is_some();
 
         info!("Get join info");
         let join_info = client
@@ -160,7 +675,8 @@ impl KakaoClient {
                 link_url: link_url.into(),
                 referer: "EW".to_owned(),
             })
-            .await?;
+            .await
+            .map_err(|err| JoinError::from_request(JoinStage::JoinInfo, err, has_passcode))?;
         info!("Join info: {:?}", join_info);
 
         let token = match passcode {
@@ -171,7 +687,8 @@ impl KakaoClient {
                         link_id: join_info.open_link.link_id,
                         passcode: passcode.to_owned(),
                     })
-                    .await?;
+                    .await
+                    .map_err(|err| JoinError::from_request(JoinStage::CheckJoin, err, true))?;
                 info!("Check join: {:?}", check_join);
                 Some(check_join.token)
             }
@@ -186,43 +703,236 @@ impl KakaoClient {
                 profile: JoinChannelReqProfile::KakaoAnon {
                     ptp: 2,
                     nickname: nickname.to_owned(),
-                    profile_path: profile_path.map(|x| x.to_owned()), // TODO: Does this do stuff?
+                    // Server path of an uploaded image, see upload_media
+                    profile_path: profile_path.map(|x| x.to_owned()),
                 },
                 token,
             })
-            .await?;
+            .await
+            .map_err(|err| JoinError::from_request(JoinStage::Join, err, has_passcode))?;
         info!("Joined successfully");
 
-        for user in join_channel_response.chat_room.members.iter() {
-            self.known_users.insert(user.user_id, user.clone().into());
+        // The join response lists members without their open chat roles
+        let channel_id = join_channel_response.chat_room.chat_id;
+        if let Err(err) = self.sync_roster(channel_id).await {
+            warn!(
+                "Cannot sync members of channel_id={}: {:?}",
+                channel_id, err
+            );
         }
 
-        Ok(join_channel_response)
+        let channel = OpenChannel::new(
+            channel_id,
+            ChannelKind::Open {
+                link_id: join_info.open_link.link_id,
+            },
+            Some(join_info.open_link.link_name),
+        );
+        self.channels.insert(channel_id, channel.clone());
+        Ok(channel)
+    }
+
+    pub async fn leave_channel(
+        &mut self,
+        channel_id: i64,
+        block: bool,
+    ) -> Result<(), ClientRequestError> {
+        info!("Leave channel_id={} block={}", channel_id, block);
+        let client = TalkClient(&self.talk_client.connection().session);
+        client
+            .leave(&LeaveReq {
+                chat_id: channel_id,
+                block,
+            })
+            .await?;
+        info!("Left channel successfully");
+
+        self.channels.remove(&channel_id);
+        self.roster.remove_channel(channel_id);
+        self.last_log_ids.remove(&channel_id);
+        Ok(())
+    }
+
+    // Changes the nickname and profile image the account uses in one open chat
+    pub async fn update_link_profile(
+        &mut self,
+        link_id: i64,
+        nickname: &str,
+        profile_path: Option<&str>,
+    ) -> Result<OpenLinkUser, ClientRequestError> {
+        info!("Update profile in link_id={} to '{}'", link_id, nickname);
+        let client = TalkClient(&self.talk_client.connection().session);
+        let res = client
+            .update_link_profile(&UpdateLinkProfileReq {
+                link_id,
+                profile: JoinChannelReqProfile::KakaoAnon {
+                    ptp: 2,
+                    nickname: nickname.to_owned(),
+                    profile_path: profile_path.map(|x| x.to_owned()),
+                },
+            })
+            .await?;
+        info!("Updated profile successfully");
+
+        let channel_ids: Vec<i64> = self
+            .channels
+            .values()
+            .filter(|channel| channel.link_id() == Some(link_id))
+            .map(|channel| channel.channel_id)
+            .collect();
+        for channel_id in channel_ids {
+            self.roster
+                .upsert(channel_id, res.updated_profile.clone().into());
+        }
+        Ok(res.updated_profile)
+    }
+
+    // Open links of the channels the account is in, also fills in the channel names
+    pub async fn joined_links(
+        &mut self,
+    ) -> Result<Vec<(OpenChannel, OpenLink)>, ClientRequestError> {
+        let link_ids: Vec<i64> = self
+            .channels
+            .values()
+            .filter_map(OpenChannel::link_id)
+            .collect();
+        if link_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        info!("Get info of {} links", link_ids.len());
+        let client = TalkClient(&self.talk_client.connection().session);
+        let res = client.info_link(&InfoLinkReq { link_ids }).await?;
+
+        let mut joined = Vec::new();
+        for link in res.links {
+            for channel in self.channels.values_mut() {
+                if channel.link_id() == Some(link.link_id) {
+                    channel.name = Some(link.link_name.clone());
+                    joined.push((channel.clone(), link.clone()));
+                }
+            }
+        }
+        Ok(joined)
+    }
+
+    pub fn user_id(&self) -> Option<i64> {
+        self.credential.user_id
+    }
+
+    // Channels the account is in right now, including ones joined since connecting
+    pub fn channels(&self) -> impl Iterator<Item = &OpenChannel> {
+        self.channels.values()
+    }
+
+    pub fn channel(&self, channel_id: i64) -> Option<OpenChannel> {
+        self.channels.get(&channel_id).cloned()
     }
 
-    pub fn get_initial_channels(&self) -> &HashMap<i64, ChannelDataVariant> {
-        &self.initial_channels
+    pub fn get_channel_link_id(&self, channel_id: i64) -> Option<i64> {
+        self.channels.get(&channel_id)?.link_id()
     }
 
-    pub fn get_known_user_info(&self, user_id: i64) -> Option<&KakaoUser> {
-        self.known_users.get(&user_id)
+    pub fn get_open_member_type(&self, channel_id: i64, user_id: i64) -> OpenMemberType {
+        self.roster.role(channel_id, user_id)
     }
 
-    pub async fn get_chat_logs(
+    // One GETCHATLOGS request for several (chat_id, since) pairs, also returns the eof flag
+    pub async fn get_chat_logs_batch(
         &self,
-        chat_id: i64,
-        since: i64,
-    ) -> Result<Vec<Chatlog2>, ClientRequestError> {
-        info!("Get chat logs for chat_id={} since={}", chat_id, since);
+        sinces: &[(i64, i64)],
+    ) -> Result<(Vec<Chatlog2>, bool), ClientRequestError> {
+        info!("Get chat logs for {:?}", sinces);
         let client = TalkClient(&self.talk_client.connection().session);
         let res = client
             .get_chat_logs(&GetChatLogsReq {
-                chat_ids: vec![chat_id],
-                sinces: vec![since],
+                chat_ids: sinces.iter().map(|(chat_id, _)| *chat_id).collect(),
+                sinces: sinces.iter().map(|(_, since)| *since).collect(),
             })
             .await?;
         info!("Got chat logs successfully");
-        Ok(res.chat_logs)
+        Ok((res.chat_logs, res.eof))
+    }
+
+    // Queues the chat behind the send rate limits, the receipt resolves once next_event
+    // (or another queued send) gets to it
+    pub fn queue_message(
+        &mut self,
+        channel_id: i64,
+        chat: Chat,
+        priority: SendPriority,
+    ) -> SendReceipt {
+        self.send_queue.push(channel_id, chat, false, priority)
+    }
+
+    // Queues the chat and drives the queue until it is sent, safe to await from handlers
+    pub async fn send_message_queued(
+        &mut self,
+        channel_id: i64,
+        chat: Chat,
+        priority: SendPriority,
+    ) -> Result<Chatlog, SendError> {
+        let mut receipt = self.queue_message(channel_id, chat, priority);
+        loop {
+            let wait = self.flush_sends().await;
+            if let Some(result) = receipt.try_result() {
+                return result;
+            }
+            match wait {
+                Some(wait) => sleep(wait).await,
+                None => return Err(SendError::Dropped),
+            }
+        }
+    }
+
+    pub async fn upload_media(&self, upload: &MediaUpload) -> Result<UploadedMedia> {
+        let user_id = self
+            .credential
+            .user_id
+            .context("user id of the logged in account is unknown")?;
+        self.media.upload(user_id, upload).await
+    }
+
+    // Uploads the photo or file and sends it as the matching chat type
+    pub async fn send_media(&mut self, channel_id: i64, upload: &MediaUpload) -> Result<Chatlog> {
+        let uploaded = self.upload_media(upload).await?;
+        let chat = match uploaded.kind {
+            MediaKind::Photo => MessageBuilder::photo(&uploaded),
+            MediaKind::File => MessageBuilder::file(&uploaded),
+        }
+        .build();
+
+        Ok(self
+            .send_message_queued(channel_id, chat, SendPriority::Normal)
+            .await?)
+    }
+
+    // Sends every queued chat the rate limits allow, returns how long until the next one can go
+    async fn flush_sends(&mut self) -> Option<Duration> {
+        loop {
+            match self.send_queue.next(Instant::now()) {
+                Next::Send(queued) => {
+                    let res = self
+                        .send_message(queued.channel_id, queued.chat.clone(), queued.no_seen)
+                        .await;
+                    match res {
+                        Ok(chatlog) => {
+                            self.advance_cursor(queued.channel_id, chatlog.log_id);
+                            self.send_queue.complete(queued, Ok(chatlog));
+                        }
+                        Err(err) => {
+                            warn!(
+                                "Queued send to channel_id={} failed: {:?}",
+                                queued.channel_id, err
+                            );
+                            self.send_queue.retry(queued, err, Instant::now());
+                        }
+                    }
+                }
+                Next::Wait(wait) => return Some(wait),
+                Next::Empty => return None,
+            }
+        }
     }
 
     pub async fn send_message(
@@ -232,35 +942,102 @@ impl KakaoClient {
         no_seen: bool,
     ) -> Result<Chatlog, KiwiTalkClientError> {
         info!("Send chat to channel_id={} chat={:?}", channel_id, chat);
-        let res = ClientChannel::new(channel_id, &self.talk_client.connection())
+        let res = ClientChannel::new(channel_id, self.talk_client.connection())
             .send_chat(chat, no_seen)
             .await?;
         info!("Sent chat successfully");
         Ok(res)
     }
 
-    pub async fn delete_message(&self, req: DeleteMsgReq) -> Result<(), ClientRequestError> {
+    // Moderation requests, use moderation::apply (or OpenChannel) so shadow mode and the audit
+    // log apply. The server response is returned for the audit log
+    pub async fn delete_message(
+        &self,
+        req: DeleteMsgReq,
+    ) -> Result<impl Debug, ClientRequestError> {
         info!("Delete message {:?}", req);
         let client = TalkClient(&self.talk_client.connection().session);
-        let _res = client.delete_chat(&req).await?;
-        info!("Deleted message successfully");
-        Ok(())
+        let res = client.delete_chat(&req).await?;
+        info!("Deleted message successfully: {:?}", res);
+        Ok(res)
     }
 
-    pub async fn hide_message(&self, req: HideMsgReq) -> Result<(), ClientRequestError> {
+    pub async fn hide_message(&self, req: HideMsgReq) -> Result<impl Debug, ClientRequestError> {
         info!("Hide message {:?}", req);
         let client = TalkClient(&self.talk_client.connection().session);
-        let _res = client.hide_chat(&req).await?;
-        info!("Hid message successfully");
-        Ok(())
+        let res = client.hide_chat(&req).await?;
+        info!("Hid message successfully: {:?}", res);
+        Ok(res)
     }
 
-    pub async fn kick_user(&self, req: KickUserReq) -> Result<(), ClientRequestError> {
+    pub async fn kick_user(&self, req: KickUserReq) -> Result<impl Debug, ClientRequestError> {
         info!("Kick user {:?}", req);
         let client = TalkClient(&self.talk_client.connection().session);
-        let _res = client.kick_user(&req).await?;
-        info!("Kicked user successfully");
-        Ok(())
+        let res = client.kick_user(&req).await?;
+        info!("Kicked user successfully: {:?}", res);
+        Ok(res)
+    }
+}
+
+async fn next_api_request(api_recv: &mut Option<mpsc::Receiver<ApiRequest>>) -> Option<ApiRequest> {
+    match api_recv {
+        Some(api_recv) => api_recv.recv().await,
+        None => pending().await,
+    }
+}
+
+async fn sleep_for(wait: Option<Duration>) {
+    match wait {
+        Some(wait) => sleep(wait).await,
+        None => pending().await,
+    }
+}
+
+fn open_channels(initial_channels: &HashMap<i64, ChannelDataVariant>) -> HashMap<i64, OpenChannel> {
+    initial_channels
+        .iter()
+        .map(|(channel_id, data)| (*channel_id, OpenChannel::from_data(*channel_id, data)))
+        .collect()
+}
+
+pub fn chatlog_from_loco(chatlog: Chatlog2) -> Chatlog {
+    Chatlog {
+        log_id: chatlog.log_id,
+        prev_log_id: chatlog.prev_log_id,
+        channel_id: chatlog.chat_id,
+        sender_id: chatlog.author_id,
+        send_at: chatlog.send_at,
+        chat: Chat {
+            chat_type: ChatType(chatlog.chat_type),
+            content: ChatContent {
+                message: chatlog.message,
+                attachment: chatlog.attachment,
+                supplement: chatlog.supplement,
+            },
+            message_id: chatlog.msg_id,
+        },
+        referer: chatlog.referer,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMemberType {
+    Host,
+    Member,
+    Manager,
+    Bot,
+    Unknown,
+}
+
+impl From<i32> for OpenMemberType {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => Self::Host,
+            2 => Self::Member,
+            4 => Self::Manager,
+            8 => Self::Bot,
+            _ => Self::Unknown,
+        }
     }
 }
 
@@ -289,13 +1066,3 @@ impl From<DisplayUserInfo> for KakaoUser {
         }
     }
 }
-
-impl From<ChatRoomMember> for KakaoUser {
-    fn from(value: ChatRoomMember) -> Self {
-        Self {
-            user_id: value.user_id,
-            nickname: value.nickname,
-            image_url: value.profile_image_url,
-        }
-    }
-}
@@ -1,11 +1,54 @@
+use std::{sync::Arc, time::Duration};
+
+use admin::ChannelAdmin;
 use anyhow::Result;
-use kakao::{KakaoClient, KakaoClientCfg};
-use kiwi_talk_client::chat::{Chat, ChatContent, ChatType};
-use log::LevelFilter;
+use audit::AuditQuery;
+use commands::{ArgKind, Command, CommandContext, CommandRegistry, Permission};
+use config::KakaoClientCfg;
+use dispatcher::{BotEvent, Dispatcher, EventKind, Flow, HandlerEntry};
+use flood::FloodGuard;
+use futures::future::LocalBoxFuture;
+use kakao::KakaoClient;
+use log::{debug, LevelFilter};
+use media::MediaArchiver;
+use membership::Reconciler;
+use moderation::{ModerationAction, Target, Trigger};
+use rules::RulesEngine;
 use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
-use talk_loco_command::request::chat::{DeleteMsgReq, HideMsgReq};
+use spam::SpamPipeline;
+use webhook::WebhookSink;
 
+mod admin;
+mod api;
+mod archive;
+mod attachment;
+mod audit;
+mod builder;
+mod channel;
+mod commands;
+mod config;
+mod credential;
+mod cursor;
+mod db;
+mod dispatcher;
+mod feed;
+mod flood;
+mod history;
+mod join;
 mod kakao;
+mod media;
+mod membership;
+mod moderation;
+mod roster;
+mod rules;
+mod send_queue;
+mod spam;
+mod users;
+mod webhook;
+
+// Entries shown by the audit command, the api exports the full log
+const AUDIT_COMMAND_LIMIT: usize = 10;
+const WHOIS_LIMIT: usize = 5;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -16,110 +59,309 @@ async fn main() -> Result<()> {
         ColorChoice::Auto,
     )?;
 
-    let cfg = KakaoClientCfg {
-        email: "#",
-        password: "#",
+    let cfg = KakaoClientCfg::load()?;
+
+    let mut client = KakaoClient::new(&cfg).await?;
+    if cfg.api.enabled {
+        client.attach_api(api::serve(&cfg.api)?);
+    }
+
+    let mut commands = CommandRegistry::new(&cfg.commands);
+    commands.register(
+        Command::new("ping", ping)
+            .help("Check that the bot is alive")
+            .cooldown(Duration::from_secs(5)),
+    );
+    commands.register(
+        Command::new("shadow", shadow)
+            .optional_arg("mode", ArgKind::Word)
+            .help("Show or switch shadow moderation for this channel: on, off or default")
+            .permission(Permission::Operator),
+    );
+    commands.register(
+        Command::new("hide", hide)
+            .help("Hide the chat this replies to")
+            .permission(Permission::Manager),
+    );
+    commands.register(
+        Command::new("kick", kick)
+            .optional_arg("user_id", ArgKind::Integer)
+            .help("Kick the sender of the chat this replies to, or the given user")
+            .permission(Permission::Manager),
+    );
+    commands.register(
+        Command::new("whois", whois)
+            .alias("who")
+            .optional_arg("nickname", ArgKind::Rest)
+            .help("Look up users by current or past nickname, or the sender of the chat this replies to")
+            .permission(Permission::Operator),
+    );
+    commands.register(
+        Command::new("unban", unban)
+            .arg("user_id", ArgKind::Integer)
+            .help("Let a kicked user join again, the bot has to be host or manager")
+            .permission(Permission::Manager),
+    );
+    commands.register(
+        Command::new("audit", audit)
+            .optional_arg("user_id", ArgKind::Integer)
+            .help("Show the latest moderation actions in this channel, or against a user")
+            .permission(Permission::Manager),
+    );
+
+    let archive = client.archive.clone();
+
+    let mut dispatcher = Dispatcher::new();
+    if let Some(archive) = &archive {
+        dispatcher.on_any(archive.clone()).priority(100);
+    }
+    if let Some(archive) = archive.as_ref().filter(|_| cfg.media.download_incoming) {
+        let media = MediaArchiver::new(&cfg.media, &cfg.system.data_dir, archive.clone())?;
+        dispatcher.on(EventKind::Chat, media).priority(90);
+    }
+    // Before the moderation handlers, which may stop events from reaching later ones
+    if cfg.webhook.enabled {
+        let webhooks = WebhookSink::new(&cfg.webhook, &cfg.system.data_dir)?;
+        webhooks.redeliver_dead_letters()?;
+        dispatcher.on_any(webhooks).priority(80);
+    }
+    // Moderation handlers only see moderation.channels when it is set
+    let moderated = |entry: &mut HandlerEntry| {
+        if !cfg.moderation.channels.is_empty() {
+            entry.channels(cfg.moderation.channels.iter().copied());
+        }
     };
+    if cfg.flood.enabled {
+        moderated(dispatcher.on(EventKind::AbuseDetected, FloodGuard::new(&cfg.flood)));
+    }
+    if cfg.rules.enabled {
+        let rules = RulesEngine::load(
+            &cfg.rules.path,
+            Duration::from_secs(cfg.rules.strike_decay_secs),
+        )?;
+        moderated(dispatcher.on(EventKind::Chat, rules).priority(60));
+    }
+    if cfg.spam.enabled {
+        let spam = SpamPipeline::from_cfg(&cfg.spam, archive.as_deref())?;
+        moderated(dispatcher.on(EventKind::Chat, spam).priority(50));
+    }
+    if !cfg.membership.links.is_empty() {
+        let reconciler = Arc::new(Reconciler::new(&cfg.membership));
+        reconciler.reconcile(&mut client).await;
+        dispatcher.on_any(reconciler);
+    }
+    dispatcher.on_any(print_event);
+    dispatcher.on(EventKind::Chat, commands);
 
-    let mut client = KakaoClient::new(cfg).await?;
-
-    let chat_logs = client
-        .get_chat_logs(18384565413113921, 3032501404205867008)
-        .await?;
-
-    println!("{:?}", chat_logs);
-
-    // let channel = ChannelWrapper {
-    //     link_id: 283608594,
-    //     channel_id: 18384565413113921,
-    // };
-
-    let mut done = false;
-
-    loop {
-        let event = client.next_event().await?;
-
-        println!("Got an event: {:?}", event);
-
-        if !done {
-            done = true;
-
-            // channel
-            //     .hide_message(&client, 3032496737807724544, 1)
-            //     .await?;
-
-            // client
-            //     .delete_message(DeleteMsgReq {
-            //         chat_id: 0,
-            //         log_id: 0,
-            //     })
-            //     .await?;
-
-            // client
-            //     .send_message(
-            //         18384565413113921,
-            //         Chat {
-            //             chat_type: ChatType::TEXT,
-            //             content: ChatContent {
-            //                 message: Some("Test".to_owned()),
-            //                 attachment: None,
-            //                 supplement: None,
-            //             },
-            //             message_id: 0,
-            //         },
-            //     )
-            //     .await?;
-
-            // client
-            //     .hide_message(HideMsgReq {
-            //         // link_id and channel_id are specific to the channel; log_id is the mssage id
-            //         link_id: 283608594,
-            //         channel_id: 18384565413113921,
-            //         log_id: 3032496737807724544,
-            //         chat_type: 1,
-            //     })
-            //     .await?;
-
-            // client
-            //     .kick_user(KickUserReq {
-            //         channel_id: 18384565413113921,
-            //         user_id: 6766397537925605521,
-            //         link_id: 283608594,
-            //     })
-            //     .await?;
-
-            // client
-            //     .join_channel(
-            //         "https://open.kakao.com/o/gfvKeahf",
-            //         "codementor",
-            //         None,
-            //         Some("333111"),
-            //     )
-            //     .await?;
+    dispatcher.run(&mut client).await
+}
+
+fn print_event<'a>(
+    _client: &'a mut KakaoClient,
+    event: &'a BotEvent,
+) -> LocalBoxFuture<'a, Result<Flow>> {
+    Box::pin(async move {
+        match event {
+            BotEvent::Unhandled(event) => debug!("Got an event no handler decodes: {:?}", event),
+            event => debug!("Got an event: {:?}", event),
         }
+        Ok(Flow::Continue)
+    })
+}
+
+fn ping<'a>(
+    _client: &'a mut KakaoClient,
+    _ctx: &'a CommandContext,
+) -> LocalBoxFuture<'a, Result<Option<String>>> {
+    Box::pin(async move { Ok(Some("pong".to_owned())) })
+}
+
+fn shadow<'a>(
+    client: &'a mut KakaoClient,
+    ctx: &'a CommandContext,
+) -> LocalBoxFuture<'a, Result<Option<String>>> {
+    Box::pin(async move {
+        match ctx.text("mode") {
+            Some("on") => client.shadow.set_channel(ctx.channel_id, true),
+            Some("off") => client.shadow.set_channel(ctx.channel_id, false),
+            Some("default") => client.shadow.clear_channel(ctx.channel_id),
+            Some(mode) => return Ok(Some(format!("Unknown mode '{}'", mode))),
+            None => (),
+        }
+
+        let enabled = client.shadow.is_enabled(ctx.channel_id);
+        Ok(Some(format!(
+            "Shadow moderation is {} in this channel",
+            if enabled { "on" } else { "off" }
+        )))
+    })
+}
+
+fn hide<'a>(
+    client: &'a mut KakaoClient,
+    ctx: &'a CommandContext,
+) -> LocalBoxFuture<'a, Result<Option<String>>> {
+    Box::pin(async move {
+        let Some(reply) = &ctx.reply_to else {
+            return Ok(Some("Reply to the chat to hide".to_owned()));
+        };
+
+        let target = Target {
+            channel_id: ctx.channel_id,
+            link_id: ctx.link_id,
+            log_id: Some(reply.src_log_id),
+            chat_type: reply.src_type,
+            user_id: Some(reply.src_user_id),
+            message: reply.src_message.clone(),
+            ..Default::default()
+        };
+        moderate(client, ctx, &target, ModerationAction::Hide).await
+    })
+}
+
+fn kick<'a>(
+    client: &'a mut KakaoClient,
+    ctx: &'a CommandContext,
+) -> LocalBoxFuture<'a, Result<Option<String>>> {
+    Box::pin(async move {
+        let user_id = match (ctx.integer("user_id"), &ctx.reply_to) {
+            (Some(user_id), _) => user_id,
+            (None, Some(reply)) => reply.src_user_id,
+            (None, None) => {
+                return Ok(Some(
+                    "Reply to a chat of the user to kick, or give their user id".to_owned(),
+                ))
+            }
+        };
+
+        let target = Target::user(ctx.channel_id, ctx.link_id, user_id);
+        moderate(client, ctx, &target, ModerationAction::Kick).await
+    })
+}
+
+// Audited with the command and who sent it, shadow mode applies as for every other action
+async fn moderate(
+    client: &mut KakaoClient,
+    ctx: &CommandContext,
+    target: &Target,
+    action: ModerationAction,
+) -> Result<Option<String>> {
+    let trigger = Trigger::Command {
+        command: ctx.command.clone(),
+        user_id: ctx.sender_id,
+    };
+    match moderation::apply(client, target, action, &trigger).await {
+        Ok(()) if client.shadow.is_enabled(ctx.channel_id) => {
+            Ok(Some(format!("{:?} recorded in shadow mode", action)))
+        }
+        Ok(()) => Ok(None),
+        Err(err) => Ok(Some(err.to_string())),
     }
 }
 
-struct ChannelWrapper {
-    link_id: i64,
-    channel_id: i64,
+fn unban<'a>(
+    client: &'a mut KakaoClient,
+    ctx: &'a CommandContext,
+) -> LocalBoxFuture<'a, Result<Option<String>>> {
+    Box::pin(async move {
+        // Required, parsing fails without it
+        let user_id = ctx.integer("user_id").unwrap_or_default();
+        let res = async {
+            ChannelAdmin::new(client, ctx.channel_id)?
+                .unban_user(user_id)
+                .await
+        }
+        .await;
+        match res {
+            Ok(()) => Ok(Some(format!("User {} can join again", user_id))),
+            Err(err) => Ok(Some(err.to_string())),
+        }
+    })
 }
 
-impl ChannelWrapper {
-    pub async fn hide_message(
-        &self,
-        client: &KakaoClient,
-        log_id: i64,
-        chat_type: i32,
-    ) -> Result<()> {
-        client
-            .hide_message(HideMsgReq {
-                link_id: self.link_id,
-                channel_id: self.channel_id,
-                log_id,
-                chat_type,
+fn audit<'a>(
+    client: &'a mut KakaoClient,
+    ctx: &'a CommandContext,
+) -> LocalBoxFuture<'a, Result<Option<String>>> {
+    Box::pin(async move {
+        let Some(audit_log) = &client.audit else {
+            return Ok(Some("The audit log is disabled".to_owned()));
+        };
+
+        let entries = audit_log.query(&AuditQuery {
+            channel_id: Some(ctx.channel_id),
+            target_user_id: ctx.integer("user_id"),
+            limit: Some(AUDIT_COMMAND_LIMIT),
+            ..Default::default()
+        })?;
+        if entries.is_empty() {
+            return Ok(Some("No moderation actions recorded".to_owned()));
+        }
+
+        let lines: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                let user = entry
+                    .target_nickname
+                    .clone()
+                    .or_else(|| entry.target_user_id.map(|user_id| user_id.to_string()))
+                    .unwrap_or_else(|| "-".to_owned());
+                let trigger = entry
+                    .trigger
+                    .get("kind")
+                    .and_then(|kind| kind.as_str())
+                    .unwrap_or("unknown");
+                format!(
+                    "#{} {:?} {} by {}: {:?}",
+                    entry.id, entry.action, user, trigger, entry.outcome
+                )
             })
-            .await?;
-        Ok(())
-    }
+            .collect();
+        Ok(Some(lines.join("\n")))
+    })
+}
+
+// Operators only, it tells which other channels the user was seen in
+fn whois<'a>(
+    client: &'a mut KakaoClient,
+    ctx: &'a CommandContext,
+) -> LocalBoxFuture<'a, Result<Option<String>>> {
+    Box::pin(async move {
+        let users = match (ctx.text("nickname"), &ctx.reply_to) {
+            (Some(nickname), _) => client.users.find_by_nickname(nickname, WHOIS_LIMIT)?,
+            (None, Some(reply)) => client.users.get(reply.src_user_id)?.into_iter().collect(),
+            (None, None) => {
+                return Ok(Some(
+                    "Give a nickname, or reply to a chat of the user".to_owned(),
+                ))
+            }
+        };
+        if users.is_empty() {
+            return Ok(Some("No such user".to_owned()));
+        }
+
+        let mut lines = Vec::new();
+        for user in users {
+            let channels = client.users.channels_of(user.user_id)?;
+            let mut previous: Vec<String> = client
+                .users
+                .nickname_history(user.user_id)?
+                .into_iter()
+                .map(|change| change.nickname)
+                .filter(|nickname| *nickname != user.nickname)
+                .collect();
+            previous.dedup();
+
+            lines.push(format!(
+                "{} ({}), seen in {} channels",
+                user.nickname,
+                user.user_id,
+                channels.len()
+            ));
+            if !previous.is_empty() {
+                lines.push(format!("  previously {}", previous.join(", ")));
+            }
+        }
+        Ok(Some(lines.join("\n")))
+    })
 }
@@ -0,0 +1,411 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::*;
+use reqwest::{header::CONTENT_TYPE, multipart, redirect, Client, Response, Url};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt, sync::mpsc};
+
+use crate::{
+    archive::Archive,
+    attachment::{Attachment, AttachmentKind},
+    config::MediaCfg,
+    dispatcher::{BotEvent, EventHandler, Flow},
+    kakao::KakaoClient,
+};
+
+// Same as the reqwest default
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Photo,
+    File,
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaUpload {
+    pub kind: MediaKind,
+    pub name: String,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+    // Shown by clients before the photo is loaded
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl MediaUpload {
+    pub fn from_bytes(kind: MediaKind, name: impl Into<String>, data: Vec<u8>) -> Self {
+        let name = name.into();
+        Self {
+            kind,
+            mime_type: mime_type_of(&name).to_owned(),
+            name,
+            data,
+            width: None,
+            height: None,
+        }
+    }
+
+    pub async fn from_path(kind: MediaKind, path: &Path) -> Result<Self> {
+        let data = fs::read(path)
+            .await
+            .with_context(|| format!("read {}", path.display()))?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "file".to_owned());
+        Ok(Self::from_bytes(kind, name, data))
+    }
+
+    pub fn dimensions(mut self, width: u32, height: u32) -> Self {
+        self.width = Some(width);
+        self.height = Some(height);
+        self
+    }
+}
+
+// Media stored by the upload server, ready to be attached to a chat
+#[derive(Debug, Clone)]
+pub struct UploadedMedia {
+    pub kind: MediaKind,
+    pub name: String,
+    pub mime_type: String,
+    // Server path, the media key in attachments
+    pub path: String,
+    pub url: String,
+    pub size: u64,
+    // Hex SHA-1 of the content, what the official clients put in attachments
+    pub checksum: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DownloadedMedia {
+    pub url: String,
+    pub path: PathBuf,
+    // Hex SHA-256 of the content, also the file name
+    pub sha256: String,
+    pub size: u64,
+    pub mime_type: Option<String>,
+}
+
+pub struct MediaClient {
+    cfg: MediaCfg,
+    http: Client,
+    download_dir: PathBuf,
+}
+
+impl MediaClient {
+    pub fn new(cfg: &MediaCfg, data_dir: &Path) -> Result<Self> {
+        // Redirects are held to the same hosts as the urls themselves
+        let hosts = cfg.download_hosts.clone();
+        let redirect = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if is_allowed_host(&hosts, attempt.url()) {
+                attempt.follow()
+            } else {
+                attempt.error("redirect to a host outside media.download_hosts")
+            }
+        });
+        let http = Client::builder()
+            .timeout(Duration::from_secs(cfg.timeout_secs))
+            .redirect(redirect)
+            .build()
+            .context("create media http client")?;
+
+        Ok(Self {
+            cfg: cfg.clone(),
+            http,
+            download_dir: data_dir.join(&cfg.download_dir),
+        })
+    }
+
+    // Uses the legacy HTTP upload endpoint, which answers with the server path of the stored media
+    pub async fn upload(&self, user_id: i64, upload: &MediaUpload) -> Result<UploadedMedia> {
+        let part = multipart::Part::bytes(upload.data.clone())
+            .file_name(upload.name.clone())
+            .mime_str(&upload.mime_type)?;
+        let form = multipart::Form::new()
+            .text("user_id", user_id.to_string())
+            .text("attachment_type", upload.mime_type.clone())
+            .part("attachment", part);
+
+        let res = self
+            .http
+            .post(&self.cfg.upload_url)
+            .multipart(form)
+            .send()
+            .await
+            .context("upload media")?
+            .error_for_status()
+            .context("upload media")?;
+        let path = res.text().await?.trim().to_owned();
+        if !path.starts_with('/') {
+            bail!("unexpected upload response '{}'", path);
+        }
+        debug!(
+            "Uploaded {} ({} bytes) to {}",
+            upload.name,
+            upload.data.len(),
+            path
+        );
+
+        Ok(UploadedMedia {
+            kind: upload.kind,
+            name: upload.name.clone(),
+            mime_type: upload.mime_type.clone(),
+            url: format!("{}{}", self.cfg.download_url.trim_end_matches('/'), path),
+            path,
+            size: upload.data.len() as u64,
+            checksum: hex::encode(Sha1::digest(&upload.data)),
+            width: upload.width,
+            height: upload.height,
+        })
+    }
+
+    // Stores the content under its hash, downloading the same media twice keeps one file
+    pub async fn download(&self, url: &str, name_hint: &str) -> Result<DownloadedMedia> {
+        self.check_url(url)?;
+        let res = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .with_context(|| format!("download {}", url))?
+            .error_for_status()
+            .with_context(|| format!("download {}", url))?;
+        if res
+            .content_length()
+            .is_some_and(|length| length > self.cfg.max_download_bytes)
+        {
+            bail!("{} is larger than media.max_download_bytes", url);
+        }
+        let mime_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+
+        fs::create_dir_all(&self.download_dir)
+            .await
+            .with_context(|| format!("create {}", self.download_dir.display()))?;
+        let partial_path = self
+            .download_dir
+            .join(format!(".partial-{:016x}", rand::random::<u64>()));
+        let mut file = fs::File::create(&partial_path).await?;
+        let written = self.write_body(url, res, &mut file).await;
+        drop(file);
+        let (sha256, size) = match written {
+            Ok(written) => written,
+            Err(err) => {
+                let _ = fs::remove_file(&partial_path).await;
+                return Err(err);
+            }
+        };
+
+        let path = self
+            .download_dir
+            .join(format!("{}.{}", sha256, extension_of(name_hint, url)));
+        match fs::try_exists(&path).await? {
+            true => fs::remove_file(&partial_path).await?,
+            false => fs::rename(&partial_path, &path).await?,
+        }
+        debug!("Downloaded {} ({} bytes) to {}", url, size, path.display());
+
+        Ok(DownloadedMedia {
+            url: url.to_owned(),
+            path,
+            sha256,
+            size,
+            mime_type,
+        })
+    }
+
+    // Returns the hex SHA-256 and size of what was written
+    async fn write_body(
+        &self,
+        url: &str,
+        res: Response,
+        file: &mut fs::File,
+    ) -> Result<(String, u64)> {
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        let mut stream = res.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.with_context(|| format!("download {}", url))?;
+            size += chunk.len() as u64;
+            if size > self.cfg.max_download_bytes {
+                bail!("{} is larger than media.max_download_bytes", url);
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok((hex::encode(hasher.finalize()), size))
+    }
+
+    pub async fn download_attachment(
+        &self,
+        attachment: &Attachment,
+    ) -> Result<Vec<DownloadedMedia>> {
+        let sources: Vec<(&str, &str)> = match attachment {
+            Attachment::Photo(photo) => vec![(&photo.url, "")],
+            Attachment::MultiPhoto(photos) => photos
+                .image_urls
+                .iter()
+                .map(|url| (url.as_str(), ""))
+                .collect(),
+            Attachment::File(file) => vec![(&file.url, &file.name)],
+            _ => Vec::new(),
+        };
+
+        for (url, _) in sources.iter() {
+            self.check_url(url)?;
+        }
+
+        let mut downloaded = Vec::with_capacity(sources.len());
+        for (url, name_hint) in sources {
+            downloaded.push(self.download(url, name_hint).await?);
+        }
+        Ok(downloaded)
+    }
+
+    fn check_url(&self, url: &str) -> Result<()> {
+        let parsed = Url::parse(url).with_context(|| format!("invalid media url '{}'", url))?;
+        if !is_allowed_host(&self.cfg.download_hosts, &parsed) {
+            bail!(
+                "refusing to download {}, not a media.download_hosts url",
+                url
+            );
+        }
+        Ok(())
+    }
+}
+
+fn is_allowed_host(hosts: &[String], url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+
+    hosts.iter().any(|allowed| {
+        host.eq_ignore_ascii_case(allowed)
+            || host.len() > allowed.len()
+                && host[host.len() - allowed.len()..].eq_ignore_ascii_case(allowed)
+                && host.as_bytes()[host.len() - allowed.len() - 1] == b'.'
+    })
+}
+
+fn mime_type_of(name: &str) -> &'static str {
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("mp4") => "video/mp4",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+// Keeps a short alphanumeric extension from the file name or url, for file browsers
+fn extension_of(name_hint: &str, url: &str) -> String {
+    let url_path = url.split(['?', '#']).next().unwrap_or_default();
+    [name_hint, url_path.rsplit('/').next().unwrap_or_default()]
+        .iter()
+        .filter_map(|name| name.rsplit_once('.').map(|(_, ext)| ext))
+        .find(|ext| {
+            !ext.is_empty() && ext.len() <= 5 && ext.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+        .map(|ext| ext.to_ascii_lowercase())
+        .unwrap_or_else(|| "bin".to_owned())
+}
+
+struct MediaJob {
+    channel_id: i64,
+    log_id: i64,
+    attachment: Attachment,
+}
+
+// Downloads photos and files of incoming chats and records them in the archive. Downloads run
+// one at a time on their own task, so slow media servers don't hold up the event loop
+pub struct MediaArchiver {
+    queue: mpsc::Sender<MediaJob>,
+}
+
+impl MediaArchiver {
+    pub fn new(cfg: &MediaCfg, data_dir: &Path, archive: Arc<Archive>) -> Result<Self> {
+        let media = MediaClient::new(cfg, data_dir)?;
+        let (queue, recv) = mpsc::channel(cfg.download_queue_size);
+        tokio::spawn(archive_loop(media, archive, recv));
+
+        Ok(Self { queue })
+    }
+}
+
+#[async_trait(?Send)]
+impl EventHandler for MediaArchiver {
+    async fn handle(&self, _client: &mut KakaoClient, event: &BotEvent) -> Result<Flow> {
+        let BotEvent::Chat(chat) = event else {
+            return Ok(Flow::Continue);
+        };
+        let attachment = Attachment::parse(&chat.chat.chat);
+        if !matches!(
+            attachment.kind(),
+            AttachmentKind::Photo | AttachmentKind::MultiPhoto | AttachmentKind::File
+        ) {
+            return Ok(Flow::Continue);
+        }
+
+        let job = MediaJob {
+            channel_id: chat.channel_id,
+            log_id: chat.log_id,
+            attachment,
+        };
+        if self.queue.try_send(job).is_err() {
+            warn!(
+                "Media download queue is full, not downloading chat {}",
+                chat.log_id
+            );
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+async fn archive_loop(
+    media: MediaClient,
+    archive: Arc<Archive>,
+    mut queue: mpsc::Receiver<MediaJob>,
+) {
+    while let Some(job) = queue.recv().await {
+        let downloaded = match media.download_attachment(&job.attachment).await {
+            Ok(downloaded) => downloaded,
+            Err(err) => {
+                warn!("Cannot download media of chat {}: {:?}", job.log_id, err);
+                continue;
+            }
+        };
+        for downloaded in downloaded.iter() {
+            if let Err(err) = archive.record_media(job.channel_id, job.log_id, downloaded) {
+                error!("Cannot archive media of chat {}: {:?}", job.log_id, err);
+            }
+        }
+    }
+}
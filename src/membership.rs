@@ -0,0 +1,249 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use log::*;
+use talk_loco_client::client::talk::TalkClient;
+use talk_loco_command::request::chat::JoinInfoReq;
+
+use crate::{
+    config::{MembershipCfg, MembershipLink},
+    dispatcher::{BotEvent, EventHandler, Flow},
+    kakao::KakaoClient,
+    media::{MediaKind, MediaUpload},
+};
+
+#[derive(Debug, Default)]
+struct ReconcilerState {
+    last_run: Option<Instant>,
+    // Invite url -> link id, links don't change id
+    link_ids: HashMap<String, i64>,
+    // Local profile image -> uploaded server path
+    profile_paths: HashMap<PathBuf, String>,
+    // Links retrying can't join, skipped until restart
+    abandoned: HashSet<String>,
+    // Link id -> profile image last set there, the server may serve it under another url
+    applied_images: HashMap<i64, AppliedImage>,
+}
+
+#[derive(Debug)]
+struct AppliedImage {
+    // Uploaded server path
+    path: String,
+    // Image url the roster showed after setting it, None until the next check
+    image_url: Option<String>,
+}
+
+// Keeps the bot joined to membership.links with the configured profile. Runs on startup
+// (call reconcile before dispatching), on reconnect, when the bot leaves or is removed from
+// a channel and on the first event after check_interval_secs
+pub struct Reconciler {
+    links: Vec<MembershipLink>,
+    interval: Duration,
+    state: Mutex<ReconcilerState>,
+}
+
+impl Reconciler {
+    pub fn new(cfg: &MembershipCfg) -> Self {
+        Self {
+            links: cfg.links.clone(),
+            interval: Duration::from_secs(cfg.check_interval_secs),
+            state: Mutex::new(ReconcilerState::default()),
+        }
+    }
+
+    pub async fn reconcile(&self, client: &mut KakaoClient) {
+        self.state.lock().unwrap().last_run = Some(Instant::now());
+        for link in self.links.iter() {
+            if self.state.lock().unwrap().abandoned.contains(&link.url) {
+                continue;
+            }
+            if let Err(err) = self.reconcile_link(client, link).await {
+                warn!("Cannot reconcile membership of {}: {:?}", link.url, err);
+            }
+        }
+    }
+
+    async fn reconcile_link(&self, client: &mut KakaoClient, link: &MembershipLink) -> Result<()> {
+        let link_id = self.link_id(client, &link.url).await?;
+        let profile_path = self.profile_path(client, link).await?;
+
+        let joined = client
+            .channels()
+            .find(|channel| channel.link_id() == Some(link_id))
+            .map(|channel| channel.channel_id);
+        let Some(channel_id) = joined else {
+            info!("Not in {}, joining", link.url);
+            let res = client
+                .join_channel(
+                    &link.url,
+                    &link.nickname,
+                    profile_path.as_deref(),
+                    link.passcode.as_deref(),
+                )
+                .await;
+            match res {
+                Ok(_) => self.applied(link_id, profile_path),
+                Err(err) if !err.is_retryable() => {
+                    error!("Giving up on {}: {}", link.url, err);
+                    self.state
+                        .lock()
+                        .unwrap()
+                        .abandoned
+                        .insert(link.url.clone());
+                }
+                Err(err) => return Err(err).context("join channel"),
+            }
+            return Ok(());
+        };
+
+        let (nickname, image_url) = match client
+            .user_id()
+            .and_then(|user_id| client.roster.member(channel_id, user_id))
+        {
+            Some(member) => (Some(member.nickname.clone()), member.image_url.clone()),
+            None => (None, None),
+        };
+        let nickname_changed = nickname.as_deref() != Some(link.nickname.as_str());
+        let image_changed = match &profile_path {
+            Some(path) => !self.is_image_applied(link_id, path, image_url.as_deref()),
+            None => false,
+        };
+        if nickname_changed || image_changed {
+            info!(
+                "Profile in {} is {:?} with image {:?}, changing to '{}' with {:?}",
+                link.url, nickname, image_url, link.nickname, link.profile_image
+            );
+            client
+                .update_link_profile(link_id, &link.nickname, profile_path.as_deref())
+                .await
+                .context("update profile")?;
+            self.applied(link_id, profile_path);
+        }
+        Ok(())
+    }
+
+    // Whether the bot's image in the link is the uploaded one, either served under the upload
+    // path or still the url seen after it was last set
+    fn is_image_applied(&self, link_id: i64, path: &str, image_url: Option<&str>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some(applied) = state
+            .applied_images
+            .get_mut(&link_id)
+            .filter(|applied| applied.path == path)
+        {
+            match &applied.image_url {
+                Some(applied_url) => return Some(applied_url.as_str()) == image_url,
+                None => {
+                    applied.image_url = image_url.map(str::to_owned);
+                    return true;
+                }
+            }
+        }
+
+        let applied = image_url.is_some_and(|image_url| image_url.contains(path));
+        if applied {
+            state.applied_images.insert(
+                link_id,
+                AppliedImage {
+                    path: path.to_owned(),
+                    image_url: image_url.map(str::to_owned),
+                },
+            );
+        }
+        applied
+    }
+
+    fn applied(&self, link_id: i64, profile_path: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        match profile_path {
+            Some(path) => {
+                state.applied_images.insert(
+                    link_id,
+                    AppliedImage {
+                        path,
+                        image_url: None,
+                    },
+                );
+            }
+            None => {
+                state.applied_images.remove(&link_id);
+            }
+        }
+    }
+
+    async fn link_id(&self, client: &KakaoClient, url: &str) -> Result<i64> {
+        if let Some(link_id) = self.state.lock().unwrap().link_ids.get(url) {
+            return Ok(*link_id);
+        }
+
+        let join_info = TalkClient(&client.talk_client.connection().session)
+            .get_join_info(&JoinInfoReq {
+                link_url: url.to_owned(),
+                referer: "EW".to_owned(),
+            })
+            .await
+            .context("get join info")?;
+        let link_id = join_info.open_link.link_id;
+        self.state
+            .lock()
+            .unwrap()
+            .link_ids
+            .insert(url.to_owned(), link_id);
+        Ok(link_id)
+    }
+
+    async fn profile_path(
+        &self,
+        client: &KakaoClient,
+        link: &MembershipLink,
+    ) -> Result<Option<String>> {
+        let Some(image) = &link.profile_image else {
+            return Ok(None);
+        };
+        if let Some(path) = self.state.lock().unwrap().profile_paths.get(image) {
+            return Ok(Some(path.clone()));
+        }
+
+        let upload = MediaUpload::from_path(MediaKind::Photo, image).await?;
+        let uploaded = client
+            .upload_media(&upload)
+            .await
+            .context("upload profile image")?;
+        self.state
+            .lock()
+            .unwrap()
+            .profile_paths
+            .insert(image.clone(), uploaded.path.clone());
+        Ok(Some(uploaded.path))
+    }
+
+    fn is_due(&self) -> bool {
+        match self.state.lock().unwrap().last_run {
+            Some(last_run) => last_run.elapsed() >= self.interval,
+            None => true,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl EventHandler for Reconciler {
+    async fn handle(&self, client: &mut KakaoClient, event: &BotEvent) -> Result<Flow> {
+        let removed = match event {
+            BotEvent::MemberLeft { members, .. } => members
+                .iter()
+                .any(|member| Some(member.user_id) == client.user_id()),
+            _ => false,
+        };
+
+        if removed || matches!(event, BotEvent::Reconnected { .. }) || self.is_due() {
+            self.reconcile(client).await;
+        }
+        Ok(Flow::Continue)
+    }
+}
@@ -0,0 +1,438 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use kiwi_talk_client::event::chat::ChatReceived;
+use log::*;
+use serde::{Deserialize, Serialize};
+use talk_loco_client::client::ClientRequestError;
+use talk_loco_command::request::chat::{DeleteMsgReq, HideMsgReq, KickUserReq};
+
+use crate::{
+    audit::{AuditEntry, Outcome, UndoInfo},
+    builder::MessageBuilder,
+    config::ModerationCfg,
+    db::unix_now,
+    flood::AbuseKind,
+    kakao::KakaoClient,
+    send_queue::SendPriority,
+};
+
+// Shadowed actions kept in memory for review
+const MAX_SHADOW_RECORDS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    Log,
+    Hide,
+    Delete,
+    Kick,
+}
+
+// Who or what asked for the action, kept in the audit log
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Trigger {
+    Rule { name: String, strike: u32 },
+    Spam { score: f64, reasons: String },
+    Command { command: String, user_id: i64 },
+    // Outside of chat, via says where from, e.g. "api"
+    Operator { via: String },
+    Flood { abuses: Vec<AbuseKind> },
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Rule { name, strike } => write!(f, "rule '{}', strike {}", name, strike),
+            Trigger::Spam { score, reasons } => write!(f, "spam score {:.2} ({})", score, reasons),
+            Trigger::Command { command, user_id } => {
+                write!(f, "command '{}' by user {}", command, user_id)
+            }
+            Trigger::Operator { via } => write!(f, "operator via {}", via),
+            Trigger::Flood { abuses } => {
+                let abuses: Vec<String> = abuses.iter().map(AbuseKind::to_string).collect();
+                write!(f, "flood: {}", abuses.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ModerationError {
+    #[error("{action:?} needs an open chat, channel {channel_id} is a normal chat")]
+    NotOpenChannel {
+        channel_id: i64,
+        action: ModerationAction,
+    },
+    #[error("{action:?} needs a {missing} to act on")]
+    MissingTarget {
+        action: ModerationAction,
+        missing: &'static str,
+    },
+    #[error("request failed: {0}")]
+    Request(#[from] ClientRequestError),
+}
+
+// What an action applies to. Received chats bring their content into the audit log,
+// operators acting by id only know the ids
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    pub channel_id: i64,
+    // None in normal chats, hide and kick need it
+    pub link_id: Option<i64>,
+    // Hide needs the chat and its type, delete the chat and kick the user
+    pub log_id: Option<i64>,
+    pub chat_type: Option<i32>,
+    pub user_id: Option<i64>,
+    pub nickname: Option<String>,
+    pub message: Option<String>,
+    pub attachment: Option<String>,
+}
+
+impl Target {
+    pub fn message(channel_id: i64, link_id: Option<i64>, log_id: i64, chat_type: i32) -> Self {
+        Self {
+            channel_id,
+            link_id,
+            log_id: Some(log_id),
+            chat_type: Some(chat_type),
+            ..Default::default()
+        }
+    }
+
+    pub fn user(channel_id: i64, link_id: Option<i64>, user_id: i64) -> Self {
+        Self {
+            channel_id,
+            link_id,
+            user_id: Some(user_id),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&ChatReceived> for Target {
+    fn from(chat: &ChatReceived) -> Self {
+        let content = &chat.chat.chat.content;
+        Self {
+            channel_id: chat.channel_id,
+            link_id: chat.link_id,
+            log_id: Some(chat.log_id),
+            chat_type: Some(chat.chat.chat.chat_type.0),
+            user_id: Some(chat.chat.sender_id),
+            nickname: chat.user_nickname.clone(),
+            message: content.message.clone(),
+            attachment: content.attachment.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShadowRecord {
+    pub at: i64,
+    pub channel_id: i64,
+    pub log_id: Option<i64>,
+    pub user_id: Option<i64>,
+    pub message: Option<String>,
+    pub action: ModerationAction,
+    pub reason: String,
+}
+
+// Channels in shadow mode only record the actions they would take, nothing is hidden,
+// deleted or kicked. Toggled per channel at runtime, the config gives the default, which the
+// admin api can change
+#[derive(Debug)]
+pub struct ShadowMode {
+    default: bool,
+    channels: HashMap<i64, bool>,
+    // Shadowed actions are also reported to this channel
+    pub report_channel_id: Option<i64>,
+    records: VecDeque<ShadowRecord>,
+}
+
+impl ShadowMode {
+    pub fn new(cfg: &ModerationCfg) -> Self {
+        Self {
+            default: cfg.shadow,
+            channels: cfg
+                .shadow_channels
+                .iter()
+                .map(|channel_id| (*channel_id, true))
+                .collect(),
+            report_channel_id: cfg.report_channel_id,
+            records: VecDeque::new(),
+        }
+    }
+
+    pub fn is_enabled(&self, channel_id: i64) -> bool {
+        self.channels
+            .get(&channel_id)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    pub fn set_default(&mut self, enabled: bool) {
+        self.default = enabled;
+    }
+
+    pub fn set_channel(&mut self, channel_id: i64, enabled: bool) {
+        info!("Shadow mode {} for channel_id={}", enabled, channel_id);
+        self.channels.insert(channel_id, enabled);
+    }
+
+    // Back to the default mode
+    pub fn clear_channel(&mut self, channel_id: i64) {
+        self.channels.remove(&channel_id);
+    }
+
+    // Oldest first
+    pub fn records(&self) -> impl Iterator<Item = &ShadowRecord> {
+        self.records.iter()
+    }
+
+    fn record(&mut self, record: ShadowRecord) {
+        if self.records.len() >= MAX_SHADOW_RECORDS {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+}
+
+// Applies the action to a chat or member, or only records it when the channel is in shadow
+// mode. Hide and kick only exist in open chats. Every call ends up in the audit log when it
+// is enabled, all moderation requests go through here
+pub async fn apply(
+    client: &mut KakaoClient,
+    target: &Target,
+    action: ModerationAction,
+    trigger: &Trigger,
+) -> Result<(), ModerationError> {
+    let request = request_for(target, action)?;
+    let undo = request.as_ref().map(ModerationRequest::undo_info);
+    if action != ModerationAction::Log && client.shadow.is_enabled(target.channel_id) {
+        shadow(client, target, action, trigger).await;
+        audit(
+            client,
+            target,
+            action,
+            trigger,
+            Outcome::Shadowed,
+            Ok(None),
+            undo,
+        );
+        return Ok(());
+    }
+
+    let res = match request {
+        None => {
+            info!(
+                "Flagged chat {:?} from user {:?} in channel_id={}: {}",
+                target.log_id, target.user_id, target.channel_id, trigger
+            );
+            Ok(None)
+        }
+        Some(ModerationRequest::Hide(req)) => client
+            .hide_message(req)
+            .await
+            .map(|res| Some(format!("{:?}", res))),
+        Some(ModerationRequest::Delete(req)) => client
+            .delete_message(req)
+            .await
+            .map(|res| Some(format!("{:?}", res))),
+        Some(ModerationRequest::Kick(req)) => client
+            .kick_user(req)
+            .await
+            .map(|res| Some(format!("{:?}", res))),
+    };
+
+    match res {
+        Ok(response) => {
+            audit(
+                client,
+                target,
+                action,
+                trigger,
+                Outcome::Ok,
+                Ok(response),
+                undo,
+            );
+            Ok(())
+        }
+        Err(err) => {
+            let error = format!("{:?}", err);
+            audit(
+                client,
+                target,
+                action,
+                trigger,
+                Outcome::Error,
+                Err(error),
+                undo,
+            );
+            Err(err.into())
+        }
+    }
+}
+
+enum ModerationRequest {
+    Hide(HideMsgReq),
+    Delete(DeleteMsgReq),
+    Kick(KickUserReq),
+}
+
+impl ModerationRequest {
+    fn undo_info(&self) -> UndoInfo {
+        match self {
+            ModerationRequest::Hide(req) => UndoInfo::Hide {
+                link_id: req.link_id,
+                channel_id: req.channel_id,
+                log_id: req.log_id,
+                chat_type: req.chat_type,
+            },
+            ModerationRequest::Delete(req) => UndoInfo::Delete {
+                channel_id: req.chat_id,
+                log_id: req.log_id,
+            },
+            ModerationRequest::Kick(req) => UndoInfo::Kick {
+                link_id: req.link_id,
+                channel_id: req.channel_id,
+                user_id: req.user_id,
+            },
+        }
+    }
+}
+
+// Checked before anything is shadowed or recorded, so impossible actions fail the same way
+// in shadow mode
+fn request_for(
+    target: &Target,
+    action: ModerationAction,
+) -> Result<Option<ModerationRequest>, ModerationError> {
+    let link_id = || {
+        target.link_id.ok_or(ModerationError::NotOpenChannel {
+            channel_id: target.channel_id,
+            action,
+        })
+    };
+    let missing_chat = ModerationError::MissingTarget {
+        action,
+        missing: "chat",
+    };
+    let user_id = || {
+        target.user_id.ok_or(ModerationError::MissingTarget {
+            action,
+            missing: "user",
+        })
+    };
+
+    let request = match action {
+        ModerationAction::Log => return Ok(None),
+        ModerationAction::Hide => {
+            let (Some(log_id), Some(chat_type)) = (target.log_id, target.chat_type) else {
+                return Err(missing_chat);
+            };
+            ModerationRequest::Hide(HideMsgReq {
+                link_id: link_id()?,
+                channel_id: target.channel_id,
+                log_id,
+                chat_type,
+            })
+        }
+        ModerationAction::Delete => ModerationRequest::Delete(DeleteMsgReq {
+            chat_id: target.channel_id,
+            log_id: target.log_id.ok_or(missing_chat)?,
+        }),
+        ModerationAction::Kick => ModerationRequest::Kick(KickUserReq {
+            channel_id: target.channel_id,
+            user_id: user_id()?,
+            link_id: link_id()?,
+        }),
+    };
+    Ok(Some(request))
+}
+
+fn audit(
+    client: &KakaoClient,
+    target: &Target,
+    action: ModerationAction,
+    trigger: &Trigger,
+    outcome: Outcome,
+    // The server response, or why the request failed
+    result: Result<Option<String>, String>,
+    undo: Option<UndoInfo>,
+) {
+    let Some(audit_log) = &client.audit else {
+        return;
+    };
+
+    let entry = AuditEntry {
+        id: 0,
+        at: unix_now(),
+        action,
+        trigger: serde_json::to_value(trigger).unwrap_or_default(),
+        channel_id: target.channel_id,
+        link_id: target.link_id,
+        log_id: target.log_id,
+        target_user_id: target.user_id,
+        target_nickname: target.nickname.clone(),
+        chat_type: target.chat_type,
+        message: target.message.clone(),
+        attachment: target.attachment.clone(),
+        outcome,
+        response: result.clone().ok().flatten(),
+        error: result.err(),
+        undo,
+    };
+    if let Err(err) = audit_log.insert(&entry) {
+        error!("Cannot write audit entry {:?}: {:?}", entry, err);
+    }
+}
+
+async fn shadow(
+    client: &mut KakaoClient,
+    target: &Target,
+    action: ModerationAction,
+    trigger: &Trigger,
+) {
+    let record = ShadowRecord {
+        at: unix_now(),
+        channel_id: target.channel_id,
+        log_id: target.log_id,
+        user_id: target.user_id,
+        message: target.message.clone(),
+        action,
+        reason: trigger.to_string(),
+    };
+    info!("Shadowed {:?}", record);
+
+    if let Some(report_channel_id) = client.shadow.report_channel_id {
+        let user = target
+            .nickname
+            .clone()
+            .or_else(|| record.user_id.map(|user_id| user_id.to_string()))
+            .unwrap_or_else(|| "unknown user".to_owned());
+        let chat = match record.log_id {
+            Some(log_id) => format!("chat {} from {}", log_id, user),
+            None => user,
+        };
+        let message = format!(
+            "[shadow] Would {:?} {} in channel {}: {}\n{}",
+            action,
+            chat,
+            record.channel_id,
+            record.reason,
+            record.message.as_deref().unwrap_or_default()
+        );
+        let report = MessageBuilder::text(message).build();
+        if let Err(err) = client
+            .send_message_queued(report_channel_id, report, SendPriority::High)
+            .await
+        {
+            warn!("Cannot report shadowed action: {:?}", err);
+        }
+    }
+
+    client.shadow.record(record);
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use talk_loco_command::structs::{openlink::OpenLinkUser, user::UserVariant};
+
+use crate::{
+    feed::{self, Feed, FeedMember},
+    kakao::{KakaoUser, OpenMemberType},
+};
+
+#[derive(Debug, Clone)]
+pub struct RosterMember {
+    pub user_id: i64,
+    pub nickname: String,
+    pub image_url: Option<String>,
+    // Unknown in normal channels, roles only exist in open chats
+    pub role: OpenMemberType,
+    // Only known for members who joined while the bot was watching
+    pub joined_at: Option<i64>,
+}
+
+impl From<UserVariant> for RosterMember {
+    fn from(value: UserVariant) -> Self {
+        match value {
+            UserVariant::Normal(user) => Self {
+                user_id: user.user_id,
+                nickname: user.nickname,
+                image_url: user.profile_image_url,
+                role: OpenMemberType::Unknown,
+                joined_at: None,
+            },
+            UserVariant::Open(user) => Self {
+                user_id: user.user_id,
+                nickname: user.nickname,
+                image_url: user.profile_image_url,
+                role: user.member_type.into(),
+                joined_at: None,
+            },
+        }
+    }
+}
+
+impl From<OpenLinkUser> for RosterMember {
+    fn from(value: OpenLinkUser) -> Self {
+        Self {
+            user_id: value.user_id,
+            nickname: value.nickname,
+            image_url: value.profile_image_url,
+            role: value.member_type.into(),
+            joined_at: None,
+        }
+    }
+}
+
+impl From<&RosterMember> for KakaoUser {
+    fn from(value: &RosterMember) -> Self {
+        Self {
+            user_id: value.user_id,
+            nickname: value.nickname.clone(),
+            image_url: value.image_url.clone(),
+        }
+    }
+}
+
+// Current members of every channel, loaded from member lists and kept up to date from feeds
+#[derive(Debug, Default)]
+pub struct Roster {
+    channels: HashMap<i64, HashMap<i64, RosterMember>>,
+}
+
+impl Roster {
+    // Replaces the channel with a fresh member list, join times already known are kept
+    pub fn replace(&mut self, channel_id: i64, members: impl IntoIterator<Item = RosterMember>) {
+        let old = self.channels.remove(&channel_id).unwrap_or_default();
+        let members = members
+            .into_iter()
+            .map(|mut member| {
+                if let Some(old) = old.get(&member.user_id) {
+                    member.joined_at = member.joined_at.or(old.joined_at);
+                }
+                (member.user_id, member)
+            })
+            .collect();
+        self.channels.insert(channel_id, members);
+    }
+
+    pub fn upsert(&mut self, channel_id: i64, mut member: RosterMember) {
+        let members = self.channels.entry(channel_id).or_default();
+        if let Some(old) = members.get(&member.user_id) {
+            member.joined_at = member.joined_at.or(old.joined_at);
+        }
+        members.insert(member.user_id, member);
+    }
+
+    pub fn set_role(&mut self, channel_id: i64, user_id: i64, role: OpenMemberType) {
+        if let Some(member) = self
+            .channels
+            .get_mut(&channel_id)
+            .and_then(|members| members.get_mut(&user_id))
+        {
+            member.role = role;
+        }
+    }
+
+    pub fn remove(&mut self, channel_id: i64, user_id: i64) -> Option<RosterMember> {
+        self.channels.get_mut(&channel_id)?.remove(&user_id)
+    }
+
+    pub fn remove_channel(&mut self, channel_id: i64) {
+        self.channels.remove(&channel_id);
+    }
+
+    pub fn member(&self, channel_id: i64, user_id: i64) -> Option<&RosterMember> {
+        self.channels.get(&channel_id)?.get(&user_id)
+    }
+
+    pub fn role(&self, channel_id: i64, user_id: i64) -> OpenMemberType {
+        self.member(channel_id, user_id)
+            .map(|member| member.role)
+            .unwrap_or(OpenMemberType::Unknown)
+    }
+
+    // Sorted by user id so listings are stable
+    pub fn members(&self, channel_id: i64) -> Vec<&RosterMember> {
+        let mut members: Vec<_> = self
+            .channels
+            .get(&channel_id)
+            .map(|members| members.values().collect())
+            .unwrap_or_default();
+        members.sort_by_key(|member| member.user_id);
+        members
+    }
+
+    pub fn with_role(&self, channel_id: i64, role: OpenMemberType) -> Vec<&RosterMember> {
+        self.members(channel_id)
+            .into_iter()
+            .filter(|member| member.role == role)
+            .collect()
+    }
+
+    // send_at of the feed chat is used as the join time
+    pub fn apply_feed(&mut self, channel_id: i64, feed: &Feed, send_at: i64) {
+        match feed.feed_type {
+            feed::FEED_INVITE | feed::FEED_OPENLINK_JOIN => {
+                for member in feed.all_members() {
+                    self.upsert(channel_id, joined_member(member, send_at));
+                }
+            }
+            feed::FEED_LEAVE
+            | feed::FEED_SECRET_LEAVE
+            | feed::FEED_OPENLINK_KICKED
+            | feed::FEED_CHANNEL_KICKED => {
+                for member in feed.all_members() {
+                    self.remove(channel_id, member.user_id);
+                }
+            }
+            feed::FEED_OPEN_MANAGER_GRANT => {
+                for member in feed.all_members() {
+                    self.set_role(channel_id, member.user_id, OpenMemberType::Manager);
+                }
+            }
+            feed::FEED_OPEN_MANAGER_REVOKE => {
+                for member in feed.all_members() {
+                    self.set_role(channel_id, member.user_id, OpenMemberType::Member);
+                }
+            }
+            feed::FEED_OPENLINK_HAND_OVER_HOST => {
+                if let Some(prev_host) = &feed.prev_host {
+                    self.set_role(channel_id, prev_host.user_id, OpenMemberType::Member);
+                }
+                if let Some(new_host) = &feed.new_host {
+                    self.set_role(channel_id, new_host.user_id, OpenMemberType::Host);
+                }
+            }
+            feed::FEED_CHANNEL_DELETED | feed::FEED_OPENLINK_DELETE_LINK => {
+                self.remove_channel(channel_id);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn joined_member(member: FeedMember, send_at: i64) -> RosterMember {
+    RosterMember {
+        user_id: member.user_id,
+        nickname: member.nickname.unwrap_or_default(),
+        image_url: None,
+        role: OpenMemberType::Member,
+        joined_at: Some(send_at),
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use kiwi_talk_client::event::chat::ChatReceived;
+use log::*;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    attachment::{Attachment, AttachmentKind},
+    db::unix_now,
+    dispatcher::{BotEvent, EventHandler, Flow},
+    kakao::{KakaoClient, OpenMemberType},
+    moderation::{self, ModerationAction, Target, Trigger},
+    spam::LinkDetector,
+};
+
+// Strikes added between sweeps of forgiven users
+const SWEEP_INTERVAL: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Host,
+    Manager,
+    Member,
+    Bot,
+    Unknown,
+}
+
+impl From<OpenMemberType> for Role {
+    fn from(value: OpenMemberType) -> Self {
+        match value {
+            OpenMemberType::Host => Self::Host,
+            OpenMemberType::Manager => Self::Manager,
+            OpenMemberType::Member => Self::Member,
+            OpenMemberType::Bot => Self::Bot,
+            OpenMemberType::Unknown => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleDef>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleDef {
+    name: String,
+    #[serde(default)]
+    when: ConditionDef,
+    #[serde(rename = "on_strike")]
+    steps: Vec<StrikeStep>,
+}
+
+// Every condition that is set has to match
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConditionDef {
+    channels: Vec<i64>,
+    // Defaults to members and unknown users, hosts, managers and bots are left alone
+    roles: Vec<Role>,
+    joined_within_secs: Option<u64>,
+    contains_link: Option<bool>,
+    keywords: Vec<String>,
+    matches: Option<String>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    chat_types: Vec<i32>,
+    attachments: Vec<AttachmentKind>,
+    min_mentions: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrikeStep {
+    // Applies from this strike count on, until a step with a higher count takes over
+    pub strike: u32,
+    pub actions: Vec<ModerationAction>,
+}
+
+struct Condition {
+    channels: Vec<i64>,
+    roles: Vec<Role>,
+    joined_within: Option<Duration>,
+    contains_link: Option<bool>,
+    keywords: Vec<String>,
+    matches: Option<Regex>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    chat_types: Vec<i32>,
+    attachments: Vec<AttachmentKind>,
+    min_mentions: Option<usize>,
+}
+
+impl TryFrom<ConditionDef> for Condition {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ConditionDef) -> Result<Self> {
+        let roles = match value.roles.is_empty() {
+            true => vec![Role::Member, Role::Unknown],
+            false => value.roles,
+        };
+        let matches = value
+            .matches
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("invalid `matches` regex")?;
+
+        Ok(Self {
+            channels: value.channels,
+            roles,
+            joined_within: value.joined_within_secs.map(Duration::from_secs),
+            contains_link: value.contains_link,
+            keywords: value
+                .keywords
+                .iter()
+                .map(|keyword| keyword.to_lowercase())
+                .collect(),
+            matches,
+            min_length: value.min_length,
+            max_length: value.max_length,
+            chat_types: value.chat_types,
+            attachments: value.attachments,
+            min_mentions: value.min_mentions,
+        })
+    }
+}
+
+pub struct Rule {
+    pub name: String,
+    condition: Condition,
+    steps: Vec<StrikeStep>,
+}
+
+impl Rule {
+    pub fn actions_for(&self, strikes: u32) -> &[ModerationAction] {
+        self.steps
+            .iter()
+            .rev()
+            .find(|step| step.strike <= strikes)
+            .map(|step| step.actions.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+// What a rule condition can look at
+pub struct RuleInput<'a> {
+    pub chat: &'a ChatReceived,
+    pub text: &'a str,
+    pub attachment: &'a Attachment,
+    pub role: Role,
+    // Unix seconds, only known for members who joined while the bot was watching
+    pub joined_at: Option<i64>,
+}
+
+// Strikes per (channel, user), one strike is forgiven every decay period without offenses
+struct Strikes {
+    decay: Duration,
+    counts: HashMap<(i64, i64), (u32, Instant)>,
+    added: u32,
+}
+
+impl Strikes {
+    fn add(&mut self, channel_id: i64, user_id: i64) -> u32 {
+        let now = Instant::now();
+        self.added += 1;
+        if self.added % SWEEP_INTERVAL == 0 {
+            self.sweep(now);
+        }
+
+        let (count, last) = self.counts.entry((channel_id, user_id)).or_insert((0, now));
+
+        if !self.decay.is_zero() {
+            let decayed = now.duration_since(*last).as_secs() / self.decay.as_secs().max(1);
+            *count = count.saturating_sub(decayed.min(u32::MAX as u64) as u32);
+        }
+        *count += 1;
+        *last = now;
+        *count
+    }
+
+    // Drops users whose strikes have all been forgiven, they start over from zero anyway.
+    // Without decay strikes are kept for good
+    fn sweep(&mut self, now: Instant) {
+        if self.decay.is_zero() {
+            return;
+        }
+        let decay = self.decay.as_secs().max(1);
+        self.counts
+            .retain(|_, (count, last)| now.duration_since(*last).as_secs() / decay < *count as u64);
+    }
+}
+
+pub struct RulesEngine {
+    rules: Vec<Rule>,
+    links: LinkDetector,
+    strikes: RefCell<Strikes>,
+}
+
+impl RulesEngine {
+    pub fn load(path: &Path, strike_decay: Duration) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("read rules file {}", path.display()))?;
+        let engine = Self::parse(&data, strike_decay)
+            .with_context(|| format!("parse rules file {}", path.display()))?;
+        info!(
+            "Loaded {} rules from {}",
+            engine.rules.len(),
+            path.display()
+        );
+        Ok(engine)
+    }
+
+    pub fn parse(data: &str, strike_decay: Duration) -> Result<Self> {
+        let file: RulesFile = toml::from_str(data)?;
+
+        let mut rules = Vec::new();
+        for def in file.rules {
+            if def.steps.is_empty() {
+                bail!("rule '{}' has no on_strike actions", def.name);
+            }
+            let condition =
+                Condition::try_from(def.when).with_context(|| format!("rule '{}'", def.name))?;
+
+            let mut steps = def.steps;
+            steps.sort_by_key(|step| step.strike);
+            rules.push(Rule {
+                name: def.name,
+                condition,
+                steps,
+            });
+        }
+
+        Ok(Self {
+            rules,
+            links: LinkDetector::new(1.0, 1.0),
+            strikes: RefCell::new(Strikes {
+                decay: strike_decay,
+                counts: HashMap::new(),
+                added: 0,
+            }),
+        })
+    }
+
+    // First rule whose conditions all hold
+    pub fn find_match(&self, input: &RuleInput) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|rule| self.matches(&rule.condition, input))
+    }
+
+    fn matches(&self, condition: &Condition, input: &RuleInput) -> bool {
+        let chat = input.chat;
+        if !condition.channels.is_empty() && !condition.channels.contains(&chat.channel_id) {
+            return false;
+        }
+        if !condition.roles.contains(&input.role) {
+            return false;
+        }
+        if !condition.chat_types.is_empty()
+            && !condition.chat_types.contains(&chat.chat.chat.chat_type.0)
+        {
+            return false;
+        }
+        if !condition.attachments.is_empty()
+            && !condition.attachments.contains(&input.attachment.kind())
+        {
+            return false;
+        }
+        if condition
+            .min_mentions
+            .is_some_and(|min| input.attachment.mentions().len() < min)
+        {
+            return false;
+        }
+
+        if let Some(joined_within) = condition.joined_within {
+            let recently_joined = input
+                .joined_at
+                .is_some_and(|joined_at| unix_now() - joined_at <= joined_within.as_secs() as i64);
+            if !recently_joined {
+                return false;
+            }
+        }
+
+        let length = input.text.chars().count();
+        if condition.min_length.is_some_and(|min| length < min)
+            || condition.max_length.is_some_and(|max| length > max)
+        {
+            return false;
+        }
+        if condition
+            .contains_link
+            .is_some_and(|contains_link| self.links.contains_link(input.text) != contains_link)
+        {
+            return false;
+        }
+        if !condition.keywords.is_empty() {
+            let text = input.text.to_lowercase();
+            if !condition
+                .keywords
+                .iter()
+                .any(|keyword| text.contains(keyword.as_str()))
+            {
+                return false;
+            }
+        }
+        if let Some(regex) = &condition.matches {
+            if !regex.is_match(input.text) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[async_trait(?Send)]
+impl EventHandler for RulesEngine {
+    async fn handle(&self, client: &mut KakaoClient, event: &BotEvent) -> Result<Flow> {
+        // Hide and kick only exist in open chats
+        let BotEvent::Chat(chat) = event else {
+            return Ok(Flow::Continue);
+        };
+        if chat.link_id.is_none() {
+            return Ok(Flow::Continue);
+        }
+
+        let sender_id = chat.chat.sender_id;
+        let member = client.roster.member(chat.channel_id, sender_id);
+        let attachment = Attachment::parse(&chat.chat.chat);
+        let input = RuleInput {
+            chat,
+            text: chat
+                .chat
+                .chat
+                .content
+                .message
+                .as_deref()
+                .unwrap_or_default(),
+            attachment: &attachment,
+            role: client
+                .get_open_member_type(chat.channel_id, sender_id)
+                .into(),
+            joined_at: member.and_then(|member| member.joined_at),
+        };
+        let Some(rule) = self.find_match(&input) else {
+            return Ok(Flow::Continue);
+        };
+
+        let strikes = self.strikes.borrow_mut().add(chat.channel_id, sender_id);
+        let actions = rule.actions_for(strikes);
+        info!(
+            "Rule '{}' matched chat {} from user {} (strike {}): {:?}",
+            rule.name, chat.log_id, sender_id, strikes, actions
+        );
+
+        let trigger = Trigger::Rule {
+            name: rule.name.clone(),
+            strike: strikes,
+        };
+        let mut flow = Flow::Continue;
+        for action in actions.iter().copied() {
+            if action != ModerationAction::Log {
+                flow = Flow::Stop;
+            }
+            if let Err(err) = moderation::apply(client, &Target::from(chat), action, &trigger).await
+            {
+                error!(
+                    "Cannot {:?} chat {} for rule '{}': {:?}",
+                    action, chat.log_id, rule.name, err
+                );
+            }
+        }
+
+        Ok(flow)
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::channel::oneshot;
+use kiwi_talk_client::{
+    chat::{Chat, Chatlog},
+    error::KiwiTalkClientError,
+};
+use talk_loco_client::client::ClientRequestError;
+
+use crate::config::SendCfg;
+
+// Lanes are drained in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SendPriority {
+    // Moderation notices and reports
+    High,
+    Normal,
+    // Chatter that can wait
+    Low,
+}
+
+const PRIORITIES: [SendPriority; 3] = [SendPriority::High, SendPriority::Normal, SendPriority::Low];
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    #[error("send failed: {0}")]
+    Client(#[from] KiwiTalkClientError),
+    #[error("send queue dropped the chat")]
+    Dropped,
+}
+
+// Resolves to the sent Chatlog once the queue gets to the chat
+pub struct SendReceipt(oneshot::Receiver<Result<Chatlog, SendError>>);
+
+impl SendReceipt {
+    // None while the chat is still queued
+    pub fn try_result(&mut self) -> Option<Result<Chatlog, SendError>> {
+        match self.0.try_recv() {
+            Ok(result) => result,
+            Err(_) => Some(Err(SendError::Dropped)),
+        }
+    }
+}
+
+impl Future for SendReceipt {
+    type Output = Result<Chatlog, SendError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|result| result.unwrap_or(Err(SendError::Dropped)))
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    // Tokens per second
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, rate: f64, now: Instant) -> Self {
+        Self {
+            capacity: capacity as f64,
+            rate,
+            tokens: capacity as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    // Time until a token is available
+    fn wait(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+pub struct QueuedChat {
+    pub channel_id: i64,
+    pub chat: Chat,
+    pub no_seen: bool,
+    priority: SendPriority,
+    attempts: u32,
+    not_before: Instant,
+    reply: oneshot::Sender<Result<Chatlog, SendError>>,
+}
+
+pub enum Next {
+    Send(QueuedChat),
+    // Nothing can be sent before this much time has passed
+    Wait(Duration),
+    Empty,
+}
+
+// Outgoing chats by priority, limited by a global and a per-channel token bucket
+pub struct SendQueue {
+    cfg: SendCfg,
+    lanes: HashMap<SendPriority, VecDeque<QueuedChat>>,
+    global: TokenBucket,
+    channels: HashMap<i64, TokenBucket>,
+}
+
+impl SendQueue {
+    pub fn new(cfg: &SendCfg) -> Self {
+        Self {
+            cfg: cfg.clone(),
+            lanes: HashMap::new(),
+            global: TokenBucket::new(cfg.global_burst, cfg.global_rate, Instant::now()),
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lanes.values().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(
+        &mut self,
+        channel_id: i64,
+        chat: Chat,
+        no_seen: bool,
+        priority: SendPriority,
+    ) -> SendReceipt {
+        let (reply, receipt) = oneshot::channel();
+        self.lanes
+            .entry(priority)
+            .or_default()
+            .push_back(QueuedChat {
+                channel_id,
+                chat,
+                no_seen,
+                priority,
+                attempts: 0,
+                not_before: Instant::now(),
+                reply,
+            });
+        SendReceipt(receipt)
+    }
+
+    // Oldest sendable chat of the highest priority lane, chats waiting on a busy channel
+    // don't hold back other channels. Within a channel a chat that is not ready, like one
+    // waiting to be retried, holds back everything queued after it
+    pub fn next(&mut self, now: Instant) -> Next {
+        if self.is_empty() {
+            return Next::Empty;
+        }
+
+        let global_wait = self.global.wait(now);
+        let (channel_burst, channel_rate) = (self.cfg.channel_burst, self.cfg.channel_rate);
+        let mut wait = Duration::MAX;
+        let mut blocked = HashSet::new();
+        for priority in PRIORITIES {
+            let Some(lane) = self.lanes.get_mut(&priority) else {
+                continue;
+            };
+            lane.retain(|queued| !queued.reply.is_canceled());
+
+            for i in 0..lane.len() {
+                let queued = &lane[i];
+                if blocked.contains(&queued.channel_id) {
+                    continue;
+                }
+                let channel = self
+                    .channels
+                    .entry(queued.channel_id)
+                    .or_insert_with(|| TokenBucket::new(channel_burst, channel_rate, now));
+                let ready_in = channel
+                    .wait(now)
+                    .max(global_wait)
+                    .max(queued.not_before.saturating_duration_since(now));
+
+                if ready_in.is_zero() {
+                    channel.take();
+                    self.global.take();
+                    return Next::Send(lane.remove(i).unwrap());
+                }
+                wait = wait.min(ready_in);
+                blocked.insert(queued.channel_id);
+            }
+        }
+
+        match wait {
+            Duration::MAX => Next::Empty,
+            wait => Next::Wait(wait),
+        }
+    }
+
+    pub fn complete(&mut self, queued: QueuedChat, result: Result<Chatlog, KiwiTalkClientError>) {
+        let _ = queued.reply.send(result.map_err(SendError::from));
+    }
+
+    // Puts a failed chat back at the front of its lane with a delay, or fails it for good
+    pub fn retry(&mut self, mut queued: QueuedChat, err: KiwiTalkClientError, now: Instant) {
+        queued.attempts += 1;
+        if !is_transient(&err) || queued.attempts > self.cfg.max_retries {
+            self.complete(queued, Err(err));
+            return;
+        }
+
+        let delay = 2u64
+            .checked_pow(queued.attempts - 1)
+            .map_or(u64::MAX, |factor| {
+                self.cfg.retry_delay_ms.saturating_mul(factor)
+            });
+        queued.not_before = now + Duration::from_millis(delay).min(MAX_RETRY_DELAY);
+        self.lanes
+            .entry(queued.priority)
+            .or_default()
+            .push_front(queued);
+    }
+}
+
+// Status errors are the server refusing the chat, anything else is the connection
+fn is_transient(err: &KiwiTalkClientError) -> bool {
+    !matches!(
+        err,
+        KiwiTalkClientError::Request(ClientRequestError::Status(_))
+    )
+}
@@ -0,0 +1,488 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use log::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    archive::Archive,
+    config::{SpamCfg, SpamThreshold},
+    dispatcher::{BotEvent, EventHandler, Flow},
+    kakao::{KakaoClient, OpenMemberType},
+    moderation::{self, ModerationAction, Target, Trigger},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpamAggregate {
+    Max,
+    Sum,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SpamInput<'a> {
+    pub sender_id: i64,
+    pub text: &'a str,
+}
+
+#[derive(Debug, Clone)]
+pub struct Verdict {
+    pub classifier: &'static str,
+    // 0.0 (clean) to 1.0 (certainly spam)
+    pub score: f64,
+    pub reason: String,
+}
+
+// None means the classifier has no opinion on the message
+pub trait SpamClassifier {
+    fn name(&self) -> &'static str;
+
+    fn classify(&self, input: &SpamInput) -> Option<Verdict>;
+
+    fn verdict(&self, score: f64, reason: impl Into<String>) -> Option<Verdict>
+    where
+        Self: Sized,
+    {
+        Some(Verdict {
+            classifier: self.name(),
+            score,
+            reason: reason.into(),
+        })
+    }
+}
+
+pub struct LinkDetector {
+    link: Regex,
+    invite: Regex,
+    link_score: f64,
+    invite_score: f64,
+}
+
+impl LinkDetector {
+    pub fn new(link_score: f64, invite_score: f64) -> Self {
+        Self {
+            link: Regex::new(
+                r"(?i)(?:https?://|www\.)\S+|\b[a-z0-9-]+\.(?:com|net|org|kr|io|me|ly|gg|link|xyz)\b",
+            )
+            .unwrap(),
+            invite: Regex::new(
+                r"(?i)open\.kakao\.com/o/|kakaotalk://|band\.us/|t\.me/|telegram\.me/|discord\.gg/",
+            )
+            .unwrap(),
+            link_score,
+            invite_score,
+        }
+    }
+
+    pub fn contains_link(&self, text: &str) -> bool {
+        self.invite.is_match(text) || self.link.is_match(text)
+    }
+}
+
+impl SpamClassifier for LinkDetector {
+    fn name(&self) -> &'static str {
+        "link"
+    }
+
+    fn classify(&self, input: &SpamInput) -> Option<Verdict> {
+        if let Some(invite) = self.invite.find(input.text) {
+            return self.verdict(
+                self.invite_score,
+                format!("invite link {}", invite.as_str()),
+            );
+        }
+        let link = self.link.find(input.text)?;
+        self.verdict(self.link_score, format!("link {}", link.as_str()))
+    }
+}
+
+pub struct ContactDetector {
+    phone: Regex,
+    messenger_id: Regex,
+    score: f64,
+}
+
+impl ContactDetector {
+    pub fn new(score: f64) -> Self {
+        Self {
+            phone: Regex::new(r"\b(?:\+?82[-. ]?)?0?1[016789][-. ]?\d{3,4}[-. ]?\d{4}\b").unwrap(),
+            messenger_id: Regex::new(
+                r"(?i)(?:카톡|카카오톡|kakao|텔레그램|텔레|telegram|라인|line)\s*(?:id|아이디)?\s*[:：@]\s*[a-z0-9_.-]{3,}",
+            )
+            .unwrap(),
+            score,
+        }
+    }
+}
+
+impl SpamClassifier for ContactDetector {
+    fn name(&self) -> &'static str {
+        "contact"
+    }
+
+    fn classify(&self, input: &SpamInput) -> Option<Verdict> {
+        if self.phone.is_match(input.text) {
+            return self.verdict(self.score, "phone number");
+        }
+        let id = self.messenger_id.find(input.text)?;
+        self.verdict(self.score, format!("messenger id '{}'", id.as_str()))
+    }
+}
+
+pub struct KeywordClassifier {
+    keywords: Vec<String>,
+    score: f64,
+}
+
+impl KeywordClassifier {
+    pub fn new(keywords: &[String], score: f64) -> Self {
+        Self {
+            keywords: keywords
+                .iter()
+                .map(|keyword| keyword.to_lowercase())
+                .collect(),
+            score,
+        }
+    }
+}
+
+impl SpamClassifier for KeywordClassifier {
+    fn name(&self) -> &'static str {
+        "keyword"
+    }
+
+    fn classify(&self, input: &SpamInput) -> Option<Verdict> {
+        let text = input.text.to_lowercase();
+        let keyword = self
+            .keywords
+            .iter()
+            .find(|keyword| text.contains(keyword.as_str()))?;
+        self.verdict(self.score, format!("keyword '{}'", keyword))
+    }
+}
+
+// Same text posted repeatedly by one sender, across all channels
+pub struct RepeatDetector {
+    count: usize,
+    window: Duration,
+    score: f64,
+    recent: RefCell<HashMap<i64, VecDeque<(Instant, String)>>>,
+}
+
+impl RepeatDetector {
+    pub fn new(count: usize, window: Duration, score: f64) -> Self {
+        Self {
+            count,
+            window,
+            score,
+            recent: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl SpamClassifier for RepeatDetector {
+    fn name(&self) -> &'static str {
+        "repeat"
+    }
+
+    fn classify(&self, input: &SpamInput) -> Option<Verdict> {
+        let text: String = input
+            .text
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .flat_map(char::to_lowercase)
+            .collect();
+        if text.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut recent = self.recent.borrow_mut();
+        recent.retain(|_, sent| {
+            sent.retain(|(at, _)| now.duration_since(*at) < self.window);
+            !sent.is_empty()
+        });
+
+        let sent = recent.entry(input.sender_id).or_default();
+        let repeats = sent.iter().filter(|(_, sent)| *sent == text).count() + 1;
+        sent.push_back((now, text));
+
+        if repeats < self.count {
+            return None;
+        }
+        self.verdict(
+            self.score,
+            format!("same message {} times in {:?}", repeats, self.window),
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct TokenCounts {
+    docs: usize,
+    tokens: usize,
+    counts: HashMap<String, usize>,
+}
+
+// Multinomial naive Bayes over words and, for Hangul and other non-ASCII text, character bigrams
+#[derive(Debug)]
+pub struct NaiveBayes {
+    min_samples: usize,
+    spam: TokenCounts,
+    ham: TokenCounts,
+}
+
+impl NaiveBayes {
+    pub fn new(min_samples: usize) -> Self {
+        Self {
+            min_samples,
+            spam: TokenCounts::default(),
+            ham: TokenCounts::default(),
+        }
+    }
+
+    pub fn train(&mut self, text: &str, is_spam: bool) {
+        let class = if is_spam {
+            &mut self.spam
+        } else {
+            &mut self.ham
+        };
+        class.docs += 1;
+        for token in tokens(text) {
+            class.tokens += 1;
+            *class.counts.entry(token).or_default() += 1;
+        }
+    }
+
+    pub fn train_from_archive(&mut self, archive: &Archive, limit: usize) -> Result<()> {
+        let messages = archive
+            .labeled_messages(limit)
+            .context("load labeled messages")?;
+        for (text, is_spam) in messages.iter() {
+            self.train(text, *is_spam);
+        }
+        info!(
+            "Trained spam model on {} spam and {} ham messages",
+            self.spam.docs, self.ham.docs
+        );
+        Ok(())
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.spam.docs > 0
+            && self.ham.docs > 0
+            && self.spam.docs + self.ham.docs >= self.min_samples
+    }
+
+    // Probability of spam
+    pub fn predict(&self, text: &str) -> Option<f64> {
+        if !self.is_ready() {
+            return None;
+        }
+
+        let total_docs = (self.spam.docs + self.ham.docs) as f64;
+        let vocabulary = self
+            .spam
+            .counts
+            .keys()
+            .chain(
+                self.ham
+                    .counts
+                    .keys()
+                    .filter(|token| !self.spam.counts.contains_key(*token)),
+            )
+            .count() as f64;
+        let log_likelihood = |class: &TokenCounts, token: &str| {
+            let count = class.counts.get(token).copied().unwrap_or_default() as f64;
+            ((count + 1.0) / (class.tokens as f64 + vocabulary)).ln()
+        };
+
+        let mut spam = (self.spam.docs as f64 / total_docs).ln();
+        let mut ham = (self.ham.docs as f64 / total_docs).ln();
+        for token in tokens(text) {
+            spam += log_likelihood(&self.spam, &token);
+            ham += log_likelihood(&self.ham, &token);
+        }
+
+        Some(1.0 / (1.0 + (ham - spam).exp()))
+    }
+}
+
+impl SpamClassifier for NaiveBayes {
+    fn name(&self) -> &'static str {
+        "bayes"
+    }
+
+    fn classify(&self, input: &SpamInput) -> Option<Verdict> {
+        let score = self.predict(input.text)?;
+        if score < 0.5 {
+            return None;
+        }
+        self.verdict(score, format!("model score {:.2}", score))
+    }
+}
+
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        let word = word.to_lowercase();
+        if !word.is_ascii() {
+            let chars: Vec<char> = word.chars().collect();
+            tokens.extend(chars.windows(2).map(|pair| pair.iter().collect::<String>()));
+        }
+        tokens.push(word);
+    }
+    tokens
+}
+
+#[derive(Debug, Clone)]
+pub struct SpamReport {
+    pub score: f64,
+    pub verdicts: Vec<Verdict>,
+    pub actions: Vec<ModerationAction>,
+}
+
+impl SpamReport {
+    pub fn reasons(&self) -> String {
+        self.verdicts
+            .iter()
+            .map(|verdict| format!("{}: {}", verdict.classifier, verdict.reason))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+pub struct SpamPipeline {
+    aggregate: SpamAggregate,
+    thresholds: Vec<SpamThreshold>,
+    classifiers: Vec<Box<dyn SpamClassifier>>,
+}
+
+impl SpamPipeline {
+    pub fn new(aggregate: SpamAggregate, thresholds: Vec<SpamThreshold>) -> Self {
+        Self {
+            aggregate,
+            thresholds,
+            classifiers: Vec::new(),
+        }
+    }
+
+    // The built-in classifiers, with the Bayes model trained from the archive when there is one
+    pub fn from_cfg(cfg: &SpamCfg, archive: Option<&Archive>) -> Result<Self> {
+        let mut pipeline = Self::new(cfg.aggregate, cfg.actions.clone());
+        pipeline
+            .add(LinkDetector::new(cfg.link_score, cfg.invite_link_score))
+            .add(ContactDetector::new(cfg.contact_score))
+            .add(RepeatDetector::new(
+                cfg.repeat_count,
+                Duration::from_secs(cfg.repeat_window_secs),
+                cfg.repeat_score,
+            ));
+        if !cfg.keywords.is_empty() {
+            pipeline.add(KeywordClassifier::new(&cfg.keywords, cfg.keyword_score));
+        }
+
+        match archive {
+            Some(archive) if cfg.bayes => {
+                let mut bayes = NaiveBayes::new(cfg.bayes_min_samples);
+                bayes.train_from_archive(archive, cfg.bayes_max_samples)?;
+                if !bayes.is_ready() {
+                    warn!("Not enough archived chats to use the spam model yet");
+                }
+                pipeline.add(bayes);
+            }
+            None if cfg.bayes => warn!("Spam model needs the archive, skipping it"),
+            _ => (),
+        }
+
+        Ok(pipeline)
+    }
+
+    pub fn add(&mut self, classifier: impl SpamClassifier + 'static) -> &mut Self {
+        self.classifiers.push(Box::new(classifier));
+        self
+    }
+
+    pub fn evaluate(&self, input: &SpamInput) -> SpamReport {
+        let verdicts: Vec<Verdict> = self
+            .classifiers
+            .iter()
+            .filter_map(|classifier| classifier.classify(input))
+            .collect();
+
+        let scores = verdicts.iter().map(|verdict| verdict.score);
+        let score = match self.aggregate {
+            SpamAggregate::Max => scores.fold(0.0, f64::max),
+            SpamAggregate::Sum => scores.sum::<f64>().min(1.0),
+        };
+        let actions = self
+            .thresholds
+            .iter()
+            .filter(|threshold| score >= threshold.score)
+            .map(|threshold| threshold.action)
+            .collect();
+
+        SpamReport {
+            score,
+            verdicts,
+            actions,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl EventHandler for SpamPipeline {
+    async fn handle(&self, client: &mut KakaoClient, event: &BotEvent) -> Result<Flow> {
+        // Hide and kick only exist in open chats
+        let BotEvent::Chat(chat) = event else {
+            return Ok(Flow::Continue);
+        };
+        let (Some(_), Some(text)) = (chat.link_id, chat.chat.chat.content.message.as_deref())
+        else {
+            return Ok(Flow::Continue);
+        };
+
+        let sender_id = chat.chat.sender_id;
+        match client.get_open_member_type(chat.channel_id, sender_id) {
+            OpenMemberType::Host | OpenMemberType::Manager | OpenMemberType::Bot => {
+                return Ok(Flow::Continue)
+            }
+            _ => (),
+        }
+
+        let report = self.evaluate(&SpamInput { sender_id, text });
+        if report.actions.is_empty() {
+            return Ok(Flow::Continue);
+        }
+        info!(
+            "Spam score {:.2} for chat {} from user {}: {:?}",
+            report.score, chat.log_id, sender_id, report
+        );
+
+        let trigger = Trigger::Spam {
+            score: report.score,
+            reasons: report.reasons(),
+        };
+        let mut flow = Flow::Continue;
+        for action in report.actions.iter().copied() {
+            if action != ModerationAction::Log {
+                flow = Flow::Stop;
+            }
+            if let Err(err) = moderation::apply(client, &Target::from(chat), action, &trigger).await
+            {
+                error!("Cannot {:?} spam chat {}: {:?}", action, chat.log_id, err);
+            }
+        }
+
+        Ok(flow)
+    }
+}
@@ -0,0 +1,325 @@
+use std::{path::Path, sync::Mutex};
+
+use anyhow::{Context, Result};
+use log::*;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::{
+    db::{self, unix_now},
+    kakao::KakaoUser,
+};
+
+const MIGRATIONS: &[&str] = &[
+    // 1: users, nickname/image history and channel presence
+    "CREATE TABLE users (
+        user_id INTEGER PRIMARY KEY,
+        nickname TEXT NOT NULL,
+        image_url TEXT,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+
+    CREATE TABLE nickname_history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        nickname TEXT NOT NULL,
+        changed_at INTEGER NOT NULL
+    );
+    CREATE INDEX nickname_history_user ON nickname_history (user_id, changed_at);
+
+    CREATE TABLE image_history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        image_url TEXT,
+        changed_at INTEGER NOT NULL
+    );
+    CREATE INDEX image_history_user ON image_history (user_id, changed_at);
+
+    CREATE TABLE channel_users (
+        channel_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        left_at INTEGER,
+        PRIMARY KEY (channel_id, user_id)
+    );
+    CREATE INDEX channel_users_user ON channel_users (user_id);",
+    // 2: open chat nicknames are per channel, older history rows have no channel
+    "ALTER TABLE channel_users ADD COLUMN nickname TEXT;
+    ALTER TABLE nickname_history ADD COLUMN channel_id INTEGER;",
+];
+
+#[derive(Debug, Clone)]
+pub struct UserRecord {
+    pub user_id: i64,
+    // Last nickname seen in any channel
+    pub nickname: String,
+    pub image_url: Option<String>,
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+impl UserRecord {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            user_id: row.get("user_id")?,
+            nickname: row.get("nickname")?,
+            image_url: row.get("image_url")?,
+            first_seen: row.get("first_seen")?,
+            last_seen: row.get("last_seen")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NicknameChange {
+    pub channel_id: Option<i64>,
+    pub nickname: String,
+    pub changed_at: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelPresence {
+    pub channel_id: i64,
+    pub user_id: i64,
+    pub nickname: Option<String>,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    // Set while the user is out of the channel, cleared when they are seen again
+    pub left_at: Option<i64>,
+}
+
+impl ChannelPresence {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            channel_id: row.get("channel_id")?,
+            user_id: row.get("user_id")?,
+            nickname: row.get("nickname")?,
+            first_seen: row.get("first_seen")?,
+            last_seen: row.get("last_seen")?,
+            left_at: row.get("left_at")?,
+        })
+    }
+}
+
+// Every user the bot has come across, kept across restarts with their nickname and
+// profile image history and the channels they were seen in
+pub struct UserRegistry {
+    conn: Mutex<Connection>,
+}
+
+impl UserRegistry {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = db::open(path, MIGRATIONS).context("open user registry")?;
+        info!("Opened user registry {}", path.display());
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    // Nickname only, as carried by chats. The known profile image is left untouched
+    pub fn record_nickname(&self, channel_id: i64, user_id: i64, nickname: &str) -> Result<()> {
+        self.record(channel_id, user_id, nickname, None)
+    }
+
+    // Full profile, as carried by profile changes and member lists
+    pub fn record_user(&self, channel_id: i64, user: &KakaoUser) -> Result<()> {
+        self.record(
+            channel_id,
+            user.user_id,
+            &user.nickname,
+            Some(user.image_url.as_deref()),
+        )
+    }
+
+    // A user can go by different nicknames in different open chats at the same time,
+    // so only a change within the same channel counts as a rename
+    fn record(
+        &self,
+        channel_id: i64,
+        user_id: i64,
+        nickname: &str,
+        image_url: Option<Option<&str>>,
+    ) -> Result<()> {
+        let now = unix_now();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let current_image_url: Option<Option<String>> = tx
+            .query_row(
+                "SELECT image_url FROM users WHERE user_id = ?1",
+                [user_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let channel_nickname: Option<String> = tx
+            .query_row(
+                "SELECT nickname FROM channel_users WHERE channel_id = ?1 AND user_id = ?2",
+                params![channel_id, user_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        if channel_nickname.as_deref() != Some(nickname) {
+            if let Some(channel_nickname) = &channel_nickname {
+                info!(
+                    "User {} renamed from '{}' to '{}' in channel_id={}",
+                    user_id, channel_nickname, nickname, channel_id
+                );
+            }
+            tx.execute(
+                "INSERT INTO nickname_history (user_id, channel_id, nickname, changed_at)
+                    VALUES (?1, ?2, ?3, ?4)",
+                params![user_id, channel_id, nickname, now],
+            )?;
+            tx.execute(
+                "INSERT INTO channel_users (channel_id, user_id, nickname, first_seen, last_seen)
+                    VALUES (?1, ?2, ?3, ?4, ?4)
+                    ON CONFLICT (channel_id, user_id) DO UPDATE SET nickname = excluded.nickname",
+                params![channel_id, user_id, nickname, now],
+            )?;
+        }
+
+        match current_image_url {
+            Some(current_image_url) => {
+                let image_url = match image_url {
+                    Some(image_url) if image_url != current_image_url.as_deref() => {
+                        tx.execute(
+                            "INSERT INTO image_history (user_id, image_url, changed_at)
+                                VALUES (?1, ?2, ?3)",
+                            params![user_id, image_url, now],
+                        )?;
+                        image_url
+                    }
+                    _ => current_image_url.as_deref(),
+                };
+                tx.execute(
+                    "UPDATE users SET nickname = ?2, image_url = ?3, last_seen = ?4
+                        WHERE user_id = ?1",
+                    params![user_id, nickname, image_url, now],
+                )?;
+            }
+            None => {
+                let image_url = image_url.flatten();
+                tx.execute(
+                    "INSERT INTO users (user_id, nickname, image_url, first_seen, last_seen)
+                        VALUES (?1, ?2, ?3, ?4, ?4)",
+                    params![user_id, nickname, image_url, now],
+                )?;
+                if image_url.is_some() {
+                    tx.execute(
+                        "INSERT INTO image_history (user_id, image_url, changed_at)
+                            VALUES (?1, ?2, ?3)",
+                        params![user_id, image_url, now],
+                    )?;
+                }
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn seen_in_channel(&self, channel_id: i64, user_id: i64) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO channel_users (channel_id, user_id, first_seen, last_seen)
+                VALUES (?1, ?2, ?3, ?3)
+                ON CONFLICT (channel_id, user_id)
+                DO UPDATE SET last_seen = excluded.last_seen, left_at = NULL",
+            params![channel_id, user_id, unix_now()],
+        )?;
+        Ok(())
+    }
+
+    pub fn left_channel(&self, channel_id: i64, user_id: i64) -> Result<()> {
+        let now = unix_now();
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO channel_users (channel_id, user_id, first_seen, last_seen, left_at)
+                VALUES (?1, ?2, ?3, ?3, ?3)
+                ON CONFLICT (channel_id, user_id) DO UPDATE SET left_at = excluded.left_at",
+            params![channel_id, user_id, now],
+        )?;
+        Ok(())
+    }
+
+    pub fn get(&self, user_id: i64) -> Result<Option<UserRecord>> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT * FROM users WHERE user_id = ?1",
+                [user_id],
+                UserRecord::from_row,
+            )
+            .optional()?)
+    }
+
+    // Matches current and previous nicknames, case insensitive for ASCII
+    pub fn find_by_nickname(&self, query: &str, limit: usize) -> Result<Vec<UserRecord>> {
+        let pattern = format!(
+            "%{}%",
+            query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM users WHERE user_id IN (
+                SELECT user_id FROM nickname_history WHERE nickname LIKE ?1 ESCAPE '\\'
+                UNION SELECT user_id FROM channel_users WHERE nickname LIKE ?1 ESCAPE '\\'
+                UNION SELECT user_id FROM users WHERE nickname LIKE ?1 ESCAPE '\\'
+            ) ORDER BY last_seen DESC LIMIT ?2",
+        )?;
+        let users = stmt
+            .query_map(params![pattern, limit as i64], UserRecord::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(users)
+    }
+
+    // Oldest first across all channels, the last entry of each channel is the nickname there
+    pub fn nickname_history(&self, user_id: i64) -> Result<Vec<NicknameChange>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT channel_id, nickname, changed_at FROM nickname_history
+                WHERE user_id = ?1 ORDER BY changed_at, id",
+        )?;
+        let history = stmt
+            .query_map([user_id], |row| {
+                Ok(NicknameChange {
+                    channel_id: row.get(0)?,
+                    nickname: row.get(1)?,
+                    changed_at: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(history)
+    }
+
+    pub fn channels_of(&self, user_id: i64) -> Result<Vec<ChannelPresence>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT * FROM channel_users WHERE user_id = ?1 ORDER BY last_seen DESC")?;
+        let channels = stmt
+            .query_map([user_id], ChannelPresence::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(channels)
+    }
+
+    // Users seen in the channel who have not left since
+    pub fn members_of(&self, channel_id: i64) -> Result<Vec<ChannelPresence>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM channel_users WHERE channel_id = ?1 AND left_at IS NULL
+                ORDER BY last_seen DESC",
+        )?;
+        let members = stmt
+            .query_map([channel_id], ChannelPresence::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(members)
+    }
+}
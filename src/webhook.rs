@@ -0,0 +1,857 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use kiwi_talk_client::event::chat::ChatReceived;
+use log::*;
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{
+    attachment::{Attachment, AttachmentKind},
+    config::{WebhookCfg, WebhookEndpoint},
+    db::unix_now,
+    dispatcher::{BotEvent, EventHandler, EventKind, Flow},
+    feed::FeedMember,
+    flood::AbuseKind,
+    kakao::KakaoClient,
+};
+
+// Bumped on breaking changes to the payload, fields are only added within a version
+const SCHEMA_VERSION: u32 = 1;
+
+const HEADER_EVENT: &str = "X-Kakao-Event";
+const HEADER_DELIVERY: &str = "X-Kakao-Delivery";
+const HEADER_TIMESTAMP: &str = "X-Kakao-Timestamp";
+const HEADER_SIGNATURE: &str = "X-Kakao-Signature";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookChat {
+    pub channel_id: i64,
+    pub link_id: Option<i64>,
+    pub log_id: i64,
+    pub prev_log_id: Option<i64>,
+    pub sender_id: i64,
+    pub sender_nickname: Option<String>,
+    // Unix seconds
+    pub sent_at: i64,
+    pub chat_type: i32,
+    pub attachment_kind: AttachmentKind,
+    pub message: Option<String>,
+    // Attachment JSON as sent by the server, null when missing or not JSON
+    pub attachment: Option<Value>,
+    // The attachment decoded by chat type, with our field names instead of the server's keys
+    pub parsed_attachment: Attachment,
+}
+
+impl WebhookChat {
+    fn new(chat: &ChatReceived) -> Self {
+        let content = &chat.chat.chat.content;
+        let parsed_attachment = Attachment::parse(&chat.chat.chat);
+        Self {
+            channel_id: chat.channel_id,
+            link_id: chat.link_id,
+            log_id: chat.log_id,
+            prev_log_id: chat.chat.prev_log_id,
+            sender_id: chat.chat.sender_id,
+            sender_nickname: chat.user_nickname.clone(),
+            sent_at: chat.chat.send_at,
+            chat_type: chat.chat.chat.chat_type.0,
+            attachment_kind: parsed_attachment.kind(),
+            message: content.message.clone(),
+            attachment: content
+                .attachment
+                .as_deref()
+                .and_then(|attachment| serde_json::from_str(attachment).ok()),
+            parsed_attachment,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookMember {
+    pub user_id: i64,
+    pub nickname: Option<String>,
+}
+
+impl From<&FeedMember> for WebhookMember {
+    fn from(member: &FeedMember) -> Self {
+        Self {
+            user_id: member.user_id,
+            nickname: member.nickname.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookProfile {
+    pub user_id: i64,
+    pub nickname: String,
+    pub profile_image_url: Option<String>,
+    pub member_type: i32,
+}
+
+// The part of BotEvent other services get, kept stable across changes to the client types.
+// Serialized as {"event": "<kind>", "data": {...}}
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Chat(WebhookChat),
+    ProfileChanged {
+        channel_id: i64,
+        link_id: i64,
+        user: WebhookProfile,
+    },
+    MemberJoined {
+        chat: WebhookChat,
+        members: Vec<WebhookMember>,
+    },
+    MemberLeft {
+        chat: WebhookChat,
+        members: Vec<WebhookMember>,
+        kicked: bool,
+    },
+    MessageDeleted {
+        chat: WebhookChat,
+        log_id: i64,
+    },
+    MessageHidden {
+        chat: WebhookChat,
+        log_ids: Vec<i64>,
+    },
+    MessageUnhidden {
+        chat: WebhookChat,
+        log_ids: Vec<i64>,
+    },
+    AbuseDetected {
+        chat: WebhookChat,
+        abuses: Vec<AbuseKind>,
+    },
+    Error {
+        message: String,
+    },
+    // Events may be missed until reconnected
+    Disconnected {
+        reason: String,
+    },
+    // channel_id -> last log id seen before the disconnect, backfill continues from there
+    Reconnected {
+        attempts: u32,
+        last_log_ids: HashMap<i64, i64>,
+    },
+}
+
+impl WebhookEvent {
+    // Client events this crate doesn't decode stay internal
+    pub fn from_event(event: &BotEvent) -> Option<Self> {
+        let members = |members: &[FeedMember]| members.iter().map(WebhookMember::from).collect();
+
+        Some(match event {
+            BotEvent::Chat(chat) => WebhookEvent::Chat(WebhookChat::new(chat)),
+            BotEvent::ProfileChanged {
+                channel_id,
+                link_id,
+                user,
+            } => WebhookEvent::ProfileChanged {
+                channel_id: *channel_id,
+                link_id: *link_id,
+                user: WebhookProfile {
+                    user_id: user.user_id,
+                    nickname: user.nickname.clone(),
+                    profile_image_url: user.profile_image_url.clone(),
+                    member_type: user.member_type,
+                },
+            },
+            BotEvent::MemberJoined {
+                chat,
+                members: joined,
+            } => WebhookEvent::MemberJoined {
+                chat: WebhookChat::new(chat),
+                members: members(joined),
+            },
+            BotEvent::MemberLeft {
+                chat,
+                members: left,
+                kicked,
+            } => WebhookEvent::MemberLeft {
+                chat: WebhookChat::new(chat),
+                members: members(left),
+                kicked: *kicked,
+            },
+            BotEvent::MessageDeleted { chat, log_id } => WebhookEvent::MessageDeleted {
+                chat: WebhookChat::new(chat),
+                log_id: *log_id,
+            },
+            BotEvent::MessageHidden { chat, log_ids } => WebhookEvent::MessageHidden {
+                chat: WebhookChat::new(chat),
+                log_ids: log_ids.clone(),
+            },
+            BotEvent::MessageUnhidden { chat, log_ids } => WebhookEvent::MessageUnhidden {
+                chat: WebhookChat::new(chat),
+                log_ids: log_ids.clone(),
+            },
+            BotEvent::AbuseDetected { chat, abuses } => WebhookEvent::AbuseDetected {
+                chat: WebhookChat::new(chat),
+                abuses: abuses.clone(),
+            },
+            BotEvent::Error(err) => WebhookEvent::Error {
+                message: err.to_string(),
+            },
+            BotEvent::Disconnected { reason } => WebhookEvent::Disconnected {
+                reason: reason.clone(),
+            },
+            BotEvent::Reconnected {
+                attempts,
+                last_log_ids,
+            } => WebhookEvent::Reconnected {
+                attempts: *attempts,
+                last_log_ids: last_log_ids.clone(),
+            },
+            BotEvent::Unhandled(_) => return None,
+        })
+    }
+}
+
+// The request body, the id is also sent as X-Kakao-Delivery and stays the same across retries
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload<'a> {
+    pub id: &'a str,
+    pub version: u32,
+    // Unix seconds the event was received
+    pub created_at: i64,
+    #[serde(flatten)]
+    pub event: &'a WebhookEvent,
+}
+
+#[derive(Debug, Clone)]
+struct Delivery {
+    id: String,
+    // The event name as in the payload
+    event: String,
+    body: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeadLetter {
+    url: String,
+    id: String,
+    event: String,
+    attempts: u32,
+    error: String,
+    failed_at: i64,
+    body: String,
+}
+
+// Append only JSON Lines file, redeliver_dead_letters takes entries back out
+struct DeadLetters {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl DeadLetters {
+    fn push(&self, url: &str, delivery: &Delivery, attempts: u32, error: &str) {
+        let entry = DeadLetter {
+            url: url.to_owned(),
+            id: delivery.id.clone(),
+            event: delivery.event.clone(),
+            attempts,
+            error: error.to_owned(),
+            failed_at: unix_now(),
+            body: delivery.body.clone(),
+        };
+        if let Err(err) = self.append(&[entry]) {
+            error!(
+                "Cannot write webhook {} for {} to dead letters, dropping it: {:?}",
+                delivery.id, url, err
+            );
+        }
+    }
+
+    fn append(&self, entries: &[DeadLetter]) -> Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("open {}", self.path.display()))?;
+        for entry in entries {
+            let line = serde_json::to_string(entry)?;
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    // Keeps the entries keep returns true for, under one lock so failed deliveries written
+    // meanwhile aren't lost. Kept entries go to a temporary file renamed over the original,
+    // so they survive a crash halfway through
+    fn retain(&self, mut keep: impl FnMut(&DeadLetter) -> bool) -> Result<usize> {
+        let _lock = self.lock.lock().unwrap();
+        let entries = self.read()?;
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        let kept: Vec<DeadLetter> = entries.into_iter().filter(|entry| keep(entry)).collect();
+        if kept.is_empty() {
+            fs::remove_file(&self.path)
+                .with_context(|| format!("remove {}", self.path.display()))?;
+            return Ok(0);
+        }
+
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let mut file = fs::File::create(&temp_path)
+            .with_context(|| format!("create {}", temp_path.display()))?;
+        for entry in kept.iter() {
+            let line = serde_json::to_string(entry)?;
+            writeln!(file, "{}", line)?;
+        }
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("replace {}", self.path.display()))?;
+        Ok(kept.len())
+    }
+
+    fn read(&self) -> Result<Vec<DeadLetter>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err).with_context(|| format!("read {}", self.path.display())),
+        };
+
+        let mut entries = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => warn!(
+                    "Skipping malformed line {} of {}: {}",
+                    index + 1,
+                    self.path.display(),
+                    err
+                ),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum DeliveryError {
+    #[error("endpoint answered {0}")]
+    Status(StatusCode),
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+}
+
+impl DeliveryError {
+    // Other client errors mean the endpoint rejected the event, sending it again won't help
+    fn is_retryable(&self) -> bool {
+        match self {
+            DeliveryError::Status(status) => {
+                status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            DeliveryError::Request(_) => true,
+        }
+    }
+}
+
+struct EndpointQueue {
+    url: String,
+    channels: HashSet<i64>,
+    events: HashSet<EventKind>,
+    queue: mpsc::Sender<Delivery>,
+}
+
+impl EndpointQueue {
+    fn accepts(&self, kind: EventKind, channel_id: Option<i64>) -> bool {
+        if !self.events.is_empty() && !self.events.contains(&kind) {
+            return false;
+        }
+
+        match channel_id {
+            Some(channel_id) => self.channels.is_empty() || self.channels.contains(&channel_id),
+            None => true,
+        }
+    }
+}
+
+// POSTs events to webhook.endpoints. Each endpoint has its own queue and task, so events reach
+// an endpoint in order and a slow or failing endpoint only holds up itself. Events that run
+// out of attempts or don't fit in the queue are written to the dead letter file
+pub struct WebhookSink {
+    endpoints: Vec<EndpointQueue>,
+    dead_letters: Arc<DeadLetters>,
+}
+
+impl WebhookSink {
+    pub fn new(cfg: &WebhookCfg, data_dir: &Path) -> Result<Self> {
+        let http = Client::builder()
+            .timeout(Duration::from_secs(cfg.timeout_secs))
+            .build()
+            .context("create webhook http client")?;
+        let retry = RetryPolicy {
+            max_attempts: cfg.max_attempts,
+            initial_backoff: Duration::from_millis(cfg.initial_backoff_ms),
+            max_backoff: Duration::from_millis(cfg.max_backoff_ms),
+        };
+        let dead_letters = Arc::new(DeadLetters {
+            path: data_dir.join(&cfg.dead_letter_path),
+            lock: Mutex::new(()),
+        });
+
+        let endpoints = cfg
+            .endpoints
+            .iter()
+            .map(|endpoint| {
+                let (queue, recv) = mpsc::channel(cfg.queue_size);
+                tokio::spawn(deliver_loop(
+                    endpoint.clone(),
+                    http.clone(),
+                    retry,
+                    dead_letters.clone(),
+                    recv,
+                ));
+
+                EndpointQueue {
+                    url: endpoint.url.clone(),
+                    channels: endpoint.channels.iter().copied().collect(),
+                    events: endpoint.events.iter().copied().collect(),
+                    queue,
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            endpoints,
+            dead_letters,
+        })
+    }
+
+    pub fn publish(&self, event: &BotEvent) -> Result<()> {
+        let kind = event.kind();
+        let channel_id = event.channel_id();
+        let endpoints: Vec<&EndpointQueue> = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.accepts(kind, channel_id))
+            .collect();
+        if endpoints.is_empty() {
+            return Ok(());
+        }
+        let Some(event) = WebhookEvent::from_event(event) else {
+            return Ok(());
+        };
+
+        let id = format!("{:032x}", rand::random::<u128>());
+        let body = serde_json::to_string(&WebhookPayload {
+            id: &id,
+            version: SCHEMA_VERSION,
+            created_at: unix_now(),
+            event: &event,
+        })
+        .context("serialize webhook payload")?;
+        let delivery = Delivery {
+            id,
+            event: serde_json::to_value(kind)?
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            body,
+        };
+
+        for endpoint in endpoints {
+            self.enqueue(endpoint, delivery.clone());
+        }
+        Ok(())
+    }
+
+    fn enqueue(&self, endpoint: &EndpointQueue, delivery: Delivery) {
+        let (delivery, reason) = match endpoint.queue.try_send(delivery) {
+            Ok(()) => return,
+            Err(TrySendError::Full(delivery)) => (delivery, "queue full"),
+            Err(TrySendError::Closed(delivery)) => (delivery, "delivery task stopped"),
+        };
+        warn!(
+            "Webhook queue of {} refused {}: {}",
+            endpoint.url, delivery.id, reason
+        );
+        self.dead_letters.push(&endpoint.url, &delivery, 0, reason);
+    }
+
+    // Queues dead letters again for endpoints that are still configured, the rest are kept.
+    // Returns how many were queued
+    pub fn redeliver_dead_letters(&self) -> Result<usize> {
+        let mut queued = 0;
+        let kept = self.dead_letters.retain(|entry| {
+            let Some(endpoint) = self
+                .endpoints
+                .iter()
+                .find(|endpoint| endpoint.url == entry.url)
+            else {
+                return true;
+            };
+
+            let delivery = Delivery {
+                id: entry.id.clone(),
+                event: entry.event.clone(),
+                body: entry.body.clone(),
+            };
+            match endpoint.queue.try_send(delivery) {
+                Ok(()) => {
+                    queued += 1;
+                    false
+                }
+                Err(_) => true,
+            }
+        })?;
+
+        info!(
+            "Queued {} dead letter webhooks again, {} kept",
+            queued, kept
+        );
+        Ok(queued)
+    }
+}
+
+#[async_trait(?Send)]
+impl EventHandler for WebhookSink {
+    async fn handle(&self, _client: &mut KakaoClient, event: &BotEvent) -> Result<Flow> {
+        self.publish(event)?;
+        Ok(Flow::Continue)
+    }
+}
+
+async fn deliver_loop(
+    endpoint: WebhookEndpoint,
+    http: Client,
+    retry: RetryPolicy,
+    dead_letters: Arc<DeadLetters>,
+    mut queue: mpsc::Receiver<Delivery>,
+) {
+    while let Some(delivery) = queue.recv().await {
+        let mut backoff = retry.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let err = match post(&http, &endpoint, &delivery).await {
+                Ok(()) => {
+                    debug!("Delivered webhook {} to {}", delivery.id, endpoint.url);
+                    break;
+                }
+                Err(err) => err,
+            };
+
+            if !err.is_retryable() || attempts >= retry.max_attempts {
+                error!(
+                    "Giving up on webhook {} to {} after {} attempts: {}",
+                    delivery.id, endpoint.url, attempts, err
+                );
+                dead_letters.push(&endpoint.url, &delivery, attempts, &err.to_string());
+                break;
+            }
+
+            warn!(
+                "Webhook {} to {} failed: {}, retrying in {:?}",
+                delivery.id, endpoint.url, err, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(retry.max_backoff);
+        }
+    }
+}
+
+async fn post(
+    http: &Client,
+    endpoint: &WebhookEndpoint,
+    delivery: &Delivery,
+) -> Result<(), DeliveryError> {
+    // The timestamp is signed with the body so receivers can reject replayed requests
+    let timestamp = unix_now().to_string();
+    let signature = sign(&endpoint.secret, &timestamp, &delivery.body);
+
+    let res = http
+        .post(&endpoint.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(HEADER_EVENT, &delivery.event)
+        .header(HEADER_DELIVERY, &delivery.id)
+        .header(HEADER_TIMESTAMP, &timestamp)
+        .header(HEADER_SIGNATURE, format!("sha256={}", signature))
+        .body(delivery.body.clone())
+        .send()
+        .await?;
+
+    match res.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(DeliveryError::Status(status)),
+    }
+}
+
+// Hex HMAC-SHA256 of "<timestamp>.<body>"
+fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, convert::Infallible, net::SocketAddr, time::Instant};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, HeaderMap, Request, Response, Server,
+    };
+
+    use super::*;
+
+    const SECRET: &str = "secret";
+
+    // Records every request and answers with the given statuses in order, then 200
+    struct Stub {
+        url: String,
+        requests: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+    }
+
+    impl Stub {
+        fn start(statuses: &[u16]) -> Self {
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let statuses = Arc::new(Mutex::new(VecDeque::from(statuses.to_vec())));
+
+            let recorded = requests.clone();
+            let make_service = make_service_fn(move |_| {
+                let requests = recorded.clone();
+                let statuses = statuses.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let requests = requests.clone();
+                        let statuses = statuses.clone();
+                        async move {
+                            let headers = req.headers().clone();
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            let body = String::from_utf8(body.to_vec()).unwrap();
+                            requests.lock().unwrap().push((headers, body));
+
+                            let status = statuses.lock().unwrap().pop_front().unwrap_or(200);
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(status)
+                                    .body(Body::empty())
+                                    .unwrap(),
+                            )
+                        }
+                    }))
+                }
+            });
+            let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+            let url = format!("http://{}/hook", server.local_addr());
+            tokio::spawn(server);
+
+            Self { url, requests }
+        }
+
+        fn requests(&self) -> Vec<(HeaderMap, String)> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    fn sink(url: &str, max_attempts: u32) -> (WebhookSink, PathBuf) {
+        let data_dir =
+            std::env::temp_dir().join(format!("webhook-test-{:016x}", rand::random::<u64>()));
+        let cfg = WebhookCfg {
+            enabled: true,
+            endpoints: vec![WebhookEndpoint {
+                url: url.to_owned(),
+                secret: SECRET.to_owned(),
+                channels: Vec::new(),
+                events: Vec::new(),
+            }],
+            max_attempts,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 5,
+            ..Default::default()
+        };
+        (WebhookSink::new(&cfg, &data_dir).unwrap(), data_dir)
+    }
+
+    fn delivery(id: &str) -> Delivery {
+        Delivery {
+            id: id.to_owned(),
+            event: "chat".to_owned(),
+            body: format!(r#"{{"id":"{}"}}"#, id),
+        }
+    }
+
+    fn dead_letter(url: &str, id: &str) -> DeadLetter {
+        let delivery = delivery(id);
+        DeadLetter {
+            url: url.to_owned(),
+            id: delivery.id,
+            event: delivery.event,
+            attempts: 5,
+            error: "endpoint answered 503".to_owned(),
+            failed_at: 0,
+            body: delivery.body,
+        }
+    }
+
+    async fn wait_until(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn sign_is_hmac_of_timestamp_and_body() {
+        assert_eq!(
+            sign(SECRET, "1700000000", r#"{"event":"chat"}"#),
+            "536640f34816caf4323c665b30ede92a95154ad95a6ce3535bf96d1fd2cd0340"
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_request() {
+        let stub = Stub::start(&[]);
+        let (sink, data_dir) = sink(&stub.url, 3);
+
+        sink.enqueue(&sink.endpoints[0], delivery("a"));
+        wait_until(|| stub.requests().len() == 1).await;
+
+        let (headers, body) = &stub.requests()[0];
+        let header = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_owned();
+        assert_eq!(body, r#"{"id":"a"}"#);
+        assert_eq!(header("content-type"), "application/json");
+        assert_eq!(header(HEADER_EVENT), "chat");
+        assert_eq!(header(HEADER_DELIVERY), "a");
+        let signature = format!("sha256={}", sign(SECRET, &header(HEADER_TIMESTAMP), body));
+        assert_eq!(header(HEADER_SIGNATURE), signature);
+
+        let _ = fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_and_rate_limits() {
+        let stub = Stub::start(&[503, 429, 500]);
+        let (sink, data_dir) = sink(&stub.url, 5);
+
+        sink.enqueue(&sink.endpoints[0], delivery("a"));
+        wait_until(|| stub.requests().len() == 4).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests
+            .iter()
+            .all(|(headers, _)| headers.get(HEADER_DELIVERY).unwrap() == "a"));
+        assert!(sink.dead_letters.read().unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn client_errors_go_to_dead_letters_without_retry() {
+        let stub = Stub::start(&[404]);
+        let (sink, data_dir) = sink(&stub.url, 5);
+
+        sink.enqueue(&sink.endpoints[0], delivery("a"));
+        wait_until(|| !sink.dead_letters.read().unwrap().is_empty()).await;
+
+        assert_eq!(stub.requests().len(), 1);
+        let dead_letters = sink.dead_letters.read().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].url, stub.url);
+        assert_eq!(dead_letters[0].id, "a");
+        assert_eq!(dead_letters[0].attempts, 1);
+        assert_eq!(dead_letters[0].body, r#"{"id":"a"}"#);
+
+        let _ = fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let stub = Stub::start(&[500, 502, 503, 504]);
+        let (sink, data_dir) = sink(&stub.url, 3);
+
+        sink.enqueue(&sink.endpoints[0], delivery("a"));
+        wait_until(|| !sink.dead_letters.read().unwrap().is_empty()).await;
+
+        assert_eq!(stub.requests().len(), 3);
+        let dead_letters = sink.dead_letters.read().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].attempts, 3);
+        assert!(dead_letters[0].error.contains("503"));
+
+        let _ = fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn redelivers_dead_letters_of_configured_endpoints() {
+        let stub = Stub::start(&[]);
+        let (sink, data_dir) = sink(&stub.url, 3);
+        let removed_url = "http://127.0.0.1:1/removed";
+        sink.dead_letters
+            .append(&[
+                dead_letter(&stub.url, "a"),
+                dead_letter(removed_url, "b"),
+                dead_letter(&stub.url, "c"),
+            ])
+            .unwrap();
+
+        assert_eq!(sink.redeliver_dead_letters().unwrap(), 2);
+        wait_until(|| stub.requests().len() == 2).await;
+
+        let ids: Vec<String> = stub
+            .requests()
+            .iter()
+            .map(|(headers, _)| {
+                let id = headers.get(HEADER_DELIVERY).unwrap();
+                id.to_str().unwrap().to_owned()
+            })
+            .collect();
+        assert_eq!(ids, ["a", "c"]);
+
+        // Only the entry of the endpoint that is gone is kept, and no temporary file is left
+        let kept = sink.dead_letters.read().unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].url, removed_url);
+        assert_eq!(kept[0].id, "b");
+        let files: Vec<_> = fs::read_dir(&data_dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+
+        assert_eq!(sink.redeliver_dead_letters().unwrap(), 0);
+        assert_eq!(sink.dead_letters.read().unwrap().len(), 1);
+
+        let _ = fs::remove_dir_all(data_dir);
+    }
+}